@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// Decoding arbitrary frames must fail with an error instead of panicking or allocating
+// more than the maximum message size.
+fuzz_target!(|data: &[u8]| {
+    let _ = netbench::decode_control_frame(data);
+});
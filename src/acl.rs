@@ -0,0 +1,56 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use ipnet::IpNet;
+
+/// Decides which client addresses the server talks to. Denied networks take precedence,
+/// an empty allow list allows every address that isn't denied.
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+impl AccessList {
+    pub fn is_allowed(&self, addr: IpAddr) -> bool {
+        // IPv4 clients of a dual stack socket show up as IPv4-mapped IPv6 addresses
+        let addr = addr.to_canonical();
+        if self.deny.iter().any(|net| net.contains(&addr)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&addr))
+    }
+}
+
+/// Parses a network in CIDR notation, a plain address is a network of just that address
+pub fn parse_network(s: &str) -> Result<IpNet, ipnet::AddrParseError> {
+    IpNet::from_str(s).or_else(|e| IpAddr::from_str(s).map(IpNet::from).map_err(|_| e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_access_list() {
+        let acl = AccessList::default();
+        assert!(acl.is_allowed("192.0.2.1".parse().unwrap()));
+
+        let acl = AccessList {
+            allow: vec![
+                parse_network("10.0.0.0/8").unwrap(),
+                parse_network("2001:db8::/32").unwrap(),
+            ],
+            deny: vec![parse_network("10.0.13.37").unwrap()],
+        };
+        assert!(acl.is_allowed("10.1.2.3".parse().unwrap()));
+        assert!(acl.is_allowed("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(acl.is_allowed("2001:db8::1".parse().unwrap()));
+        assert!(!acl.is_allowed("10.0.13.37".parse().unwrap()));
+        assert!(!acl.is_allowed("192.0.2.1".parse().unwrap()));
+
+        assert!(parse_network("10.0.0.0/33").is_err());
+        assert!(parse_network("lab").is_err());
+    }
+}
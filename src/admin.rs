@@ -0,0 +1,372 @@
+use std::fmt;
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, warn};
+
+use crate::{
+    ControlMessage, Direction, MessageID, MessageType, NBError, NBytes, TestSummary,
+    MESSAGE_READ_TIMEOUT,
+};
+
+/// A test the server has admitted
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TestInfo {
+    pub id: u64,
+    pub client: SocketAddr,
+    pub state: TestState,
+    pub protocol: String,
+    pub direction: Direction,
+    /// Target bitrate in bits per second, 0 for unlimited
+    pub bitrate: u64,
+    /// Configured duration in seconds, if the test ends after a time
+    pub duration: Option<f64>,
+    /// Seconds since the test started
+    pub elapsed: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum TestState {
+    /// Waiting for the client to open the data connection
+    Outstanding,
+    Running,
+}
+
+impl fmt::Display for TestInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self.state {
+            TestState::Outstanding => "outstanding",
+            TestState::Running => "running",
+        };
+        write!(
+            f,
+            "{:>4} | {:<22} | {:<11} | {} {:?}",
+            self.id, self.client, state, self.protocol, self.direction
+        )?;
+        match (self.elapsed, self.duration) {
+            (Some(elapsed), Some(duration)) => write!(f, " | {elapsed:.0}/{duration:.0} s")?,
+            (None, Some(duration)) => write!(f, " | {duration:.0} s")?,
+            _ => (),
+        }
+        if self.bitrate > 0 {
+            let bitrate = NBytes::format_bits_per_second(self.bitrate as f64);
+            write!(f, " | {bitrate}/s")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Live statistics of a running test
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TestStats {
+    pub test: TestInfo,
+    /// The intervals measured by the server so far
+    pub summary: TestSummary,
+}
+
+impl fmt::Display for TestStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let summary = &self.summary;
+        let sent = NBytes::from(summary.bytes_sent).format_as_bytes();
+        let sent_rate = NBytes::format_bits_per_second(summary.sent_bits_per_second());
+        let received = NBytes::from(summary.bytes_received).format_as_bytes();
+        let received_rate = NBytes::format_bits_per_second(summary.received_bits_per_second());
+        writeln!(f, "{}", self.test)?;
+        write!(
+            f,
+            "{:.0} s measured: sent {sent} ({sent_rate}/s) received {received} ({received_rate}/s)",
+            summary.duration
+        )?;
+        if let Some(retransmits) = summary.retransmits {
+            write!(f, " retransmits {retransmits}")?;
+        }
+        if let Some(rtt) = summary.mean_rtt {
+            write!(f, " rtt {rtt:.3} ms")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Sent to the admin socket of a server
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase", tag = "command")]
+pub enum AdminRequest {
+    /// Lists the outstanding and running tests
+    List,
+    Stats {
+        id: u64,
+    },
+    Cancel {
+        id: u64,
+    },
+    /// Stop accepting tests and stop the server once the admitted tests are done
+    Drain,
+    Stop,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum AdminResponse {
+    Tests(Vec<TestInfo>),
+    Stats(Box<TestStats>),
+    Done,
+    Error(String),
+}
+
+/// The Unix socket the server accepts admin connections on, removed when dropped
+#[derive(Debug)]
+pub(crate) struct AdminSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl AdminSocket {
+    /// Binds the socket, only the owner may connect to it
+    pub(crate) fn bind(path: &Path) -> Result<Self> {
+        // a socket left behind by a server that didn't shut down cleanly
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if metadata.file_type().is_socket()
+                && std::os::unix::net::UnixStream::connect(path).is_err()
+            {
+                fs::remove_file(path)?;
+            }
+        }
+
+        // the socket is bound in a directory only the owner can enter and linked into place
+        // once its permissions are restricted, so nobody can connect in between
+        let dir = path.with_file_name(format!(
+            ".{}.{}",
+            path.file_name().unwrap_or_default().to_string_lossy(),
+            std::process::id()
+        ));
+        DirBuilder::new()
+            .mode(0o700)
+            .create(&dir)
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+        let tmp_path = dir.join("admin.sock");
+        let bound = UnixListener::bind(&tmp_path)
+            .map_err(anyhow::Error::from)
+            .and_then(|listener| {
+                fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o600))?;
+                // unlike a rename, fails if the path exists
+                fs::hard_link(&tmp_path, path)?;
+                Ok(listener)
+            });
+        let _ = fs::remove_file(&tmp_path);
+        let _ = fs::remove_dir(&dir);
+        let listener =
+            bound.with_context(|| format!("failed to bind the admin socket {}", path.display()))?;
+        debug!("admin socket listening on {}", path.display());
+
+        Ok(AdminSocket {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub(crate) async fn accept(&self) -> io::Result<UnixStream> {
+        Ok(self.listener.accept().await?.0)
+    }
+}
+
+impl Drop for AdminSocket {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!(
+                "failed to remove the admin socket {}: {e}",
+                self.path.display()
+            );
+        }
+    }
+}
+
+/// Answers the requests of an admin connection by passing them on to the server
+pub(crate) async fn serve(mut stream: UnixStream, com_tx: mpsc::Sender<ControlMessage>) {
+    loop {
+        let msg_type = match crate::read_message_type(&mut stream, None).await {
+            Ok(msg_type @ MessageType::AdminRequest(_)) => msg_type,
+            Ok(msg_type) => {
+                warn!("unexpected {} message on the admin socket", msg_type.name());
+                return;
+            }
+            Err(NBError::ConnectionClosed) => return,
+            Err(e) => {
+                warn!("failed to read from the admin socket: {e}");
+                return;
+            }
+        };
+        let response = match crate::read_message(&mut stream, msg_type).await {
+            Ok(request) => handle(request, &com_tx).await,
+            Err(e) => AdminResponse::Error(e.to_string()),
+        };
+
+        let res =
+            crate::send_message(response, MessageID::ADMIN_RESPONSE_MESSAGE, &mut stream).await;
+        if let Err(e) = res {
+            warn!("failed to answer on the admin socket: {e}");
+            return;
+        }
+    }
+}
+
+async fn handle(request: AdminRequest, com_tx: &mpsc::Sender<ControlMessage>) -> AdminResponse {
+    debug!("admin request {request:?}");
+    let stopped = || AdminResponse::Error("the server has stopped".to_string());
+    match request {
+        AdminRequest::List => {
+            let (reply, tests) = oneshot::channel();
+            if com_tx.send(ControlMessage::ListTests(reply)).await.is_err() {
+                return stopped();
+            }
+            tests.await.map_or_else(|_| stopped(), AdminResponse::Tests)
+        }
+        AdminRequest::Stats { id } => {
+            let (reply, stats) = oneshot::channel();
+            if com_tx
+                .send(ControlMessage::GetStats(id, reply))
+                .await
+                .is_err()
+            {
+                return stopped();
+            }
+            match stats.await {
+                Ok(Some(stats)) => AdminResponse::Stats(Box::new(stats)),
+                Ok(None) => AdminResponse::Error(format!("there's no running test {id}")),
+                Err(_) => stopped(),
+            }
+        }
+        AdminRequest::Cancel { id } => {
+            let (reply, cancelled) = oneshot::channel();
+            if com_tx
+                .send(ControlMessage::Cancel(id, reply))
+                .await
+                .is_err()
+            {
+                return stopped();
+            }
+            match cancelled.await {
+                Ok(true) => AdminResponse::Done,
+                Ok(false) => AdminResponse::Error(format!("there's no test {id}")),
+                Err(_) => stopped(),
+            }
+        }
+        AdminRequest::Drain => match com_tx.send(ControlMessage::Drain).await {
+            Ok(()) => AdminResponse::Done,
+            Err(_) => stopped(),
+        },
+        AdminRequest::Stop => match com_tx.send(ControlMessage::Stop).await {
+            Ok(()) => AdminResponse::Done,
+            Err(_) => stopped(),
+        },
+    }
+}
+
+/// Sends `request` to the admin socket of the server at `path`
+pub async fn admin_request(path: &Path, request: AdminRequest) -> Result<AdminResponse> {
+    let mut stream = UnixStream::connect(path)
+        .await
+        .with_context(|| format!("failed to connect to the admin socket {}", path.display()))?;
+    crate::send_message(request, MessageID::ADMIN_REQUEST_MESSAGE, &mut stream).await?;
+
+    let msg_type = crate::read_message_type(&mut stream, Some(MESSAGE_READ_TIMEOUT)).await?;
+    let MessageType::AdminResponse(_) = msg_type else {
+        return Err(NBError::UnexpectedMessage(msg_type.name()).into());
+    };
+
+    Ok(crate::read_message(&mut stream, msg_type).await?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A path in the temporary directory that doesn't exist yet
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("netbench-{}-{name}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_info_display() {
+        let mut info = TestInfo {
+            id: 3,
+            client: "127.0.0.1:40000".parse().unwrap(),
+            state: TestState::Outstanding,
+            protocol: "TCP".to_string(),
+            direction: Direction::ClientToServer,
+            bitrate: 0,
+            duration: Some(10.0),
+            elapsed: None,
+        };
+        assert_eq!(
+            info.to_string(),
+            "   3 | 127.0.0.1:40000        | outstanding | TCP ClientToServer | 10 s"
+        );
+
+        info.state = TestState::Running;
+        info.elapsed = Some(4.2);
+        info.duration = None;
+        info.bitrate = 1_000_000;
+        assert!(info
+            .to_string()
+            .starts_with("   3 | 127.0.0.1:40000        | running     | TCP ClientToServer | "));
+        // no duration without a time limit, the bitrate last
+        assert!(info.to_string().ends_with("/s"));
+        assert!(!info.to_string().contains(" s "));
+    }
+
+    #[tokio::test]
+    async fn test_bind_permissions() {
+        let path = temp_path("permissions.sock");
+        let socket = AdminSocket::bind(&path).unwrap();
+        let metadata = fs::symlink_metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        UnixStream::connect(&path).await.unwrap();
+
+        drop(socket);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_bind_stale_socket() {
+        let path = temp_path("stale.sock");
+        // a listener that's gone leaves its socket behind
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let _socket = AdminSocket::bind(&path).unwrap();
+        UnixStream::connect(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bind_in_use() {
+        let path = temp_path("in-use.sock");
+        let _socket = AdminSocket::bind(&path).unwrap();
+        assert!(AdminSocket::bind(&path).is_err());
+        // the socket of the running server is kept
+        UnixStream::connect(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bind_not_a_socket() {
+        let path = temp_path("file");
+        fs::write(&path, "data").unwrap();
+        assert!(AdminSocket::bind(&path).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "data");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use tokio::sync::watch;
+
+/// Limits how many tests the server runs at the same time. Tests that don't fit are
+/// either rejected or wait in a first come, first served queue.
+#[derive(Debug)]
+pub(crate) struct Admission {
+    /// 0 for unlimited
+    max_tests: usize,
+    state: Mutex<State>,
+    /// Bumped whenever a slot is released or a queued test leaves the queue
+    changed: watch::Sender<u64>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    next_id: u64,
+    /// The expected end of every admitted test, by slot id
+    running: Vec<(u64, Instant)>,
+    /// Ticket ids of the queued tests, the first one is admitted next
+    queue: VecDeque<u64>,
+    /// Number of tests that ran to completion
+    finished: u64,
+    /// No more tests are admitted
+    draining: bool,
+}
+
+impl Admission {
+    pub(crate) fn new(max_tests: usize) -> Arc<Self> {
+        Arc::new(Admission {
+            max_tests,
+            state: Mutex::new(State::default()),
+            changed: watch::channel(0).0,
+        })
+    }
+
+    fn is_full(&self, state: &State) -> bool {
+        self.max_tests != 0 && state.running.len() >= self.max_tests
+    }
+
+    fn admit(self: &Arc<Self>, state: &mut State, duration: Duration) -> TestSlot {
+        let id = state.next_id;
+        state.next_id += 1;
+        state.running.push((id, Instant::now() + duration));
+        TestSlot {
+            admission: self.clone(),
+            id,
+        }
+    }
+
+    /// Admits a test expected to run for `duration` if there's a free slot and nobody is
+    /// queued. Otherwise returns the number of seconds after which a slot is likely free.
+    pub(crate) fn try_admit(self: &Arc<Self>, duration: Duration) -> Result<TestSlot, u64> {
+        let mut state = self.state.lock();
+        if self.is_full(&state) || !state.queue.is_empty() {
+            return Err(self.retry_after(&state));
+        }
+
+        Ok(self.admit(&mut state, duration))
+    }
+
+    /// Number of seconds after which a slot is likely free
+    pub(crate) fn estimate_retry_after(&self) -> u64 {
+        self.retry_after(&self.state.lock())
+    }
+
+    fn retry_after(&self, state: &State) -> u64 {
+        let now = Instant::now();
+        state
+            .running
+            .iter()
+            .map(|(_, end)| end.saturating_duration_since(now))
+            .min()
+            .unwrap_or_default()
+            .as_secs()
+            + 1
+    }
+
+    /// Queues a test, it is admitted through `QueueTicket::try_admit`
+    pub(crate) fn enqueue(self: &Arc<Self>) -> QueueTicket {
+        let mut state = self.state.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.queue.push_back(id);
+        QueueTicket {
+            admission: self.clone(),
+            id,
+            changed: self.changed.subscribe(),
+        }
+    }
+
+    /// Number of admitted tests, this includes tests that haven't started yet
+    pub(crate) fn running(&self) -> usize {
+        self.state.lock().running.len()
+    }
+
+    /// Stops admitting tests, including the queued ones
+    pub(crate) fn drain(&self) {
+        self.state.lock().draining = true;
+        // wakes up the queued tests so that they notice
+        self.notify();
+    }
+
+    pub(crate) fn is_draining(&self) -> bool {
+        self.state.lock().draining
+    }
+
+    /// Whether no test is admitted or queued
+    pub(crate) fn is_idle(&self) -> bool {
+        let state = self.state.lock();
+        state.running.is_empty() && state.queue.is_empty()
+    }
+
+    /// Number of tests that ran to completion
+    pub(crate) fn finished(&self) -> u64 {
+        self.state.lock().finished
+    }
+
+    /// Changes whenever a slot is released or the queue changes
+    pub(crate) fn subscribe(&self) -> watch::Receiver<u64> {
+        self.changed.subscribe()
+    }
+
+    fn notify(&self) {
+        self.changed.send_modify(|generation| *generation += 1);
+    }
+}
+
+/// Held while an admitted test is outstanding or running, frees the slot when dropped
+#[derive(Debug)]
+pub(crate) struct TestSlot {
+    admission: Arc<Admission>,
+    id: u64,
+}
+
+impl TestSlot {
+    /// Identifies the test while it's admitted
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Frees the slot of a test that ran to completion
+    pub(crate) fn finish(self) {
+        self.admission.state.lock().finished += 1;
+    }
+}
+
+impl Drop for TestSlot {
+    fn drop(&mut self) {
+        self.admission
+            .state
+            .lock()
+            .running
+            .retain(|(id, _)| *id != self.id);
+        self.admission.notify();
+    }
+}
+
+/// A place in the queue, dropping it leaves the queue
+#[derive(Debug)]
+pub(crate) struct QueueTicket {
+    admission: Arc<Admission>,
+    id: u64,
+    changed: watch::Receiver<u64>,
+}
+
+impl QueueTicket {
+    /// Admits the test if it's at the front of the queue and a slot is free. Otherwise
+    /// returns its 1-based queue position.
+    pub(crate) fn try_admit(&mut self, duration: Duration) -> Result<TestSlot, usize> {
+        self.changed.borrow_and_update();
+        let mut state = self.admission.state.lock();
+        let position = state
+            .queue
+            .iter()
+            .position(|id| *id == self.id)
+            .expect("a ticket stays queued until it's dropped");
+        if position != 0 || self.admission.is_full(&state) {
+            return Err(position + 1);
+        }
+
+        state.queue.pop_front();
+        let slot = self.admission.admit(&mut state, duration);
+        // the slot is accounted for, the ticket must not remove anything on drop
+        self.id = u64::MAX;
+        Ok(slot)
+    }
+
+    /// Waits until a slot has been released or the queue has changed
+    pub(crate) async fn changed(&mut self) {
+        // the sender lives in the admission this ticket holds on to
+        let _ = self.changed.changed().await;
+    }
+}
+
+impl Drop for QueueTicket {
+    fn drop(&mut self) {
+        let mut state = self.admission.state.lock();
+        let len = state.queue.len();
+        state.queue.retain(|id| *id != self.id);
+        if state.queue.len() != len {
+            drop(state);
+            self.admission.notify();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_admission() {
+        let admission = Admission::new(1);
+        let slot = admission.try_admit(Duration::from_secs(10)).unwrap();
+        let retry_after = admission.try_admit(Duration::from_secs(10)).unwrap_err();
+        assert!((10..=11).contains(&retry_after));
+
+        let mut first = admission.enqueue();
+        let mut second = admission.enqueue();
+        assert_eq!(first.try_admit(Duration::ZERO).unwrap_err(), 1);
+        assert_eq!(second.try_admit(Duration::ZERO).unwrap_err(), 2);
+
+        drop(slot);
+        // queued tests go first
+        assert!(admission.try_admit(Duration::ZERO).is_err());
+        assert_eq!(second.try_admit(Duration::ZERO).unwrap_err(), 2);
+        let slot = first.try_admit(Duration::ZERO).unwrap();
+        drop(first);
+        assert_eq!(second.try_admit(Duration::ZERO).unwrap_err(), 1);
+        assert_eq!(admission.running(), 1);
+
+        drop(slot);
+        assert!(second.try_admit(Duration::ZERO).is_ok());
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Mixed into the MAC so that it can't be confused with other uses of the key
+const CONTEXT: &[u8] = b"netbench auth v1";
+
+/// Sent by the server after its hello if it requires authentication
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct AuthChallengeMessage {
+    pub(crate) nonce: [u8; 32],
+}
+
+impl AuthChallengeMessage {
+    pub(crate) fn new() -> Self {
+        AuthChallengeMessage {
+            nonce: rand::random(),
+        }
+    }
+}
+
+/// The client's answer to an `AuthChallengeMessage`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct AuthResponseMessage {
+    pub(crate) user: String,
+    /// HMAC-SHA256 over the challenge nonce with the user's key
+    pub(crate) mac: [u8; 32],
+}
+
+fn new_mac(key: &[u8], nonce: &[u8; 32]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(CONTEXT);
+    mac.update(nonce);
+    mac
+}
+
+/// Reads a hex encoded key, surrounding whitespace is ignored
+fn parse_key(key: &str) -> Result<Vec<u8>> {
+    let key = hex::decode(key.trim()).context("the key isn't valid hex")?;
+    if key.is_empty() {
+        bail!("the key is empty");
+    }
+
+    Ok(key)
+}
+
+/// The user name and pre-shared key a client authenticates with
+#[derive(Clone)]
+pub struct Credentials {
+    pub user: String,
+    pub key: Vec<u8>,
+}
+
+impl Credentials {
+    /// Reads the hex encoded key of `user` from `path`
+    pub fn from_key_file(user: String, path: &Path) -> Result<Self> {
+        let key = fs::read_to_string(path)
+            .with_context(|| format!("failed to read key file {}", path.display()))?;
+        let key =
+            parse_key(&key).with_context(|| format!("invalid key file {}", path.display()))?;
+
+        Ok(Credentials { user, key })
+    }
+
+    pub(crate) fn respond(&self, challenge: &AuthChallengeMessage) -> AuthResponseMessage {
+        AuthResponseMessage {
+            user: self.user.clone(),
+            mac: new_mac(&self.key, &challenge.nonce)
+                .finalize()
+                .into_bytes()
+                .into(),
+        }
+    }
+}
+
+// keep the key out of logs
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("user", &self.user)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The users that may run tests on a server and their pre-shared keys
+#[derive(Default)]
+pub struct KeyTable(HashMap<String, Vec<u8>>);
+
+impl KeyTable {
+    /// Reads a table with one `<user> <hex key>` pair per line. Empty lines and lines
+    /// starting with `#` are ignored.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let table = fs::read_to_string(path)
+            .with_context(|| format!("failed to read key table {}", path.display()))?;
+        table
+            .parse()
+            .with_context(|| format!("invalid key table {}", path.display()))
+    }
+
+    pub(crate) fn verify(
+        &self,
+        challenge: &AuthChallengeMessage,
+        response: &AuthResponseMessage,
+    ) -> bool {
+        match self.0.get(&response.user) {
+            Some(key) => new_mac(key, &challenge.nonce)
+                .verify_slice(&response.mac)
+                .is_ok(),
+            None => false,
+        }
+    }
+}
+
+impl std::str::FromStr for KeyTable {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut table = HashMap::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((user, key)) = line.split_once(char::is_whitespace) else {
+                bail!("line {}: expected a user and a key", i + 1);
+            };
+            let key = parse_key(key).with_context(|| format!("line {}", i + 1))?;
+            if table.insert(user.to_string(), key).is_some() {
+                bail!("line {}: duplicate user {user}", i + 1);
+            }
+        }
+
+        Ok(KeyTable(table))
+    }
+}
+
+impl std::fmt::Debug for KeyTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_challenge_response() {
+        let table: KeyTable = "# lab users\nalice 00112233\n\nbob   deadbeef\n"
+            .parse()
+            .unwrap();
+        let alice = Credentials {
+            user: "alice".to_string(),
+            key: vec![0x00, 0x11, 0x22, 0x33],
+        };
+        let challenge = AuthChallengeMessage::new();
+        assert!(table.verify(&challenge, &alice.respond(&challenge)));
+
+        // a response to a different challenge
+        assert!(!table.verify(&AuthChallengeMessage::new(), &alice.respond(&challenge)));
+
+        let mallory = Credentials {
+            user: "bob".to_string(),
+            key: vec![0x00, 0x11, 0x22, 0x33],
+        };
+        assert!(!table.verify(&challenge, &mallory.respond(&challenge)));
+
+        let unknown = Credentials {
+            user: "eve".to_string(),
+            ..alice
+        };
+        assert!(!table.verify(&challenge, &unknown.respond(&challenge)));
+
+        assert!("alice".parse::<KeyTable>().is_err());
+        assert!("alice zz".parse::<KeyTable>().is_err());
+        assert!("alice 00\nalice 11".parse::<KeyTable>().is_err());
+    }
+}
@@ -1,53 +1,966 @@
 use crate::{
-    tcp_test::TCPTest, test_manager, ClientConfig, Direction, EndCondition, MessageID,
-    NewTestMessage, Protocol, Role, TestAssociationMessage,
+    auth::{AuthChallengeMessage, Credentials},
+    sockopt,
+    stats::RepeatedTest,
+    sweep::{self, Sweep, SweepGrid, SweepPoint},
+    tcp_test::{Reconnect, TCPTest},
+    test_manager::{self, TestMonitor},
+    CancelTestMessage, ClientConfig, ErrorMessage, HeartbeatConfig, HelloMessage, MessageID,
+    MessageType, NBError, NBytes, NewTestMessage, Protocol, Role, TCPSocketOptions,
+    TestAcceptedMessage, TestAssociationMessage, TestConfig, TestQueuedMessage, TestResultsMessage,
+    TestSummary, TestUpdate, TestUpdatedMessage, UpdateTestMessage, MESSAGE_READ_TIMEOUT,
 };
-use anyhow::Result;
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::fmt;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
 use std::time::Duration as StdDuration;
-use time::Duration;
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::sync::{mpsc, Mutex, MutexGuard};
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, MissedTickBehavior};
+use tracing::{debug, warn};
+
+type Reply = Result<(MessageType, Vec<u8>), NBError>;
+/// How often a test that follows another one is submitted while the server is busy
+const NEXT_TEST_ATTEMPTS: usize = 3;
+/// The monitors of the running tests by their code
+type RunningTests = Arc<parking_lot::Mutex<Vec<([u8; 32], Arc<TestMonitor>)>>>;
 
 #[derive(Debug)]
 pub struct Client {
-    stream: TcpStream,
+    /// Shared with the handles of the running tests
+    connection: Arc<ControlConnection>,
     config: ClientConfig,
+    server_hello: HelloMessage,
+    /// Set if the server supports heartbeats
+    heartbeat: Option<HeartbeatConfig>,
+    /// Number of tests started through `Client::start_test`
+    started: usize,
 }
 
 impl Client {
     pub async fn new(config: ClientConfig) -> Result<Self> {
-        let stream = TcpStream::connect(&config.addr).await?;
-        Ok(Client { stream, config })
+        let (mut stream, server_hello) = Client::connect(config.addr).await?;
+        if server_hello.auth_required {
+            let credentials = config
+                .credentials
+                .as_ref()
+                .ok_or(NBError::AuthenticationRequired)?;
+            Client::authenticate(&mut stream, credentials).await?;
+        }
+
+        let heartbeat = server_hello
+            .capabilities
+            .options
+            .iter()
+            .any(|option| option == "heartbeats")
+            .then_some(config.common.heartbeat);
+        Ok(Client {
+            connection: ControlConnection::new(stream, heartbeat),
+            config,
+            server_hello,
+            heartbeat,
+            started: 0,
+        })
+    }
+
+    /// Asks the server at `addr` for its version and capabilities without running a test
+    pub async fn query_server(addr: SocketAddr) -> Result<HelloMessage> {
+        let (mut stream, server_hello) = Client::connect(addr).await?;
+        crate::send_message((), MessageID::CLOSE_MESSAGE, &mut stream).await?;
+        Ok(server_hello)
+    }
+
+    /// The version and capabilities the server reported
+    pub fn server_hello(&self) -> &HelloMessage {
+        &self.server_hello
+    }
+
+    fn status(&self, message: fmt::Arguments<'_>) {
+        status(self.config.quiet, message);
+    }
+
+    fn server_supports(&self, option: &str) -> bool {
+        self.server_hello
+            .capabilities
+            .options
+            .iter()
+            .any(|supported| supported == option)
+    }
+
+    /// Cancels the tests of this client from elsewhere, e.g. when the process is interrupted
+    pub fn canceller(&self) -> Canceller {
+        Canceller(self.connection.clone())
     }
 
-    pub async fn start_new_test(&mut self) -> Result<()> {
+    /// Answers the server's challenge and waits for it to accept the answer
+    async fn authenticate(stream: &mut TcpStream, credentials: &Credentials) -> Result<()> {
+        let challenge: AuthChallengeMessage = match read_reply(stream).await? {
+            (MessageType::AuthChallenge(_), body) => crate::decode_message(&body)?,
+            (msg_type, _) => return Err(NBError::UnexpectedMessage(msg_type.name()).into()),
+        };
+        crate::send_message(
+            credentials.respond(&challenge),
+            MessageID::AUTH_RESPONSE_MESSAGE,
+            stream,
+        )
+        .await?;
+
+        match read_reply(stream).await? {
+            (MessageType::AuthAccepted(_), _) => Ok(()),
+            (msg_type, _) => Err(NBError::UnexpectedMessage(msg_type.name()).into()),
+        }
+    }
+
+    /// Opens the control connection and exchanges the hello messages
+    async fn connect(addr: SocketAddr) -> Result<(TcpStream, HelloMessage)> {
+        let mut stream = TcpStream::connect(addr).await?;
+        crate::send_message(HelloMessage::new(), MessageID::HELLO_MESSAGE, &mut stream).await?;
+
+        let server_hello: HelloMessage = match read_reply(&mut stream).await? {
+            (MessageType::Hello(_), body) => crate::decode_message(&body)?,
+            (msg_type, _) => return Err(NBError::UnexpectedMessage(msg_type.name()).into()),
+        };
+        server_hello.check_compatible()?;
+
+        Ok((stream, server_hello))
+    }
+
+    /// Runs the configured test and waits for it to finish
+    pub async fn start_new_test(&mut self) -> Result<TestSummary> {
+        self.run_test(self.config.test.clone()).await
+    }
+
+    /// Runs `test` right after another one. The server might not have released the
+    /// previous test yet, so a busy server is asked again after the time it suggests.
+    async fn start_next_test(&mut self, test: &TestConfig) -> Result<TestSummary> {
+        for _ in 1..NEXT_TEST_ATTEMPTS {
+            match self.run_test(test.clone()).await {
+                Err(e) => match e.downcast_ref::<NBError>() {
+                    Some(NBError::ServerBusy(retry_after)) => {
+                        debug!("server busy, starting the next test in {retry_after} s");
+                        tokio::time::sleep(StdDuration::from_secs(*retry_after)).await;
+                    }
+                    _ => return Err(e),
+                },
+                result => return result,
+            }
+        }
+
+        self.run_test(test.clone()).await
+    }
+
+    async fn run_test(&mut self, test: TestConfig) -> Result<TestSummary> {
+        let monitor = TestMonitor::default().quiet(self.config.quiet);
+        self.spawn(test, monitor).await?.join().await
+    }
+
+    /// Starts a test in the background and returns right after the server accepted it,
+    /// so that several tests can run over the same control connection at the same time.
+    /// The output of the test is prefixed with its id.
+    ///
+    /// A server runs one test at a time unless it was started with a higher `--max-tests`,
+    /// tests beyond its limit fail with `NBError::ServerBusy` or wait in its queue.
+    pub async fn start_test(&mut self, test: TestConfig) -> Result<TestHandle> {
+        let label = self.started.to_string();
+        let monitor = TestMonitor::labeled(label).quiet(self.config.quiet);
+        self.spawn(test, monitor).await
+    }
+
+    async fn spawn(&mut self, test: TestConfig, monitor: TestMonitor) -> Result<TestHandle> {
+        let changes = test.changes.clone();
+        let (test_message, test_socket) = self.submit(test).await?;
+        let test = self.create_test(test_message.clone(), test_socket)?;
+        let id = self.started;
+        self.started += 1;
+
+        let monitor = Arc::new(monitor.peer_results(self.server_supports("testResults")));
+        self.connection
+            .tests
+            .lock()
+            .push((test_message.code, monitor.clone()));
+        let control = TestControl {
+            connection: self.connection.clone(),
+            code: test_message.code,
+            monitor,
+            supported: self.server_supports("testUpdates"),
+        };
+        let task = {
+            let control = control.clone();
+            tokio::spawn(async move {
+                let schedule = tokio::spawn(control.clone().apply_schedule(changes));
+                let summary = test_manager::run(test, Role::Client, &control.monitor).await;
+                schedule.abort();
+                let code = control.code;
+                control
+                    .connection
+                    .tests
+                    .lock()
+                    .retain(|test| test.0 != code);
+                summary
+            })
+        };
+
+        Ok(TestHandle {
+            id,
+            test: test_message,
+            control,
+            task,
+        })
+    }
+
+    /// Submits a test to the server, waiting while it's queued, and connects its data
+    /// socket once it has been accepted
+    async fn submit(&mut self, test: TestConfig) -> Result<(NewTestMessage, TcpStream)> {
         let code = rand::random();
         let new_test_message = NewTestMessage {
-            bw: self.config.bw.unwrap_or(0),
-            direction: Direction::ClientToServer,
-            protocol: self.config.proto,
+            bw: test.bw.unwrap_or(0),
+            pacing: test.pacing,
+            direction: test.direction,
+            protocol: test.proto,
             code,
-            end_condition: EndCondition::Time(Duration::new(10, 0)),
+            end_condition: test.end_condition,
+            resilient: test.resilient,
         };
+        new_test_message.check_supported()?;
 
-        //let new_test_message_clone = new_test_message.clone();
+        let missing = self.server_hello.capabilities.missing(&new_test_message);
+        if !missing.is_empty() {
+            return Err(NBError::MissingCapabilities(missing.join(", ")).into());
+        }
+
+        // no other request gets in between until the test has been accepted
+        let mut replies = self
+            .connection
+            .request(&new_test_message, MessageID::NEW_TEST_MESSAGE)
+            .await?;
+        let accepted: TestAcceptedMessage = loop {
+            match replies.next().await? {
+                (MessageType::TestAccepted(_), body) => break crate::decode_message(&body)?,
+                (MessageType::TestQueued(_), body) => {
+                    let queued: TestQueuedMessage = crate::decode_message(&body)?;
+                    let position = queued.position;
+                    self.status(format_args!(
+                        "The server is busy, queued at position {position}"
+                    ));
+                }
+                (msg_type, _) => return Err(NBError::UnexpectedMessage(msg_type.name()).into()),
+            }
+        };
+        drop(replies);
+        for limit in &accepted.limits {
+            self.status(format_args!("Server policy: {limit}"));
+        }
+
+        // the server may have lowered some of the values, the test runs with its version
+        let test_message = accepted.test;
+        let test_socket = self.data_connector(&test_message).connect().await?;
+        Ok((test_message, test_socket))
+    }
+
+    fn create_test(
+        &self,
+        test_message: NewTestMessage,
+        test_socket: TcpStream,
+    ) -> Result<TCPTest, NBError> {
+        let connector = self.data_connector(&test_message);
+        let resilient = test_message.resilient;
+        let test = match test_message.protocol {
+            Protocol::TCP(_) => {
+                TCPTest::new(test_message, Role::Client, test_socket, &self.config.common)
+            }
+            _ => return Err(NBError::Unsupported("only TCP tests are implemented")),
+        };
+        if resilient {
+            Ok(test.with_reconnect(Reconnect::Connect(connector)))
+        } else {
+            Ok(test)
+        }
+    }
+
+    fn data_connector(&self, test: &NewTestMessage) -> DataConnector {
+        DataConnector {
+            addr: self.config.addr,
+            socket_options: match &test.protocol {
+                Protocol::TCP(tcp_test_info) => Some(tcp_test_info.socket_options.clone()),
+                _ => None,
+            },
+            user_timeout: self.heartbeat.map(|heartbeat| heartbeat.timeout),
+            code: test.code,
+        }
+    }
+
+    /// Runs the configured TCP test once per congestion control algorithm.
+    pub async fn compare_congestion_control(
+        &mut self,
+        algorithms: &[String],
+    ) -> Result<CongestionComparison> {
+        let mut results = Vec::with_capacity(algorithms.len());
+        for algorithm in algorithms {
+            let mut test = self.config.test.clone();
+            match &mut test.proto {
+                Protocol::TCP(tcp_test_info) => {
+                    tcp_test_info.socket_options.congestion = Some(algorithm.clone());
+                }
+                _ => bail!("congestion control can only be compared for TCP tests"),
+            }
+
+            let summary = self.start_next_test(&test).await?;
+            let cancelled = summary.cancelled;
+            results.push((algorithm.clone(), summary));
+            if cancelled {
+                break;
+            }
+        }
+
+        Ok(CongestionComparison(results))
+    }
+
+    /// Runs the configured test `runs` times, waiting `pause` between the runs, and
+    /// aggregates the results. Stops early if a run is cancelled or loses the server.
+    pub async fn repeat_test(
+        &mut self,
+        runs: usize,
+        pause: Option<StdDuration>,
+    ) -> Result<RepeatedTest> {
+        let test = self.config.test.clone();
+        let mut summaries = Vec::with_capacity(runs);
+        for run in 1..=runs {
+            if run > 1 {
+                if let Some(pause) = pause {
+                    tokio::time::sleep(pause).await;
+                }
+            }
+            self.status(format_args!("Run {run} of {runs}"));
+
+            let summary = self.start_next_test(&test).await?;
+            let complete = !summary.cancelled && summary.peer_lost.is_none();
+            summaries.push(summary);
+            if !complete {
+                break;
+            }
+        }
+
+        Ok(RepeatedTest::from_runs(summaries))
+    }
+
+    /// Runs the configured TCP test once per combination of the values in `grid`. Stops
+    /// early if a test is cancelled or loses the server.
+    pub async fn sweep(&mut self, grid: &SweepGrid) -> Result<Sweep> {
+        let test = &self.config.test;
+        let Protocol::TCP(tcp_test_info) = &test.proto else {
+            bail!("only TCP tests can be swept");
+        };
+        let or_configured = |values: &[u64], configured| match values {
+            [] => vec![configured],
+            values => values.to_vec(),
+        };
+        let lengths = or_configured(&grid.lengths, tcp_test_info.send_buf_size);
+        let bitrates = or_configured(&grid.bitrates, test.bw.unwrap_or(0));
+        let directions = match grid.directions.as_slice() {
+            [] => vec![test.direction],
+            directions => directions.to_vec(),
+        };
+
+        let total = lengths.len() * bitrates.len() * directions.len();
+        let mut points = Vec::with_capacity(total);
+        for &length in &lengths {
+            for &bitrate in &bitrates {
+                for &direction in &directions {
+                    let mut test = self.config.test.clone();
+                    if let Protocol::TCP(tcp_test_info) = &mut test.proto {
+                        tcp_test_info.send_buf_size = length;
+                        tcp_test_info.recv_buf_size = length;
+                    }
+                    test.bw = (bitrate != 0).then_some(bitrate);
+                    test.direction = direction;
+                    self.status(format_args!(
+                        "Run {} of {total}: length {length}, bitrate {}, {}",
+                        points.len() + 1,
+                        match bitrate {
+                            0 => String::from("unlimited"),
+                            bitrate => bitrate.to_string(),
+                        },
+                        sweep::direction_name(direction)
+                    ));
+
+                    let summary = self.start_next_test(&test).await?;
+                    let complete = !summary.cancelled && summary.peer_lost.is_none();
+                    points.push(SweepPoint {
+                        length,
+                        bitrate,
+                        direction,
+                        summary,
+                    });
+                    if !complete {
+                        return Ok(Sweep(points));
+                    }
+                }
+            }
+        }
+
+        Ok(Sweep(points))
+    }
+}
+
+/// Opens the data connection of a test, again whenever a resilient test lost it
+#[derive(Debug, Clone)]
+pub(crate) struct DataConnector {
+    addr: SocketAddr,
+    socket_options: Option<TCPSocketOptions>,
+    /// Only set if heartbeats are exchanged, which tell a lost peer apart from a slow one
+    user_timeout: Option<StdDuration>,
+    code: [u8; 32],
+}
+
+impl DataConnector {
+    /// Connects the data socket and associates it with the test. The socket options are
+    /// applied before the handshake so that the window size and MSS are taken into account.
+    pub(crate) async fn connect(&self) -> Result<TcpStream, NBError> {
+        let socket = if self.addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+
+        if let Some(socket_options) = &self.socket_options {
+            if socket_options.ecn {
+                sockopt::check_tcp_ecn()?;
+            }
+            sockopt::apply_tcp_options(socket.as_raw_fd(), socket_options)?;
+        }
+        if let Some(user_timeout) = self.user_timeout {
+            if let Err(e) = sockopt::set_user_timeout(socket.as_raw_fd(), user_timeout) {
+                warn!("failed to set TCP_USER_TIMEOUT: {e}");
+            }
+        }
+
+        let mut stream = socket.connect(self.addr).await?;
+        let message = TestAssociationMessage { code: self.code };
+        crate::send_message(message, MessageID::TEST_ASSOCIATION_MESSAGE, &mut stream).await?;
+        Ok(stream)
+    }
+}
+
+/// The client side of the control connection. A background task reads the messages of
+/// the server, it passes replies on to the pending request and handles the messages the
+/// server sends on its own.
+#[derive(Debug)]
+struct ControlConnection {
+    writer: Mutex<OwnedWriteHalf>,
+    /// Replies to requests, a request holds the lock until it got its final reply
+    replies: Mutex<mpsc::Receiver<Reply>>,
+    /// Set while a request waits for its replies, other messages aren't replies
+    pending: Arc<AtomicBool>,
+    tests: RunningTests,
+}
+
+impl ControlConnection {
+    /// Heartbeats are only exchanged if `heartbeat` is set, i.e. the server supports them
+    fn new(stream: TcpStream, heartbeat: Option<HeartbeatConfig>) -> Arc<Self> {
+        let (reader, writer) = stream.into_split();
+        let (reply_tx, replies) = mpsc::channel(4);
+        let tests = Arc::default();
+        let pending = Arc::default();
+        let peer_timeout = heartbeat.map(|heartbeat| heartbeat.timeout);
+        tokio::spawn(read_messages(
+            reader,
+            reply_tx,
+            Arc::clone(&pending),
+            Arc::clone(&tests),
+            peer_timeout,
+        ));
+
+        let connection = Arc::new(ControlConnection {
+            writer: Mutex::new(writer),
+            replies: Mutex::new(replies),
+            pending,
+            tests,
+        });
+        if let Some(heartbeat) = heartbeat {
+            tokio::spawn(send_heartbeats(
+                Arc::downgrade(&connection),
+                heartbeat.interval,
+            ));
+        }
+        connection
+    }
+
+    async fn send<T: Serialize>(&self, message: T, message_id: u16) -> Result<(), NBError> {
+        crate::send_message(message, message_id, &mut *self.writer.lock().await).await
+    }
+
+    /// Sends a request and returns its replies. No other request is sent until they are
+    /// dropped.
+    async fn request<T: Serialize>(
+        &self,
+        message: T,
+        message_id: u16,
+    ) -> Result<Replies<'_>, NBError> {
+        let replies = Replies {
+            receiver: self.replies.lock().await,
+            connection: self,
+        };
+        self.pending.store(true, Ordering::SeqCst);
+        self.send(message, message_id).await?;
+        Ok(replies)
+    }
+
+    /// Cancels a test on the server and locally
+    async fn cancel(&self, code: [u8; 32], monitor: &TestMonitor) {
+        let message = CancelTestMessage { code };
+        if let Err(e) = self.send(message, MessageID::CANCEL_TEST_MESSAGE).await {
+            warn!("failed to cancel the test on the server: {e}");
+        }
+        monitor.cancel();
+    }
+}
+
+/// Prints the progress of the client, to stderr if stdout holds the results
+fn status(quiet: bool, message: fmt::Arguments<'_>) {
+    match quiet {
+        false => println!("{message}"),
+        true => eprintln!("{message}"),
+    }
+}
+
+/// Sends heartbeats until the control connection is dropped or fails
+async fn send_heartbeats(connection: Weak<ControlConnection>, interval: StdDuration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let Some(connection) = connection.upgrade() else {
+            return;
+        };
+        if let Err(e) = connection.send((), MessageID::HEARTBEAT_MESSAGE).await {
+            debug!("failed to send a heartbeat: {e}");
+            return;
+        }
+    }
+}
+
+/// Reads the messages of the server until the control connection is closed. If nothing
+/// has been heard from the server for `peer_timeout`, the running tests are cancelled.
+async fn read_messages(
+    mut reader: OwnedReadHalf,
+    replies: mpsc::Sender<Reply>,
+    pending: Arc<AtomicBool>,
+    tests: RunningTests,
+    peer_timeout: Option<StdDuration>,
+) {
+    let mut last_heard = Instant::now();
+    loop {
+        let message = match peer_timeout {
+            Some(peer_timeout) => {
+                match tokio::time::timeout(peer_timeout, read_message(&mut reader, None)).await {
+                    Ok(message) => message,
+                    Err(_) => {
+                        warn!("nothing heard from the server for {peer_timeout:?}");
+                        for (_, monitor) in tests.lock().iter() {
+                            monitor.lose_peer(last_heard);
+                        }
+                        let _ = replies.try_send(Err(NBError::PeerLost(peer_timeout)));
+                        return;
+                    }
+                }
+            }
+            None => read_message(&mut reader, None).await,
+        };
+        last_heard = Instant::now();
+
+        let message = match message {
+            Ok(message) => message,
+            Err(e) => {
+                debug!("stopped reading the control connection: {e}");
+                // fails if no request is waiting, the closed channel tells later ones
+                let _ = replies.try_send(Err(e));
+                return;
+            }
+        };
+
+        match message {
+            (MessageType::CancelTest(_), body) => {
+                match crate::decode_message::<CancelTestMessage>(&body) {
+                    Ok(cancel) => {
+                        debug!("the server cancelled a test");
+                        let tests = tests.lock();
+                        if let Some((_, monitor)) = tests.iter().find(|test| test.0 == cancel.code)
+                        {
+                            monitor.cancel();
+                        }
+                    }
+                    Err(e) => warn!("invalid CancelTest message: {e}"),
+                }
+            }
+            (MessageType::TestResults(_), body) => {
+                match crate::decode_message::<TestResultsMessage>(&body) {
+                    Ok(results) => {
+                        let tests = tests.lock();
+                        if let Some((_, monitor)) = tests.iter().find(|test| test.0 == results.code)
+                        {
+                            monitor.set_peer_results(results);
+                        }
+                    }
+                    Err(e) => warn!("invalid TestResults message: {e}"),
+                }
+            }
+            (MessageType::Heartbeat(_), _) => {}
+            reply if pending.load(Ordering::SeqCst) => {
+                if replies.send(Ok(reply)).await.is_err() {
+                    return;
+                }
+            }
+            // e.g. why the server closes the connection
+            (MessageType::MsgError(_), body) => {
+                match crate::decode_message::<ErrorMessage>(&body) {
+                    Ok(error) => warn!("the server reported an error: {}", error.message),
+                    Err(e) => warn!("invalid MsgError message: {e}"),
+                }
+            }
+            (msg_type, _) => warn!("ignoring unexpected {} message", msg_type.name()),
+        }
+    }
+}
+
+/// The replies to a request
+struct Replies<'a> {
+    receiver: MutexGuard<'a, mpsc::Receiver<Reply>>,
+    connection: &'a ControlConnection,
+}
+
+impl Replies<'_> {
+    /// Waits for the next reply, turning an error message into an error. A reply that
+    /// comes too late would be taken for the reply to the next request, so the control
+    /// connection is closed instead.
+    async fn next(&mut self) -> Reply {
+        match tokio::time::timeout(MESSAGE_READ_TIMEOUT, self.receiver.recv()).await {
+            Ok(Some(reply)) => check_reply(reply?),
+            Ok(None) => Err(NBError::ConnectionClosed),
+            Err(_) => {
+                warn!("the server didn't answer in time, closing the control connection");
+                let _ = self.connection.writer.lock().await.shutdown().await;
+                Err(NBError::Timeout)
+            }
+        }
+    }
+}
+
+impl Drop for Replies<'_> {
+    fn drop(&mut self) {
+        self.connection.pending.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Cancels the running tests of a client
+#[derive(Debug, Clone)]
+pub struct Canceller(Arc<ControlConnection>);
+
+impl Canceller {
+    /// Cancels the running tests on both sides. Returns false if no test was running.
+    pub async fn cancel_all(&self) -> bool {
+        let tests = self.0.tests.lock().clone();
+        for (code, monitor) in &tests {
+            self.0.cancel(*code, monitor).await;
+        }
+
+        !tests.is_empty()
+    }
+}
+
+/// Changes a running test on both sides
+#[derive(Debug, Clone)]
+struct TestControl {
+    connection: Arc<ControlConnection>,
+    code: [u8; 32],
+    monitor: Arc<TestMonitor>,
+    /// Whether the server can change running tests
+    supported: bool,
+}
+
+impl TestControl {
+    async fn update(&self, update: TestUpdate) -> Result<TestUpdate> {
+        if !self.supported {
+            return Err(NBError::MissingCapabilities("testUpdates".to_string()).into());
+        }
+
+        let message = UpdateTestMessage {
+            code: self.code,
+            update,
+        };
+        let updated: TestUpdatedMessage = {
+            let mut replies = self
+                .connection
+                .request(message, MessageID::UPDATE_TEST_MESSAGE)
+                .await?;
+            match replies.next().await? {
+                (MessageType::TestUpdated(_), body) => crate::decode_message(&body)?,
+                (msg_type, _) => return Err(NBError::UnexpectedMessage(msg_type.name()).into()),
+            }
+        };
+
+        let prefix = self.monitor.prefix();
+        for limit in &updated.limits {
+            status(
+                self.monitor.is_quiet(),
+                format_args!("{prefix}Server policy: {limit}"),
+            );
+        }
+        // the server has applied it already
+        self.monitor.update(updated.update);
+        Ok(updated.update)
+    }
+
+    /// Applies `changes` at their time since now
+    async fn apply_schedule(self, mut changes: Vec<(StdDuration, TestUpdate)>) {
+        changes.sort_by_key(|(at, _)| *at);
+        let start = tokio::time::Instant::now();
+        for (at, update) in changes {
+            tokio::time::sleep_until(start + at).await;
+            if let Err(e) = self.update(update).await {
+                warn!("failed to change the test ({update}): {e}");
+            }
+        }
+    }
+}
+
+/// A test started through `Client::start_test`
+#[derive(Debug)]
+pub struct TestHandle {
+    id: usize,
+    test: NewTestMessage,
+    control: TestControl,
+    task: JoinHandle<TestSummary>,
+}
+
+impl TestHandle {
+    /// Numbers the tests of a client in the order they were started
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// The test as accepted by the server, which may have lowered some of the values
+    pub fn test(&self) -> &NewTestMessage {
+        &self.test
+    }
+
+    /// Summary of the intervals measured so far
+    pub fn summary(&self) -> TestSummary {
+        self.control.monitor.summary()
+    }
+
+    /// Ends the test early on both sides, `TestHandle::join` returns the results up to
+    /// this point
+    pub async fn cancel(&self) {
+        let control = &self.control;
+        control
+            .connection
+            .cancel(control.code, &control.monitor)
+            .await;
+    }
+
+    /// Changes the running test on both sides. Returns the change as it has been applied,
+    /// the server policy may have lowered a new bitrate or buffer size.
+    pub async fn update(&self, update: TestUpdate) -> Result<TestUpdate> {
+        self.control.update(update).await
+    }
+
+    /// Stops sending until the test is resumed, the test keeps running
+    pub async fn pause(&self) -> Result<()> {
+        self.update(TestUpdate::Pause).await.map(drop)
+    }
+
+    pub async fn resume(&self) -> Result<()> {
+        self.update(TestUpdate::Resume).await.map(drop)
+    }
+
+    /// Changes the target bitrate in bits per second, 0 for unlimited. Returns the
+    /// bitrate that has been applied.
+    pub async fn set_bitrate(&self, bw: u64) -> Result<u64> {
+        match self.update(TestUpdate::Bitrate(bw)).await? {
+            TestUpdate::Bitrate(bw) => Ok(bw),
+            update => bail!("the server applied {update:?} instead"),
+        }
+    }
+
+    /// Changes the length of the writes and of the reads, `None` keeps the length.
+    /// Returns the lengths that have been applied.
+    pub async fn set_buffer_sizes(
+        &self,
+        send: Option<u64>,
+        recv: Option<u64>,
+    ) -> Result<(Option<u64>, Option<u64>)> {
+        match self.update(TestUpdate::BufferSize { send, recv }).await? {
+            TestUpdate::BufferSize { send, recv } => Ok((send, recv)),
+            update => bail!("the server applied {update:?} instead"),
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Waits for the test to finish
+    pub async fn join(self) -> Result<TestSummary> {
+        Ok(self.task.await?)
+    }
+}
+
+/// Reads the server's reply to a request, turning an error message into an error
+async fn read_reply(stream: &mut TcpStream) -> Reply {
+    check_reply(read_message(stream, Some(MESSAGE_READ_TIMEOUT)).await?)
+}
+
+/// Reads the next message without decoding its body
+async fn read_message<R>(reader: &mut R, timeout: Option<StdDuration>) -> Reply
+where
+    R: AsyncRead + Unpin,
+{
+    let msg_type = crate::read_message_type(reader, timeout).await?;
+    let mut body = vec![0; msg_type.len()];
+    crate::read_body(reader, &mut body).await?;
+    Ok((msg_type, body))
+}
+
+/// Turns an error message of the server into an error
+fn check_reply((msg_type, body): (MessageType, Vec<u8>)) -> Reply {
+    if let MessageType::MsgError(_) = msg_type {
+        let error: ErrorMessage = crate::decode_message(&body)?;
+        return Err(match error.retry_after {
+            Some(retry_after) => NBError::ServerBusy(retry_after),
+            None => NBError::Peer(error.message),
+        });
+    }
+
+    Ok((msg_type, body))
+}
+
+/// Results of running the same test with different congestion control algorithms
+#[derive(Debug, Serialize)]
+pub struct CongestionComparison(pub Vec<(String, TestSummary)>);
+
+impl fmt::Display for CongestionComparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<12} | {:>16} | {:>11} | {:>10}",
+            "algorithm", "throughput", "retransmits", "rtt"
+        )?;
+        for (algorithm, summary) in &self.0 {
+            let throughput = NBytes::format_bits_per_second(summary.bits_per_second());
+            let retransmits = summary
+                .retransmits
+                .map_or_else(|| String::from("-"), |r| r.to_string());
+            let rtt = summary
+                .mean_rtt
+                .map_or_else(|| String::from("-"), |rtt| format!("{rtt:.3} ms"));
+            writeln!(
+                f,
+                "{:<12} | {:>16} | {:>11} | {:>10}",
+                algorithm,
+                format!("{throughput}/s"),
+                retransmits,
+                rtt
+            )?;
+        }
 
-        crate::send_message(
-            new_test_message,
-            MessageID::NEW_TEST_MESSAGE,
-            &mut self.stream,
-        )
-        .await?;
-        tokio::time::sleep(StdDuration::from_secs(1)).await;
-        let mut test_socket = TcpStream::connect(self.config.addr).await.unwrap();
-        let msg = TestAssociationMessage { code };
-        crate::send_message(msg, MessageID::TEST_ASSOCIATION_MESSAGE, &mut test_socket)
-            .await
-            .unwrap();
-        let test = match self.config.proto {
-            Protocol::TCP(_) => TCPTest::new(new_test_message, Role::Client, test_socket),
-            _ => todo!("only TCP is implemented so far"),
-        };
-        test_manager::run(test, Role::Client).await;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        AccessList, BasePreference, CommonConfig, Direction, EndCondition, Pacing, Policy, Server,
+        ServerConfig, SizePreference, TCPTestInfo,
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_tests() {
+        let common = CommonConfig {
+            format: SizePreference::Auto,
+            base: BasePreference::Base2,
+            file: None,
+            recv_file: None,
+            heartbeat: HeartbeatConfig::default(),
+        };
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = ServerConfig {
+            common: common.clone(),
+            addr,
+            keys: None,
+            access: AccessList::default(),
+            policy: Policy {
+                directions: Some(vec![Direction::ClientToServer]),
+                ..Default::default()
+            },
+            max_tests: 2,
+            queue_tests: false,
+            max_queue_wait: StdDuration::ZERO,
+            one_off: false,
+            idle_timeout: None,
+            admin_socket: None,
+        };
+        let (mut server, _) = Server::with_listener(config, listener).unwrap();
+        tokio::spawn(async move { server.accept().await });
+
+        let test = TestConfig {
+            bw: Some(10_000_000),
+            pacing: Pacing::Application,
+            proto: Protocol::TCP(TCPTestInfo {
+                recv_buf_size: 128 * 1024,
+                send_buf_size: 128 * 1024,
+                socket_options: Default::default(),
+                send_mode: Default::default(),
+                recv_mode: Default::default(),
+                backend: Default::default(),
+                payload: Default::default(),
+                verify: false,
+            }),
+            direction: Direction::ClientToServer,
+            end_condition: EndCondition::Time(time::Duration::SECOND),
+            changes: Vec::new(),
+            resilient: false,
+        };
+        let config = ClientConfig {
+            common,
+            addr,
+            test: test.clone(),
+            credentials: None,
+            quiet: true,
+        };
+        let mut client = Client::new(config).await.unwrap();
+        let first = client.start_test(test.clone()).await.unwrap();
+        // a rejected test leaves the connection and the running test alone
+        let reverse = TestConfig {
+            direction: Direction::ServerToClient,
+            ..test.clone()
+        };
+        let rejected = client.start_test(reverse).await.unwrap_err();
+        assert!(matches!(
+            rejected.downcast_ref::<NBError>(),
+            Some(NBError::Peer(message)) if message.contains("server policy")
+        ));
+        let second = client.start_test(test.clone()).await.unwrap();
+        // both slots of the server are taken
+        let busy = client.start_test(test).await.unwrap_err();
+        assert!(matches!(
+            busy.downcast_ref::<NBError>(),
+            Some(NBError::ServerBusy(_))
+        ));
+
+        for handle in [first, second] {
+            let summary = handle.join().await.unwrap();
+            assert!(!summary.cancelled);
+            assert!(summary.bytes_sent > 0);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::fmt;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{should_recv, should_send, Direction, NBytes, Role, TestSummary};
+
+/// Thresholds the result of a test has to meet, e.g. for the acceptance test of a link
+#[derive(Debug, Clone, Default)]
+pub struct Expectations {
+    /// Lowest throughput in bits per second, in every direction the test transfers data
+    pub min_bitrate: Option<u64>,
+    /// Highest number of retransmits of the sender. The server reports them for tests
+    /// in which it sends, bidirectional tests can't be checked.
+    pub max_retransmits: Option<u64>,
+    /// Highest jitter. For TCP tests this is the mean RTT variation reported by the kernel.
+    pub max_jitter: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Check {
+    /// In bits per second
+    MinBitrate,
+    MaxRetransmits,
+    /// In milliseconds
+    MaxJitter,
+}
+
+/// The outcome of one check against a test summary
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CheckResult {
+    pub check: Check,
+    pub expected: f64,
+    /// `None` if the test didn't measure the value, which fails the check
+    pub measured: Option<f64>,
+    pub passed: bool,
+}
+
+impl Expectations {
+    /// Checks the summary of a test the client ran in `direction`
+    pub fn check(&self, summary: &TestSummary, direction: Direction) -> Vec<CheckResult> {
+        let mut results = Vec::new();
+        let mut check = |check, expected: f64, measured: Option<f64>| {
+            let passed = measured.is_some_and(|measured| match check {
+                Check::MinBitrate => measured >= expected,
+                Check::MaxRetransmits | Check::MaxJitter => measured <= expected,
+            });
+            results.push(CheckResult {
+                check,
+                expected,
+                measured,
+                passed,
+            });
+        };
+
+        if let Some(min_bitrate) = self.min_bitrate {
+            // the slower direction of a bidirectional test has to meet it as well
+            let mut rates = Vec::new();
+            if should_send(direction, Role::Client) {
+                rates.push(summary.sent_bits_per_second());
+            }
+            if should_recv(direction, Role::Client) {
+                rates.push(summary.received_bits_per_second());
+            }
+            let measured = rates.into_iter().reduce(f64::min);
+            check(Check::MinBitrate, min_bitrate as f64, measured);
+        }
+        if let Some(max_retransmits) = self.max_retransmits {
+            // the client only knows the retransmits of one of the directions
+            let measured = match direction {
+                Direction::Bidirectional => None,
+                _ => summary.retransmits.map(|retransmits| retransmits as f64),
+            };
+            check(Check::MaxRetransmits, max_retransmits as f64, measured);
+        }
+        if let Some(max_jitter) = self.max_jitter {
+            let expected = max_jitter.as_secs_f64() * 1000.0;
+            check(Check::MaxJitter, expected, summary.mean_rttvar);
+        }
+
+        results
+    }
+}
+
+impl fmt::Display for CheckResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let format = |value: f64| match self.check {
+            Check::MinBitrate => format!("{}/s", NBytes::format_bits_per_second(value)),
+            Check::MaxRetransmits => format!("{value}"),
+            Check::MaxJitter => format!("{value:.3} ms"),
+        };
+        let (name, bound) = match self.check {
+            Check::MinBitrate => ("bitrate", "at least"),
+            Check::MaxRetransmits => ("retransmits", "at most"),
+            Check::MaxJitter => ("jitter", "at most"),
+        };
+        let measured = self
+            .measured
+            .map_or_else(|| String::from("not measured"), format);
+
+        write!(
+            f,
+            "{} {name}: {measured}, expected {bound} {}",
+            if self.passed { "PASS" } else { "FAIL" },
+            format(self.expected)
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let expectations = Expectations {
+            min_bitrate: Some(900_000_000),
+            max_retransmits: Some(100),
+            max_jitter: Some(Duration::from_millis(5)),
+        };
+        let summary = TestSummary {
+            bytes_sent: 1_250_000_000,
+            bytes_received: 1_000_000_000,
+            duration: 10.0,
+            retransmits: Some(150),
+            ..Default::default()
+        };
+
+        let passed = |results: Vec<CheckResult>| {
+            results
+                .iter()
+                .map(|result| (result.check, result.passed))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            passed(expectations.check(&summary, Direction::ClientToServer)),
+            [
+                (Check::MinBitrate, true),
+                (Check::MaxRetransmits, false),
+                (Check::MaxJitter, false),
+            ]
+        );
+        // 800 Mbit/s from the server to the client
+        let results = expectations.check(&summary, Direction::Bidirectional);
+        assert!(!results[0].passed);
+        // only the retransmits from the client to the server are known
+        assert_eq!(results[1].measured, None);
+    }
+}
@@ -0,0 +1,227 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    sockopt, Backend, EndCondition, NBError, NewTestMessage, Pacing, Payload, Protocol, RecvMode,
+    SendMode, TCPTestInfo,
+};
+
+/// Version of the control protocol. Peers only talk to each other if they speak the
+/// same version, it has to be increased whenever a message changes incompatibly.
+pub(crate) const PROTOCOL_VERSION: u32 = 1;
+
+/// The first message on a control connection, sent by the client and answered by the
+/// server.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct HelloMessage {
+    pub protocol_version: u32,
+    pub software_version: String,
+    pub capabilities: Capabilities,
+    /// Set by servers that only run tests for authenticated clients
+    #[serde(default)]
+    pub auth_required: bool,
+}
+
+impl HelloMessage {
+    pub(crate) fn new() -> Self {
+        HelloMessage {
+            protocol_version: PROTOCOL_VERSION,
+            software_version: env!("CARGO_PKG_VERSION").to_string(),
+            capabilities: Capabilities::local(),
+            auth_required: false,
+        }
+    }
+
+    pub(crate) fn check_compatible(&self) -> Result<(), NBError> {
+        if self.protocol_version != PROTOCOL_VERSION {
+            return Err(NBError::IncompatibleVersion(
+                self.protocol_version,
+                self.software_version.clone(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for HelloMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "netbench {} (protocol version {})",
+            self.software_version, self.protocol_version
+        )?;
+        writeln!(f, "protocols: {}", self.capabilities.protocols.join(", "))?;
+        writeln!(
+            f,
+            "end conditions: {}",
+            self.capabilities.end_conditions.join(", ")
+        )?;
+        write!(f, "options: {}", self.capabilities.options.join(", "))?;
+        if self.auth_required {
+            write!(f, "\nauthentication required")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// What a peer supports, each entry is the camelCase name of a protocol, end condition
+/// or test option.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Capabilities {
+    pub protocols: Vec<String>,
+    pub end_conditions: Vec<String>,
+    pub options: Vec<String>,
+}
+
+const OPTIONS: &[&str] = &[
+    "windowSize",
+    "mss",
+    "nodelay",
+    "notsentLowat",
+    "quickack",
+    "keepalive",
+    "congestion",
+    "tos",
+    "ecn",
+    "applicationPacing",
+    "kernelPacing",
+    "zeroCopySend",
+    "sendfile",
+    "splice",
+    "zeroCopyReceive",
+    "fileReceive",
+    "payload",
+    "verify",
+    "testUpdates",
+    "heartbeats",
+    "testResults",
+    "resilience",
+    #[cfg(feature = "uring")]
+    "uring",
+];
+
+impl Capabilities {
+    /// The capabilities of this build. ECN is left out if the system has it disabled.
+    pub(crate) fn local() -> Self {
+        let ecn = sockopt::tcp_ecn_sysctl().is_ok_and(|value| value != 0);
+        Capabilities {
+            protocols: vec!["tcp".to_string()],
+            end_conditions: vec!["time".to_string()],
+            options: OPTIONS
+                .iter()
+                .filter(|option| ecn || **option != "ecn")
+                .map(|option| option.to_string())
+                .collect(),
+        }
+    }
+
+    /// Returns the capabilities `test` needs that are missing
+    pub(crate) fn missing(&self, test: &NewTestMessage) -> Vec<String> {
+        let mut missing = Vec::new();
+        let mut require = |list: &[String], name: &str| {
+            if !list.iter().any(|entry| entry == name) {
+                missing.push(name.to_string());
+            }
+        };
+
+        let protocol = match &test.protocol {
+            Protocol::TCP(_) => "tcp",
+            Protocol::UDP => "udp",
+            Protocol::DCCP => "dccp",
+            Protocol::SCTP => "sctp",
+        };
+        require(&self.protocols, protocol);
+
+        let end_condition = match test.end_condition {
+            EndCondition::Time(_) => "time",
+            EndCondition::Bytes(_) => "bytes",
+        };
+        require(&self.end_conditions, end_condition);
+
+        if test.bw != 0 {
+            let pacing = match test.pacing {
+                Pacing::Application => "applicationPacing",
+                Pacing::Kernel => "kernelPacing",
+            };
+            require(&self.options, pacing);
+        }
+
+        if test.resilient {
+            require(&self.options, "resilience");
+        }
+
+        if let Protocol::TCP(tcp_test_info) = &test.protocol {
+            for option in tcp_options(tcp_test_info) {
+                require(&self.options, option);
+            }
+        }
+
+        missing
+    }
+}
+
+/// The options a TCP test uses
+fn tcp_options(info: &TCPTestInfo) -> Vec<&'static str> {
+    let socket_options = &info.socket_options;
+    let mut options = Vec::new();
+    let mut push = |used: bool, name| {
+        if used {
+            options.push(name);
+        }
+    };
+
+    push(socket_options.window_size.is_some(), "windowSize");
+    push(socket_options.mss.is_some(), "mss");
+    push(socket_options.nodelay, "nodelay");
+    push(socket_options.notsent_lowat.is_some(), "notsentLowat");
+    push(socket_options.quickack, "quickack");
+    push(socket_options.keepalive, "keepalive");
+    push(socket_options.congestion.is_some(), "congestion");
+    push(socket_options.tos.is_some(), "tos");
+    push(socket_options.ecn, "ecn");
+    push(info.send_mode == SendMode::ZeroCopy, "zeroCopySend");
+    push(info.send_mode == SendMode::Sendfile, "sendfile");
+    push(info.recv_mode == RecvMode::Splice, "splice");
+    push(info.recv_mode == RecvMode::ZeroCopy, "zeroCopyReceive");
+    push(info.recv_mode == RecvMode::File, "fileReceive");
+    push(info.payload != Payload::default(), "payload");
+    push(info.verify, "verify");
+    push(info.backend == Backend::Uring, "uring");
+
+    options
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_missing_capabilities() {
+        let mut test = NewTestMessage {
+            pacing: Pacing::Kernel,
+            ..NewTestMessage::for_test(0)
+        };
+        if let Protocol::TCP(tcp_test_info) = &mut test.protocol {
+            tcp_test_info.send_mode = SendMode::Sendfile;
+            tcp_test_info.verify = true;
+        }
+        assert!(Capabilities::local().missing(&test).is_empty());
+
+        test.bw = 1000;
+        test.end_condition = EndCondition::Bytes(1000);
+        let capabilities = Capabilities {
+            protocols: vec!["tcp".to_string()],
+            end_conditions: vec!["time".to_string()],
+            options: vec!["verify".to_string()],
+        };
+        assert_eq!(
+            capabilities.missing(&test),
+            ["bytes", "kernelPacing", "sendfile"]
+        );
+    }
+}
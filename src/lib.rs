@@ -2,27 +2,67 @@
 #![warn(missing_debug_implementations)]
 extern crate core;
 
+mod acl;
+mod admin;
+mod admission;
+mod auth;
 mod client;
+mod expect;
+mod hello;
+mod monitor;
+mod payload;
+mod policy;
 mod server;
+mod service;
+mod sockopt;
+mod stats;
+mod sweep;
 mod tcp_test;
 mod test_manager;
 mod token_bucket;
-
-pub use crate::client::Client;
+#[cfg(feature = "uring")]
+mod uring;
+mod zerocopy;
+
+pub use crate::acl::{parse_network, AccessList};
+pub use crate::admin::{
+    admin_request, AdminRequest, AdminResponse, TestInfo, TestState, TestStats,
+};
+pub use crate::auth::{Credentials, KeyTable};
+pub use crate::client::{Canceller, Client, CongestionComparison, TestHandle};
+pub use crate::expect::{Check, CheckResult, Expectations};
+pub use crate::hello::{Capabilities, HelloMessage};
+pub use crate::monitor::{Monitor, MonitorConfig, MonitorRecord};
+pub use crate::payload::IntegrityReport;
+pub use crate::policy::{OverLimit, Policy};
 pub use crate::server::{ControlMessage, Server};
-
-use serde::{Deserialize, Serialize};
+pub use crate::service::{daemonize, notify, systemd_listener, PidFile};
+pub use crate::sockopt::EffectiveTCPOptions;
+pub use crate::stats::{RepeatedTest, Statistics};
+pub use crate::sweep::{Sweep, SweepGrid, SweepPoint};
+pub use crate::test_manager::{CpuUsage, Outage, TestSummary};
+pub use crate::zerocopy::ZeroCopyCompletions;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::collections::HashMap;
+use std::io;
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::time::Duration as StdDuration;
 use std::{fmt, ops};
 use thiserror::Error;
 use time::Duration;
-use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use once_cell::sync::Lazy;
 
 pub(crate) const CONTROL_MSG_SIZE: usize = 6;
+/// Largest message body that is accepted, no legitimate message comes close to it
+pub(crate) const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+/// How long the peer has to finish sending a message once it has started it
+pub(crate) const MESSAGE_READ_TIMEOUT: StdDuration = StdDuration::from_secs(10);
+/// Largest send or receive buffer of a test, enforced by the server regardless of its policy
+pub const MAX_BUFFER_SIZE: u64 = 64 * 1024 * 1024;
 
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 pub enum MessageType {
@@ -30,6 +70,18 @@ pub enum MessageType {
     CancelTest(usize),
     MsgError(usize),
     TestAssociation(usize),
+    Hello(usize),
+    AuthChallenge(usize),
+    AuthResponse(usize),
+    AuthAccepted(usize),
+    TestAccepted(usize),
+    TestQueued(usize),
+    AdminRequest(usize),
+    AdminResponse(usize),
+    UpdateTest(usize),
+    TestUpdated(usize),
+    Heartbeat(usize),
+    TestResults(usize),
     Close(usize),
 }
 
@@ -40,6 +92,18 @@ impl MessageID {
     pub(crate) const CANCEL_TEST_MESSAGE: u16 = 0x1;
     pub(crate) const MSG_ERROR_MESSAGE: u16 = 0x2;
     pub(crate) const TEST_ASSOCIATION_MESSAGE: u16 = 0x3;
+    pub(crate) const HELLO_MESSAGE: u16 = 0x4;
+    pub(crate) const AUTH_CHALLENGE_MESSAGE: u16 = 0x5;
+    pub(crate) const AUTH_RESPONSE_MESSAGE: u16 = 0x6;
+    pub(crate) const AUTH_ACCEPTED_MESSAGE: u16 = 0x7;
+    pub(crate) const TEST_ACCEPTED_MESSAGE: u16 = 0x8;
+    pub(crate) const TEST_QUEUED_MESSAGE: u16 = 0x9;
+    pub(crate) const ADMIN_REQUEST_MESSAGE: u16 = 0xA;
+    pub(crate) const ADMIN_RESPONSE_MESSAGE: u16 = 0xB;
+    pub(crate) const UPDATE_TEST_MESSAGE: u16 = 0xC;
+    pub(crate) const TEST_UPDATED_MESSAGE: u16 = 0xD;
+    pub(crate) const HEARTBEAT_MESSAGE: u16 = 0xE;
+    pub(crate) const TEST_RESULTS_MESSAGE: u16 = 0xF;
     pub(crate) const CLOSE_MESSAGE: u16 = 0xFFFF;
 }
 
@@ -50,10 +114,67 @@ impl MessageType {
             MessageID::CANCEL_TEST_MESSAGE => Ok(MessageType::CancelTest(len)),
             MessageID::MSG_ERROR_MESSAGE => Ok(MessageType::MsgError(len)),
             MessageID::TEST_ASSOCIATION_MESSAGE => Ok(MessageType::TestAssociation(len)),
+            MessageID::HELLO_MESSAGE => Ok(MessageType::Hello(len)),
+            MessageID::AUTH_CHALLENGE_MESSAGE => Ok(MessageType::AuthChallenge(len)),
+            MessageID::AUTH_RESPONSE_MESSAGE => Ok(MessageType::AuthResponse(len)),
+            MessageID::AUTH_ACCEPTED_MESSAGE => Ok(MessageType::AuthAccepted(len)),
+            MessageID::TEST_ACCEPTED_MESSAGE => Ok(MessageType::TestAccepted(len)),
+            MessageID::TEST_QUEUED_MESSAGE => Ok(MessageType::TestQueued(len)),
+            MessageID::ADMIN_REQUEST_MESSAGE => Ok(MessageType::AdminRequest(len)),
+            MessageID::ADMIN_RESPONSE_MESSAGE => Ok(MessageType::AdminResponse(len)),
+            MessageID::UPDATE_TEST_MESSAGE => Ok(MessageType::UpdateTest(len)),
+            MessageID::TEST_UPDATED_MESSAGE => Ok(MessageType::TestUpdated(len)),
+            MessageID::HEARTBEAT_MESSAGE => Ok(MessageType::Heartbeat(len)),
+            MessageID::TEST_RESULTS_MESSAGE => Ok(MessageType::TestResults(len)),
             MessageID::CLOSE_MESSAGE => Ok(MessageType::Close(len)),
             _ => Err(NBError::InvalidMessageType(id)),
         }
     }
+
+    /// Length of the message body
+    pub(crate) fn len(&self) -> usize {
+        match *self {
+            MessageType::NewTest(len)
+            | MessageType::CancelTest(len)
+            | MessageType::MsgError(len)
+            | MessageType::TestAssociation(len)
+            | MessageType::Hello(len)
+            | MessageType::AuthChallenge(len)
+            | MessageType::AuthResponse(len)
+            | MessageType::AuthAccepted(len)
+            | MessageType::TestAccepted(len)
+            | MessageType::TestQueued(len)
+            | MessageType::AdminRequest(len)
+            | MessageType::AdminResponse(len)
+            | MessageType::UpdateTest(len)
+            | MessageType::TestUpdated(len)
+            | MessageType::Heartbeat(len)
+            | MessageType::TestResults(len)
+            | MessageType::Close(len) => len,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            MessageType::NewTest(_) => "NewTest",
+            MessageType::CancelTest(_) => "CancelTest",
+            MessageType::MsgError(_) => "MsgError",
+            MessageType::TestAssociation(_) => "TestAssociation",
+            MessageType::Hello(_) => "Hello",
+            MessageType::AuthChallenge(_) => "AuthChallenge",
+            MessageType::AuthResponse(_) => "AuthResponse",
+            MessageType::AuthAccepted(_) => "AuthAccepted",
+            MessageType::TestAccepted(_) => "TestAccepted",
+            MessageType::TestQueued(_) => "TestQueued",
+            MessageType::AdminRequest(_) => "AdminRequest",
+            MessageType::AdminResponse(_) => "AdminResponse",
+            MessageType::UpdateTest(_) => "UpdateTest",
+            MessageType::TestUpdated(_) => "TestUpdated",
+            MessageType::Heartbeat(_) => "Heartbeat",
+            MessageType::TestResults(_) => "TestResults",
+            MessageType::Close(_) => "Close",
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, PartialOrd, Clone, Copy)]
@@ -64,7 +185,7 @@ pub enum Direction {
     Bidirectional,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, PartialOrd, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, PartialOrd, Clone)]
 #[serde(rename_all = "lowercase")]
 #[allow(clippy::upper_case_acronyms)]
 pub enum Protocol {
@@ -74,10 +195,104 @@ pub enum Protocol {
     SCTP,
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, PartialOrd, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, PartialOrd, Clone)]
 pub struct TCPTestInfo {
     pub recv_buf_size: u64,
     pub send_buf_size: u64,
+    #[serde(default)]
+    pub socket_options: TCPSocketOptions,
+    #[serde(default)]
+    pub send_mode: SendMode,
+    #[serde(default)]
+    pub recv_mode: RecvMode,
+    #[serde(default)]
+    pub backend: Backend,
+    #[serde(default)]
+    pub payload: Payload,
+    /// Frame the payload into blocks with sequence numbers and checksums that the
+    /// receiver checks
+    #[serde(default)]
+    pub verify: bool,
+}
+
+/// The data written to the data connection
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum Payload {
+    Zeros,
+    /// The given bytes repeated over and over
+    Pattern(Vec<u8>),
+    /// Incompressible pseudo-random data, the same seed always generates the same data
+    Random {
+        seed: u64,
+    },
+    /// The contents of the file configured in `CommonConfig::file`
+    File,
+}
+
+impl Default for Payload {
+    fn default() -> Self {
+        Payload::Pattern(vec![0xAB])
+    }
+}
+
+/// The I/O mechanism driving the data path of a test
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum Backend {
+    /// Readiness based I/O through the tokio reactor
+    #[default]
+    Epoll,
+    /// io_uring with multishot receives, registered buffers and batched submissions.
+    /// Only available if netbench has been built with the `uring` feature.
+    Uring,
+}
+
+/// What the receiver does with the received data
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum RecvMode {
+    /// Regular reads into the receive buffer, the data is discarded
+    #[default]
+    Copy,
+    /// splice() the data through a pipe into /dev/null without copying it to user space
+    Splice,
+    /// Map the received pages with TCP_ZEROCOPY_RECEIVE
+    ZeroCopy,
+    /// Write the received data to the file configured in `CommonConfig::recv_file`
+    File,
+}
+
+/// How the sender hands the data to the kernel
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum SendMode {
+    /// Regular writes that copy the send buffer into the kernel
+    #[default]
+    Copy,
+    /// send() with MSG_ZEROCOPY, completions are read from the error queue
+    ZeroCopy,
+    /// sendfile() from the file configured in `CommonConfig::file`
+    Sendfile,
+}
+
+/// Socket level options applied to the data connection on both ends.
+#[derive(Debug, Serialize, Deserialize, PartialEq, PartialOrd, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct TCPSocketOptions {
+    /// Sets both SO_SNDBUF and SO_RCVBUF
+    pub window_size: Option<u64>,
+    pub mss: Option<u64>,
+    pub nodelay: bool,
+    pub notsent_lowat: Option<u64>,
+    pub quickack: bool,
+    pub keepalive: bool,
+    /// Congestion control algorithm (TCP_CONGESTION), used by both senders
+    pub congestion: Option<String>,
+    /// IP TOS byte, or the traffic class for IPv6
+    pub tos: Option<u8>,
+    /// Request ECN for the connection
+    pub ecn: bool,
 }
 
 impl fmt::Display for Protocol {
@@ -103,17 +318,213 @@ pub enum EndCondition {
     Bytes(u64),
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+/// How the target bitrate of a test is enforced
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum Pacing {
+    /// Rate limit the writes with a token bucket
+    #[default]
+    Application,
+    /// Let the kernel pace the socket through SO_MAX_PACING_RATE
+    Kernel,
+}
+
+impl fmt::Display for Pacing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pacing::Application => write!(f, "application"),
+            Pacing::Kernel => write!(f, "kernel"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct NewTestMessage {
     direction: Direction,
     protocol: Protocol,
+    /// target bitrate in bits per second, 0 for unlimited
     bw: u64,
+    #[serde(default)]
+    pacing: Pacing,
     code: [u8; 32],
     end_condition: EndCondition,
+    /// Replace a broken data connection instead of ending the test
+    #[serde(default)]
+    resilient: bool,
+}
+
+impl NewTestMessage {
+    /// Rejects combinations of options the data path can't run correctly
+    pub(crate) fn check_supported(&self) -> Result<(), NBError> {
+        let Protocol::TCP(tcp_test_info) = &self.protocol else {
+            return Ok(());
+        };
+        if self.resilient && tcp_test_info.verify {
+            return Err(NBError::Unsupported(
+                "verifying the data of a resilient test",
+            ));
+        }
+        // the send buffer is refilled while the kernel may still send from its pages
+        let refilled =
+            tcp_test_info.verify || matches!(tcp_test_info.payload, Payload::Random { .. });
+        if tcp_test_info.send_mode == SendMode::ZeroCopy && refilled {
+            return Err(NBError::Unsupported(
+                "zerocopy sends of a random or verified payload",
+            ));
+        }
+        if tcp_test_info.backend == Backend::Uring {
+            if tcp_test_info.send_mode != SendMode::Copy
+                || tcp_test_info.recv_mode != RecvMode::Copy
+            {
+                return Err(NBError::Unsupported(
+                    "send and receive modes on the io_uring backend",
+                ));
+            }
+            if self.resilient {
+                return Err(NBError::Unsupported(
+                    "resilient tests on the io_uring backend",
+                ));
+            }
+            if tcp_test_info.verify {
+                return Err(NBError::Unsupported(
+                    "verifying data on the io_uring backend",
+                ));
+            }
+            if self.bw != 0 && self.pacing == Pacing::Application {
+                return Err(NBError::Unsupported(
+                    "application pacing on the io_uring backend",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Changes the test the way `update` changes the running test
+    pub(crate) fn apply(&mut self, update: TestUpdate) -> Result<(), NBError> {
+        if let Protocol::TCP(tcp_test_info) = &self.protocol {
+            if tcp_test_info.backend == Backend::Uring && cfg!(feature = "uring") {
+                return Err(NBError::Unsupported(
+                    "tests on the io_uring backend can't be changed",
+                ));
+            }
+        }
+
+        match update {
+            TestUpdate::Pause | TestUpdate::Resume => {}
+            TestUpdate::Bitrate(bw) => self.bw = bw,
+            TestUpdate::BufferSize { send, recv } => match &mut self.protocol {
+                Protocol::TCP(tcp_test_info) if !tcp_test_info.verify => {
+                    if let Some(size) = send {
+                        tcp_test_info.send_buf_size = size;
+                    }
+                    if let Some(size) = recv {
+                        tcp_test_info.recv_buf_size = size;
+                    }
+                }
+                Protocol::TCP(_) => {
+                    return Err(NBError::Unsupported(
+                        "the buffer size of a verified test can't be changed",
+                    ))
+                }
+                _ => return Err(NBError::Unsupported("only TCP tests are implemented")),
+            },
+        }
+
+        Ok(())
+    }
 }
 
-impl NewTestMessage {}
+#[cfg(test)]
+impl NewTestMessage {
+    /// A TCP test from the client to the server for 10 seconds with default options
+    pub(crate) fn for_test(code: u8) -> Self {
+        NewTestMessage {
+            direction: Direction::ClientToServer,
+            protocol: Protocol::TCP(TCPTestInfo {
+                recv_buf_size: 128 * 1024,
+                send_buf_size: 128 * 1024,
+                socket_options: Default::default(),
+                send_mode: Default::default(),
+                recv_mode: Default::default(),
+                backend: Default::default(),
+                payload: Default::default(),
+                verify: false,
+            }),
+            bw: 0,
+            pacing: Pacing::Application,
+            code: [code; 32],
+            end_condition: EndCondition::Time(time::Duration::new(10, 0)),
+            resilient: false,
+        }
+    }
+}
+
+/// A change to a running test, applied by both sides
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum TestUpdate {
+    /// Stop sending until the test is resumed, the test keeps running
+    Pause,
+    Resume,
+    /// The new target bitrate in bits per second, 0 for unlimited
+    Bitrate(u64),
+    /// The new length of the writes and of the reads in bytes, `None` keeps the length
+    BufferSize {
+        send: Option<u64>,
+        recv: Option<u64>,
+    },
+}
+
+impl fmt::Display for TestUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TestUpdate::Pause => write!(f, "paused"),
+            TestUpdate::Resume => write!(f, "resumed"),
+            TestUpdate::Bitrate(0) => write!(f, "bitrate unlimited"),
+            TestUpdate::Bitrate(bw) => {
+                write!(
+                    f,
+                    "bitrate {}/s",
+                    NBytes::format_bits_per_second(*bw as f64)
+                )
+            }
+            TestUpdate::BufferSize { send, recv } if send == recv => {
+                let size = send.unwrap_or_default();
+                write!(f, "buffer size {}", NBytes::from(size).format_as_bytes())
+            }
+            TestUpdate::BufferSize { send, recv } => {
+                let sizes = [("send", send), ("receive", recv)]
+                    .into_iter()
+                    .filter_map(|(what, size)| {
+                        let size = NBytes::from((*size)?).format_as_bytes();
+                        Some(format!("{what} buffer size {size}"))
+                    })
+                    .collect::<Vec<_>>();
+                write!(f, "{}", sizes.join(", "))
+            }
+        }
+    }
+}
+
+/// Asks the server to change one of the running tests of the client
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct UpdateTestMessage {
+    pub(crate) code: [u8; 32],
+    pub(crate) update: TestUpdate,
+}
+
+/// The server's answer to an `UpdateTestMessage` it has applied
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TestUpdatedMessage {
+    /// The change as it has been applied, after the server policy has been applied
+    pub(crate) update: TestUpdate,
+    /// The limits of the server policy that changed the update
+    pub(crate) limits: Vec<String>,
+}
 
 impl std::cmp::PartialEq<TestAssociationMessage> for NewTestMessage {
     fn eq(&self, other: &TestAssociationMessage) -> bool {
@@ -127,6 +538,66 @@ pub(crate) struct TestAssociationMessage {
     pub(crate) code: [u8; 32],
 }
 
+/// Ends a test early, sent by either side over the control connection. Not answered.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CancelTestMessage {
+    pub(crate) code: [u8; 32],
+}
+
+/// The server's results of a test, sent over the control connection once the test is
+/// done if the client supports it. Not answered.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TestResultsMessage {
+    pub(crate) code: [u8; 32],
+    pub(crate) summary: TestSummary,
+    /// The socket options in effect on the server's end of the data connection
+    #[serde(default)]
+    pub(crate) effective_options: Option<EffectiveTCPOptions>,
+}
+
+/// The server's answer to a `NewTestMessage` it is going to run
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TestAcceptedMessage {
+    /// The test as it is run, after the server policy has been applied
+    pub(crate) test: NewTestMessage,
+    /// The limits of the server policy that changed the test
+    pub(crate) limits: Vec<String>,
+}
+
+/// Sent instead of a `TestAcceptedMessage` while the test waits for a free slot, and
+/// repeated periodically until the test is accepted
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TestQueuedMessage {
+    /// 1 for the test that is run next
+    pub(crate) position: usize,
+}
+
+/// Sent with `MessageID::MSG_ERROR_MESSAGE` before a connection is closed because of an error
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ErrorMessage {
+    pub(crate) message: String,
+    /// Set if the request can be retried after this many seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) retry_after: Option<u64>,
+}
+
+impl From<&NBError> for ErrorMessage {
+    fn from(error: &NBError) -> Self {
+        ErrorMessage {
+            message: error.to_string(),
+            retry_after: match error {
+                NBError::ServerBusy(retry_after) => Some(*retry_after),
+                _ => None,
+            },
+        }
+    }
+}
+
 pub(crate) async fn send_message<T, W>(
     message: T,
     message_id: u16,
@@ -136,30 +607,137 @@ where
     T: Serialize,
     W: AsyncWrite + Sync + Send + Unpin,
 {
-    let mut message = serde_json::to_vec(&message).unwrap();
+    let mut message = serde_json::to_vec(&message)?;
+    if message.len() > MAX_MESSAGE_SIZE {
+        return Err(NBError::MessageTooLarge(message.len()));
+    }
     let mut data = Vec::with_capacity(message.len() + CONTROL_MSG_SIZE);
     data.extend_from_slice(&message_id.to_be_bytes());
     data.extend_from_slice(&(message.len() as u32).to_be_bytes());
     data.append(&mut message);
 
-    writer.write_all(data.as_slice()).await.unwrap();
+    writer.write_all(data.as_slice()).await?;
     Ok(())
 }
 
-#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
-pub enum Base {
-    Base2,
-    Base10,
+/// Decodes the message header, rejecting bodies larger than `MAX_MESSAGE_SIZE`
+pub(crate) fn decode_header(buf: &[u8; CONTROL_MSG_SIZE]) -> Result<MessageType, NBError> {
+    let msg_type = u16::from_be_bytes([buf[0], buf[1]]);
+    let len = u32::from_be_bytes([buf[2], buf[3], buf[4], buf[5]]) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(NBError::MessageTooLarge(len));
+    }
+
+    MessageType::new(msg_type, len)
 }
 
-impl Default for Base {
-    fn default() -> Self {
-        Base::Base2
+pub(crate) fn decode_message<T: DeserializeOwned>(buf: &[u8]) -> Result<T, NBError> {
+    Ok(serde_json::from_slice(buf)?)
+}
+
+/// Reads the header of the next message. Waiting for the start of a message is only
+/// limited by `timeout`, once the first byte has arrived the rest of the header has to
+/// follow within `MESSAGE_READ_TIMEOUT`.
+pub(crate) async fn read_message_type<R>(
+    reader: &mut R,
+    timeout: Option<StdDuration>,
+) -> Result<MessageType, NBError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = [0; CONTROL_MSG_SIZE];
+    let first = reader.read(&mut buf[..1]);
+    let n = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, first)
+            .await
+            .map_err(|_| NBError::Timeout)??,
+        None => first.await?,
+    };
+    if n == 0 {
+        return Err(NBError::ConnectionClosed);
     }
+    read_exact_timeout(reader, &mut buf[1..]).await?;
+
+    let msg_type = decode_header(&buf)?;
+    tracing::trace!("read control info: {msg_type:?}");
+    Ok(msg_type)
 }
 
-#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+/// Reads and decodes the body of a message of type `msg_type`
+pub(crate) async fn read_message<T, R>(reader: &mut R, msg_type: MessageType) -> Result<T, NBError>
+where
+    T: DeserializeOwned,
+    R: AsyncRead + Unpin,
+{
+    // the length has been checked against MAX_MESSAGE_SIZE by decode_header
+    let mut buf = vec![0; msg_type.len()];
+    read_exact_timeout(reader, &mut buf).await?;
+    decode_message(&buf)
+}
+
+/// Reads a message body of `buf.len()` bytes without decoding it
+pub(crate) async fn read_body<R>(reader: &mut R, buf: &mut [u8]) -> Result<(), NBError>
+where
+    R: AsyncRead + Unpin,
+{
+    read_exact_timeout(reader, buf).await
+}
+
+async fn read_exact_timeout<R>(reader: &mut R, buf: &mut [u8]) -> Result<(), NBError>
+where
+    R: AsyncRead + Unpin,
+{
+    match tokio::time::timeout(MESSAGE_READ_TIMEOUT, reader.read_exact(buf)).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => Err(NBError::ConnectionClosed),
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => Err(NBError::Timeout),
+    }
+}
+
+/// Decodes a complete frame the way the server does. This is the entry point of the
+/// fuzz targets and not meant to be used otherwise.
+#[doc(hidden)]
+pub fn decode_control_frame(data: &[u8]) -> Result<(), NBError> {
+    let header = data
+        .get(..CONTROL_MSG_SIZE)
+        .ok_or(NBError::ConnectionClosed)?;
+    let msg_type = decode_header(header.try_into().unwrap())?;
+    let body = data[CONTROL_MSG_SIZE..]
+        .get(..msg_type.len())
+        .ok_or(NBError::ConnectionClosed)?;
+
+    match msg_type {
+        MessageType::NewTest(_) => decode_message::<NewTestMessage>(body).map(drop),
+        MessageType::TestAssociation(_) => decode_message::<TestAssociationMessage>(body).map(drop),
+        MessageType::MsgError(_) => decode_message::<ErrorMessage>(body).map(drop),
+        MessageType::Hello(_) => decode_message::<HelloMessage>(body).map(drop),
+        MessageType::AuthChallenge(_) => {
+            decode_message::<auth::AuthChallengeMessage>(body).map(drop)
+        }
+        MessageType::AuthResponse(_) => decode_message::<auth::AuthResponseMessage>(body).map(drop),
+        MessageType::TestAccepted(_) => decode_message::<TestAcceptedMessage>(body).map(drop),
+        MessageType::TestQueued(_) => decode_message::<TestQueuedMessage>(body).map(drop),
+        MessageType::AdminRequest(_) => decode_message::<AdminRequest>(body).map(drop),
+        MessageType::AdminResponse(_) => decode_message::<AdminResponse>(body).map(drop),
+        MessageType::UpdateTest(_) => decode_message::<UpdateTestMessage>(body).map(drop),
+        MessageType::TestUpdated(_) => decode_message::<TestUpdatedMessage>(body).map(drop),
+        MessageType::CancelTest(_) => decode_message::<CancelTestMessage>(body).map(drop),
+        MessageType::TestResults(_) => decode_message::<TestResultsMessage>(body).map(drop),
+        MessageType::AuthAccepted(_) | MessageType::Heartbeat(_) | MessageType::Close(_) => Ok(()),
+    }
+}
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy, Default)]
+pub enum Base {
+    #[default]
+    Base2,
+    Base10,
+}
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy, Default)]
 pub enum SizePreference {
+    #[default]
     Auto,
     K,
     M,
@@ -167,24 +745,13 @@ pub enum SizePreference {
     T,
 }
 
-impl Default for SizePreference {
-    fn default() -> Self {
-        SizePreference::Auto
-    }
-}
-
-#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy, Default)]
 pub enum BasePreference {
+    #[default]
     Base2,
     Base10,
 }
 
-impl Default for BasePreference {
-    fn default() -> Self {
-        BasePreference::Base2
-    }
-}
-
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
 #[allow(non_camel_case_types)]
 pub enum Unit {
@@ -321,6 +888,10 @@ impl NBytes {
         NBytes::format(n, self.base_preference, self.size_preference, true)
     }
 
+    fn format_bits_per_second(bits: f64) -> NBytesDisplay {
+        NBytes::format(bits, BasePreference::default(), SizePreference::Auto, true)
+    }
+
     fn format(
         n: f64,
         base_preference: BasePreference,
@@ -357,6 +928,15 @@ impl NBytes {
     }
 }
 
+impl From<u64> for NBytes {
+    fn from(n: u64) -> Self {
+        NBytes {
+            n,
+            ..Default::default()
+        }
+    }
+}
+
 impl ops::Add<u64> for NBytes {
     type Output = Self;
 
@@ -388,28 +968,130 @@ pub enum NBError {
     ParseSuffixError(&'static str),
     #[error("Conversion error: failed to parse {0} as a {1}")]
     ConversionError(u64, &'static str),
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid message: {0}")]
+    InvalidMessage(#[from] serde_json::Error),
+    #[error("Message of {0} bytes exceeds the maximum of {MAX_MESSAGE_SIZE} bytes")]
+    MessageTooLarge(usize),
+    #[error("Timed out waiting for the peer")]
+    Timeout,
+    #[error("Unexpected {0} message")]
+    UnexpectedMessage(&'static str),
+    #[error("Unsupported: {0}")]
+    Unsupported(&'static str),
+    #[error(
+        "The peer speaks protocol version {0} (netbench {1}), this netbench speaks version {}",
+        hello::PROTOCOL_VERSION
+    )]
+    IncompatibleVersion(u32, String),
+    #[error("The peer didn't start with a Hello message, it might run an incompatible version")]
+    MissingHello,
+    #[error("The server doesn't support: {0}")]
+    MissingCapabilities(String),
+    #[error("Rejected by the server policy: {0}")]
+    PolicyViolation(String),
+    #[error("Invalid {0} of {1} bytes, it has to be between 1 and {MAX_BUFFER_SIZE} bytes")]
+    InvalidBufferSize(&'static str, u64),
+    #[error("Congestion control {0} isn't available: {1}")]
+    CongestionUnavailable(String, io::Error),
+    #[error("The server is busy, retry in about {0} seconds")]
+    ServerBusy(u64),
+    #[error("The server is shutting down")]
+    ShuttingDown,
+    #[error("The test isn't running")]
+    TestNotRunning,
+    #[error("Nothing heard from the peer for {0:?}")]
+    PeerLost(StdDuration),
+    #[error("Access denied for {0}")]
+    AccessDenied(std::net::IpAddr),
+    #[error("The server requires authentication")]
+    AuthenticationRequired,
+    #[error("Authentication failed")]
+    AuthenticationFailed,
+    #[error("The peer reported an error: {0}")]
+    Peer(String),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CommonConfig {
     pub format: SizePreference,
     pub base: BasePreference,
     pub file: Option<PathBuf>,
+    /// Received data is written to this file by tests with `RecvMode::File`, which
+    /// fall back to copying if it isn't set
+    pub recv_file: Option<PathBuf>,
+    pub heartbeat: HeartbeatConfig,
+}
+
+/// How a peer that disappeared during a test is detected
+#[derive(Debug, Clone, Copy)]
+pub struct HeartbeatConfig {
+    /// How often a heartbeat is sent over the control connection, if the peer supports it
+    pub interval: StdDuration,
+    /// The peer is considered lost after nothing has been heard from it for this long.
+    /// Also set as TCP_USER_TIMEOUT on the data connections.
+    pub timeout: StdDuration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            interval: StdDuration::from_secs(2),
+            timeout: StdDuration::from_secs(10),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct ClientConfig {
     pub common: CommonConfig,
-    pub bw: Option<u64>,
     pub addr: SocketAddr,
+    /// The test `Client::start_new_test` runs
+    pub test: TestConfig,
+    /// Used if the server requires authentication
+    pub credentials: Option<Credentials>,
+    /// Don't print the progress of the tests, e.g. because the results are printed as JSON
+    pub quiet: bool,
+}
+
+/// The parameters of a single test
+#[derive(Debug, Clone)]
+pub struct TestConfig {
+    pub bw: Option<u64>,
+    pub pacing: Pacing,
     pub proto: Protocol,
     pub direction: Direction,
+    pub end_condition: EndCondition,
+    /// Changes applied while the test runs, by the time since the start of the test
+    pub changes: Vec<(StdDuration, TestUpdate)>,
+    /// Keep the test running when the data connection breaks: connect again and report
+    /// the outages in `TestSummary::outages`
+    pub resilient: bool,
 }
 
 #[derive(Debug)]
 pub struct ServerConfig {
     pub common: CommonConfig,
     pub addr: SocketAddr,
+    /// Clients have to authenticate with one of these users if set
+    pub keys: Option<KeyTable>,
+    /// Client addresses the server accepts connections from
+    pub access: AccessList,
+    /// Limits enforced on submitted tests
+    pub policy: Policy,
+    /// Most tests that run at the same time, 0 for unlimited
+    pub max_tests: usize,
+    /// Queue tests while the server is busy instead of rejecting them
+    pub queue_tests: bool,
+    /// Queued tests that aren't admitted within this time are rejected as busy
+    pub max_queue_wait: StdDuration,
+    /// Stop accepting clients after the first test finished
+    pub one_off: bool,
+    /// Stop accepting clients after no test ran for this long
+    pub idle_timeout: Option<StdDuration>,
+    /// Unix socket the server accepts admin requests on
+    pub admin_socket: Option<PathBuf>,
 }
 
 static SIZE_MAP: Lazy<HashMap<char, u64>> = Lazy::new(|| {
@@ -501,6 +1183,127 @@ mod test {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_decode_header() {
+        let mut header = [0; CONTROL_MSG_SIZE];
+        header[2..].copy_from_slice(&(MAX_MESSAGE_SIZE as u32).to_be_bytes());
+        assert_eq!(
+            decode_header(&header).unwrap(),
+            MessageType::NewTest(MAX_MESSAGE_SIZE)
+        );
+
+        header[2..].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(
+            decode_header(&header),
+            Err(NBError::MessageTooLarge(_))
+        ));
+
+        header[..2].copy_from_slice(&0x1234_u16.to_be_bytes());
+        header[2..].copy_from_slice(&0_u32.to_be_bytes());
+        assert!(matches!(
+            decode_header(&header),
+            Err(NBError::InvalidMessageType(0x1234))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_message() {
+        let message = TestAssociationMessage { code: [7; 32] };
+        let mut frame = Vec::new();
+        send_message(&message, MessageID::TEST_ASSOCIATION_MESSAGE, &mut frame)
+            .await
+            .unwrap();
+        assert!(decode_control_frame(&frame).is_ok());
+
+        let mut reader = frame.as_slice();
+        let msg_type = read_message_type(&mut reader, None).await.unwrap();
+        let read: TestAssociationMessage = read_message(&mut reader, msg_type).await.unwrap();
+        assert_eq!(read.code, message.code);
+
+        // a truncated body
+        let mut reader = &frame[..frame.len() - 1];
+        let msg_type = read_message_type(&mut reader, None).await.unwrap();
+        let read = read_message::<TestAssociationMessage, _>(&mut reader, msg_type).await;
+        assert!(matches!(read, Err(NBError::ConnectionClosed)));
+
+        // a body that isn't the expected message
+        let mut frame = Vec::new();
+        send_message("garbage", MessageID::NEW_TEST_MESSAGE, &mut frame)
+            .await
+            .unwrap();
+        assert!(matches!(
+            decode_control_frame(&frame),
+            Err(NBError::InvalidMessage(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_supported() {
+        let mut test = NewTestMessage::for_test(0);
+        assert!(test.check_supported().is_ok());
+
+        let Protocol::TCP(tcp_test_info) = &mut test.protocol else {
+            unreachable!()
+        };
+        tcp_test_info.send_mode = SendMode::ZeroCopy;
+        assert!(test.check_supported().is_ok());
+
+        // the zerocopy send of a buffer that's refilled in place
+        let Protocol::TCP(tcp_test_info) = &mut test.protocol else {
+            unreachable!()
+        };
+        tcp_test_info.payload = Payload::Random { seed: 1 };
+        assert!(matches!(
+            test.check_supported(),
+            Err(NBError::Unsupported(_))
+        ));
+
+        let Protocol::TCP(tcp_test_info) = &mut test.protocol else {
+            unreachable!()
+        };
+        tcp_test_info.payload = Payload::Zeros;
+        tcp_test_info.verify = true;
+        assert!(matches!(
+            test.check_supported(),
+            Err(NBError::Unsupported(_))
+        ));
+
+        // verify with copied sends, but a resilient test
+        let Protocol::TCP(tcp_test_info) = &mut test.protocol else {
+            unreachable!()
+        };
+        tcp_test_info.send_mode = SendMode::Copy;
+        assert!(test.check_supported().is_ok());
+        test.resilient = true;
+        assert!(matches!(
+            test.check_supported(),
+            Err(NBError::Unsupported(_))
+        ));
+
+        // the io_uring backend only runs copied data with kernel pacing
+        let mut test = NewTestMessage::for_test(0);
+        let Protocol::TCP(tcp_test_info) = &mut test.protocol else {
+            unreachable!()
+        };
+        tcp_test_info.backend = Backend::Uring;
+        assert!(test.check_supported().is_ok());
+        test.bw = 1_000_000;
+        assert!(matches!(
+            test.check_supported(),
+            Err(NBError::Unsupported(_))
+        ));
+        test.pacing = Pacing::Kernel;
+        assert!(test.check_supported().is_ok());
+        let Protocol::TCP(tcp_test_info) = &mut test.protocol else {
+            unreachable!()
+        };
+        tcp_test_info.recv_mode = RecvMode::Splice;
+        assert!(matches!(
+            test.check_supported(),
+            Err(NBError::Unsupported(_))
+        ));
+    }
+
     #[test]
     fn test_should_send() {
         let role = Role::Client;
@@ -1,15 +1,34 @@
 use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::process::{self, ExitCode};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::Error;
-use clap::{Parser, Subcommand, ValueEnum};
+use anyhow::{anyhow, Error};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use ipnet::IpNet;
 use netbench::{
-    parse_u64_with_suffix, BasePreference, Client, ClientConfig, CommonConfig, Protocol, Server,
-    ServerConfig, SizePreference, TCPTestInfo,
+    parse_network, parse_u64_with_suffix, AccessList, AdminRequest, AdminResponse, Backend,
+    BasePreference, Canceller, Client, ClientConfig, CommonConfig, ControlMessage, Credentials,
+    EndCondition, Expectations, HeartbeatConfig, KeyTable, Monitor, MonitorConfig, OverLimit,
+    Pacing, Payload, PidFile, Policy, Protocol, RecvMode, SendMode, Server, ServerConfig,
+    SizePreference, SweepGrid, TCPSocketOptions, TCPTestInfo, TestConfig, TestSummary, TestUpdate,
+    MAX_BUFFER_SIZE,
 };
-use tracing::Level;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::oneshot;
+use tracing::{warn, Level};
 use tracing_subscriber::filter::EnvFilter;
 
 const DEFAULT_PORT: u16 = 5202;
+/// The client's exit code if the server disappeared during the test
+const PEER_LOST_EXIT_CODE: u8 = 3;
+/// The client's exit code if the result didn't meet an --expect-* threshold
+const CHECK_FAILED_EXIT_CODE: u8 = 4;
 
 #[derive(Debug, Parser)]
 #[command(name = "Netbench")]
@@ -22,6 +41,19 @@ struct Cli {
     /// Output in json
     #[arg(long, short)]
     json: bool,
+    /// File to send from with sendfile or as the payload
+    #[arg(long, short = 'F', global = true)]
+    file: Option<PathBuf>,
+    /// File the received data is written to with --recv-mode file, it is overwritten
+    #[arg(long, global = true)]
+    recv_file: Option<PathBuf>,
+    /// Seconds between heartbeats on the control connection
+    #[arg(long, global = true, default_value = "2", value_parser = parse_seconds)]
+    heartbeat_interval: Duration,
+    /// Seconds without hearing from the peer after which its tests are aborted, also used
+    /// as TCP_USER_TIMEOUT of the data connections
+    #[arg(long, global = true, default_value = "10", value_parser = parse_seconds)]
+    peer_timeout: Duration,
     #[arg(
         long,
         value_name = "WHEN",
@@ -34,27 +66,70 @@ struct Cli {
 }
 
 #[derive(Debug, Subcommand)]
+#[allow(clippy::large_enum_variant)]
 enum Commands {
     Client {
         /// The server IP to connect to
         host: String,
         #[command(subcommand)]
         proto: ProtocolCommands,
-        /// time in seconds to transmit
-        #[arg(long, short, default_value_t = 10, conflicts_with = "bytes")]
-        time: u32,
-        /// number of bytes to transmit, 0 for unlimited
-        #[arg(long, short = 'n')]
-        bytes: Option<u64>,
-        /// port to listen on
+        #[command(flatten)]
+        test: TestArgs,
+        #[command(flatten)]
+        expect: ExpectArgs,
+        /// Run the test this many times and report statistics over the runs
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+        repeat: u32,
+        /// Seconds to wait between the runs of a repeated test
+        #[arg(long, value_parser = parse_seconds)]
+        pause: Option<Duration>,
+    },
+    /// Run a test for every combination of the given parameters and compare the results
+    Sweep {
+        /// The server IP to connect to
+        host: String,
+        #[command(subcommand)]
+        proto: ProtocolCommands,
+        #[command(flatten)]
+        test: TestArgs,
+        /// Buffer lengths to try, comma separated
+        #[arg(long, value_delimiter = ',', value_parser = parse_length)]
+        lengths: Vec<u64>,
+        /// Target bitrates to try, comma separated, 0 for unlimited
+        #[arg(long, value_delimiter = ',', value_parser = parse_u64_with_suffix)]
+        bitrates: Vec<u64>,
+        /// Directions to try, comma separated
+        #[arg(long, value_delimiter = ',')]
+        directions: Vec<Direction>,
+        /// Print the results as CSV
+        #[arg(long)]
+        csv: bool,
+    },
+    /// Run a test against each server periodically and append the results as JSON lines
+    Monitor {
+        /// The servers to test, comma separated, each an IP with an optional port
+        #[arg(value_delimiter = ',', num_args = 1, action = clap::ArgAction::Set, required = true)]
+        servers: Vec<String>,
+        #[command(subcommand)]
+        proto: ProtocolCommands,
+        #[command(flatten)]
+        test: TestArgs,
+        /// Minutes between the tests of a server, fractions are allowed. Failed tests are
+        /// retried sooner with an increasing delay.
+        #[arg(long, default_value = "15", value_parser = parse_minutes)]
+        every: Duration,
+        /// Append the results to this file instead of printing them. Without it stdout
+        /// only holds the results, the progress of the tests isn't printed.
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Show the version and capabilities of a server
+    Capabilities {
+        /// The server IP to connect to
+        host: String,
+        /// port the server listens on
         #[arg(long, short, default_value_t = DEFAULT_PORT, value_parser = clap::value_parser!(u16).range(1..))]
         port: u16,
-        /// Direction to send
-        #[arg(long, short, default_value_t = Direction::Uni)]
-        direction: Direction,
-        /// target bitrate
-        #[arg(long, short, value_parser = parse_u64_with_suffix)]
-        bitrate: Option<u64>,
     },
     Server {
         #[arg(default_value_t = String::from("0.0.0.0"))]
@@ -62,7 +137,186 @@ enum Commands {
         /// port to listen on
         #[arg(long, short, default_value_t = DEFAULT_PORT, value_parser = clap::value_parser!(u16).range(1..))]
         port: u16,
+        /// Only run tests for clients authenticated with a user from this file, one
+        /// "<user> <hex key>" per line
+        #[arg(long)]
+        auth_file: Option<PathBuf>,
+        /// Only accept clients from these networks (CIDR, comma separated or repeated)
+        #[arg(long, value_delimiter = ',', value_parser = parse_network)]
+        allow: Vec<IpNet>,
+        /// Reject clients from these networks, takes precedence over --allow
+        #[arg(long, value_delimiter = ',', value_parser = parse_network)]
+        deny: Vec<IpNet>,
+        /// Longest test duration in seconds
+        #[arg(long)]
+        max_duration: Option<u64>,
+        /// Most bytes a test may transmit
+        #[arg(long, value_parser = parse_u64_with_suffix)]
+        max_bytes: Option<u64>,
+        /// Highest bitrate a test may use, unlimited tests are limited to it as well
+        #[arg(long, value_parser = parse_u64_with_suffix)]
+        max_bitrate: Option<u64>,
+        /// Largest send/receive buffer and window size
+        #[arg(long, value_parser = parse_u64_with_suffix)]
+        max_buffer: Option<u64>,
+        /// Directions tests may use (comma separated or repeated), all if not set
+        #[arg(long, value_delimiter = ',')]
+        allow_direction: Vec<Direction>,
+        /// What happens to tests that exceed a limit
+        #[arg(long, default_value_t = OverLimitAction::Clamp)]
+        over_limit: OverLimitAction,
+        /// Most tests that run at the same time, 0 for unlimited
+        #[arg(long, default_value_t = 1)]
+        max_tests: usize,
+        /// Queue tests while the server is busy instead of rejecting them
+        #[arg(long)]
+        queue: bool,
+        /// Seconds a test may wait in the queue before it's rejected as busy
+        #[arg(long, default_value = "600", value_parser = parse_seconds, requires = "queue")]
+        max_queue_wait: Duration,
+        /// Exit after the first test
+        #[arg(long, short = '1')]
+        one_off: bool,
+        /// Exit after no test ran for this many seconds
+        #[arg(long)]
+        idle_timeout: Option<u64>,
+        /// Run in the background
+        #[arg(long, short = 'D')]
+        daemon: bool,
+        /// Write the process id to this file
+        #[arg(long)]
+        pidfile: Option<PathBuf>,
+        /// Append the output to this file in daemon mode instead of discarding it
+        #[arg(long, requires = "daemon")]
+        log_file: Option<PathBuf>,
+        /// Accept admin requests on this Unix socket, see the admin command
+        #[arg(long)]
+        admin_socket: Option<PathBuf>,
     },
+    /// Inspect and control a running server through its admin socket
+    Admin {
+        /// The admin socket of the server
+        #[arg(long, short)]
+        socket: PathBuf,
+        #[command(subcommand)]
+        command: AdminCommands,
+    },
+}
+
+/// The options of the commands that run tests
+#[derive(Debug, Args)]
+struct TestArgs {
+    /// time in seconds to transmit
+    #[arg(long, short, default_value_t = 10, conflicts_with = "bytes")]
+    time: u32,
+    /// number of bytes to transmit, 0 for unlimited
+    #[arg(long, short = 'n')]
+    bytes: Option<u64>,
+    /// port to listen on
+    #[arg(long, short, default_value_t = DEFAULT_PORT, value_parser = clap::value_parser!(u16).range(1..))]
+    port: u16,
+    /// Direction to send
+    #[arg(long, short, default_value_t = Direction::Uni)]
+    direction: Direction,
+    /// target bitrate
+    #[arg(long, short, value_parser = parse_u64_with_suffix)]
+    bitrate: Option<u64>,
+    /// How the target bitrate is enforced
+    #[arg(long, default_value_t = PacingMode::App, requires = "bitrate")]
+    pacing: PacingMode,
+    /// User to authenticate as
+    #[arg(long, requires = "key_file")]
+    user: Option<String>,
+    /// File holding the hex encoded pre-shared key of the user
+    #[arg(long, requires = "user")]
+    key_file: Option<PathBuf>,
+    /// Change the test while it runs: <seconds>:pause, <seconds>:resume,
+    /// <seconds>:bitrate=<rate> or <seconds>:length=<bytes>, send-length=<bytes> or
+    /// recv-length=<bytes> for the writes or the reads only. Can be repeated.
+    #[arg(long = "change", value_parser = parse_change)]
+    changes: Vec<(Duration, TestUpdate)>,
+    /// Keep the test running when the data connection breaks, connecting again and
+    /// reporting each outage and how long it took to regain the throughput
+    #[arg(long)]
+    resilience: bool,
+}
+
+impl TestArgs {
+    fn credentials(&self) -> Result<Option<Credentials>, Error> {
+        match (&self.user, &self.key_file) {
+            (Some(user), Some(key_file)) => {
+                Ok(Some(Credentials::from_key_file(user.clone(), key_file)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn test_config(self, proto: Protocol) -> TestConfig {
+        TestConfig {
+            bw: self.bitrate,
+            pacing: self.pacing.into(),
+            proto,
+            direction: self.direction.into(),
+            end_condition: match self.bytes {
+                Some(bytes) => EndCondition::Bytes(bytes),
+                None => EndCondition::Time(time::Duration::seconds(self.time.into())),
+            },
+            changes: self.changes,
+            resilient: self.resilience,
+        }
+    }
+}
+
+/// Thresholds checked against the result of the test, the exit code is non-zero if one
+/// isn't met
+#[derive(Debug, Args)]
+struct ExpectArgs {
+    /// Fail unless the bitrate reaches this in every direction of the test
+    #[arg(long, value_parser = parse_u64_with_suffix)]
+    expect_min_bitrate: Option<u64>,
+    /// Fail if the sender retransmitted more segments
+    #[arg(long)]
+    expect_max_retransmits: Option<u64>,
+    /// Fail if the jitter is higher, e.g. 5ms. TCP tests use the mean RTT variation
+    /// reported by the kernel.
+    #[arg(long, value_parser = parse_duration)]
+    expect_max_jitter: Option<Duration>,
+}
+
+impl From<ExpectArgs> for Expectations {
+    fn from(value: ExpectArgs) -> Self {
+        Expectations {
+            min_bitrate: value.expect_min_bitrate,
+            max_retransmits: value.expect_max_retransmits,
+            max_jitter: value.expect_max_jitter,
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum AdminCommands {
+    /// List the outstanding and running tests
+    List,
+    /// Show the live statistics of a running test
+    Stats { id: u64 },
+    /// Cancel a test
+    Cancel { id: u64 },
+    /// Stop accepting tests and stop the server once the running tests are done
+    Drain,
+    /// Stop the server right away
+    Stop,
+}
+
+impl From<AdminCommands> for AdminRequest {
+    fn from(value: AdminCommands) -> Self {
+        match value {
+            AdminCommands::List => AdminRequest::List,
+            AdminCommands::Stats { id } => AdminRequest::Stats { id },
+            AdminCommands::Cancel { id } => AdminRequest::Cancel { id },
+            AdminCommands::Drain => AdminRequest::Drain,
+            AdminCommands::Stop => AdminRequest::Stop,
+        }
+    }
 }
 
 #[derive(Debug, Subcommand)]
@@ -78,6 +332,60 @@ enum ProtocolCommands {
         /// Set the length of the send buffer
         #[arg(long, value_parser = parse_u64_with_suffix, conflicts_with = "length")]
         send_length: Option<u64>,
+        /// Set the socket buffer (window) size, SO_SNDBUF and SO_RCVBUF. The server sets it
+        /// after the handshake, so its window scale may not allow a window that large
+        #[arg(long, short, value_parser = parse_u64_with_suffix)]
+        window: Option<u64>,
+        /// Set the TCP maximum segment size. The server sets it after the handshake, so it
+        /// only limits the segments the server sends
+        #[arg(long, short = 'M', value_parser = parse_u64_with_suffix)]
+        mss: Option<u64>,
+        /// Disable Nagle's algorithm (TCP_NODELAY)
+        #[arg(long, short = 'N')]
+        no_delay: bool,
+        /// Set TCP_NOTSENT_LOWAT
+        #[arg(long, value_parser = parse_u64_with_suffix)]
+        notsent_lowat: Option<u64>,
+        /// Enable TCP_QUICKACK
+        #[arg(long)]
+        quickack: bool,
+        /// Enable SO_KEEPALIVE
+        #[arg(long)]
+        keepalive: bool,
+        /// Congestion control algorithm, a comma separated list runs one test per algorithm
+        /// and prints a comparison
+        #[arg(long, short = 'C', value_delimiter = ',')]
+        congestion: Vec<String>,
+        /// Set the IP TOS byte (IPv6 traffic class), accepts hex with a 0x prefix
+        #[arg(long, short = 'S', value_parser = parse_tos)]
+        tos: Option<u8>,
+        /// Set the DSCP value (0-63)
+        #[arg(long, value_parser = clap::value_parser!(u8).range(..64), conflicts_with = "tos")]
+        dscp: Option<u8>,
+        /// Request ECN for the data connection
+        #[arg(long)]
+        ecn: bool,
+        /// How the sender passes data to the kernel
+        #[arg(long, default_value_t = SendPath::Copy)]
+        send_mode: SendPath,
+        /// What the receiver does with the received data
+        #[arg(long, default_value_t = RecvPath::Copy)]
+        recv_mode: RecvPath,
+        /// The I/O mechanism driving the data connection, uring requires the uring feature
+        #[arg(long, default_value_t = IoBackend::Epoll)]
+        backend: IoBackend,
+        /// The data that is sent
+        #[arg(long, default_value_t = PayloadKind::Pattern)]
+        payload: PayloadKind,
+        /// The bytes repeated by the pattern payload, in hex
+        #[arg(long, value_parser = parse_hex, default_value = "ab")]
+        pattern: HexBytes,
+        /// Seed of the random payload, a random one is picked if it isn't set
+        #[arg(long)]
+        seed: Option<u64>,
+        /// Send blocks with sequence numbers and checksums and verify them on the receiver
+        #[arg(long)]
+        verify: bool,
     },
     UDP,
     DCCP,
@@ -85,6 +393,72 @@ enum ProtocolCommands {
     QUIC,
 }
 
+impl ProtocolCommands {
+    /// The congestion control algorithms to compare, if more than one is given
+    fn congestion_comparison(&self) -> Option<Vec<String>> {
+        match self {
+            ProtocolCommands::TCP { congestion, .. } if congestion.len() > 1 => {
+                Some(congestion.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn into_protocol(self) -> Protocol {
+        match self {
+            ProtocolCommands::TCP {
+                length,
+                send_length,
+                recv_length,
+                window,
+                mss,
+                no_delay,
+                notsent_lowat,
+                quickack,
+                keepalive,
+                congestion,
+                tos,
+                dscp,
+                ecn,
+                send_mode,
+                recv_mode,
+                backend,
+                payload,
+                pattern,
+                seed,
+                verify,
+            } => Protocol::TCP(TCPTestInfo {
+                recv_buf_size: recv_length.unwrap_or(length),
+                send_buf_size: send_length.unwrap_or(length),
+                socket_options: TCPSocketOptions {
+                    window_size: window,
+                    mss,
+                    nodelay: no_delay,
+                    notsent_lowat,
+                    quickack,
+                    keepalive,
+                    congestion: congestion.into_iter().next(),
+                    tos: tos.or(dscp.map(|dscp| dscp << 2)),
+                    ecn,
+                },
+                send_mode: send_mode.into(),
+                recv_mode: recv_mode.into(),
+                backend: backend.into(),
+                payload: match payload {
+                    PayloadKind::Zeros => Payload::Zeros,
+                    PayloadKind::Pattern => Payload::Pattern(pattern.0),
+                    PayloadKind::Random => Payload::Random {
+                        seed: seed.unwrap_or_else(rand::random),
+                    },
+                    PayloadKind::File => Payload::File,
+                },
+                verify,
+            }),
+            _ => todo!("only TCP is implemented right now"),
+        }
+    }
+}
+
 impl From<Direction> for netbench::Direction {
     fn from(value: Direction) -> Self {
         match value {
@@ -111,6 +485,164 @@ impl fmt::Display for Direction {
     }
 }
 
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum OverLimitAction {
+    /// Lower the value to the limit and run the test
+    Clamp,
+    /// Refuse to run the test
+    Reject,
+}
+
+impl From<OverLimitAction> for OverLimit {
+    fn from(value: OverLimitAction) -> Self {
+        match value {
+            OverLimitAction::Clamp => OverLimit::Clamp,
+            OverLimitAction::Reject => OverLimit::Reject,
+        }
+    }
+}
+
+impl fmt::Display for OverLimitAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.to_possible_value().unwrap().get_name().fmt(f)
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum PacingMode {
+    /// Rate limit the writes in netbench
+    App,
+    /// Let the kernel pace the socket (SO_MAX_PACING_RATE)
+    Kernel,
+}
+
+impl From<PacingMode> for Pacing {
+    fn from(value: PacingMode) -> Self {
+        match value {
+            PacingMode::App => Pacing::Application,
+            PacingMode::Kernel => Pacing::Kernel,
+        }
+    }
+}
+
+impl fmt::Display for PacingMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.to_possible_value().unwrap().get_name().fmt(f)
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum SendPath {
+    /// Copy the send buffer into the kernel
+    Copy,
+    /// Send with MSG_ZEROCOPY
+    Zerocopy,
+    /// Send the file given with --file using sendfile
+    Sendfile,
+}
+
+impl From<SendPath> for SendMode {
+    fn from(value: SendPath) -> Self {
+        match value {
+            SendPath::Copy => SendMode::Copy,
+            SendPath::Zerocopy => SendMode::ZeroCopy,
+            SendPath::Sendfile => SendMode::Sendfile,
+        }
+    }
+}
+
+impl fmt::Display for SendPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.to_possible_value().unwrap().get_name().fmt(f)
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum RecvPath {
+    /// Read into the receive buffer and discard the data
+    Copy,
+    /// Discard the data through a pipe without copying it to user space
+    Splice,
+    /// Map the received data with TCP_ZEROCOPY_RECEIVE
+    Zerocopy,
+    /// Write the received data to the file given with --recv-file
+    File,
+}
+
+impl From<RecvPath> for RecvMode {
+    fn from(value: RecvPath) -> Self {
+        match value {
+            RecvPath::Copy => RecvMode::Copy,
+            RecvPath::Splice => RecvMode::Splice,
+            RecvPath::Zerocopy => RecvMode::ZeroCopy,
+            RecvPath::File => RecvMode::File,
+        }
+    }
+}
+
+impl fmt::Display for RecvPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.to_possible_value().unwrap().get_name().fmt(f)
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum IoBackend {
+    /// Readiness based I/O through epoll
+    Epoll,
+    /// io_uring with multishot receives and registered buffers
+    Uring,
+}
+
+impl From<IoBackend> for Backend {
+    fn from(value: IoBackend) -> Self {
+        match value {
+            IoBackend::Epoll => Backend::Epoll,
+            IoBackend::Uring => Backend::Uring,
+        }
+    }
+}
+
+impl fmt::Display for IoBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.to_possible_value().unwrap().get_name().fmt(f)
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum PayloadKind {
+    /// All zero bytes
+    Zeros,
+    /// The bytes given with --pattern repeated
+    Pattern,
+    /// Incompressible pseudo-random data generated from --seed
+    Random,
+    /// The contents of the file given with --file
+    File,
+}
+
+impl fmt::Display for PayloadKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.to_possible_value().unwrap().get_name().fmt(f)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct HexBytes(Vec<u8>);
+
+fn parse_hex(s: &str) -> Result<HexBytes, String> {
+    let hex = s.strip_prefix("0x").unwrap_or(s);
+    if hex.is_empty() || !hex.len().is_multiple_of(2) {
+        return Err("expected a non-empty, even number of hex digits".to_string());
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|e| e.to_string()))
+        .collect::<Result<_, _>>()
+        .map(HexBytes)
+}
+
 #[derive(ValueEnum, Debug, Clone, Copy, PartialEq, PartialOrd)]
 enum ColorWhen {
     Always,
@@ -124,65 +656,499 @@ impl fmt::Display for ColorWhen {
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Error> {
+fn parse_tos(s: &str) -> Result<u8, std::num::ParseIntError> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+/// Parses a buffer length the server accepts, suffixes are allowed
+fn parse_length(s: &str) -> Result<u64, String> {
+    match parse_u64_with_suffix(s) {
+        Ok(length @ 1..=MAX_BUFFER_SIZE) => Ok(length),
+        Ok(_) => Err(format!(
+            "invalid length {s:?}, expected 1 to {MAX_BUFFER_SIZE} bytes"
+        )),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Parses a positive number of seconds, fractions are allowed
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    s.parse()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .filter(|duration| !duration.is_zero())
+        .ok_or_else(|| format!("invalid time {s:?}"))
+}
+
+/// Parses a positive number of minutes, fractions are allowed
+fn parse_minutes(s: &str) -> Result<Duration, String> {
+    parse_seconds(s).map(|minutes| minutes * 60)
+}
+
+/// Parses a duration with a unit of s, ms or us, e.g. 5ms
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (value, scale) = [("ms", 1e-3), ("us", 1e-6), ("s", 1.0)]
+        .into_iter()
+        .find_map(|(unit, scale)| Some((s.strip_suffix(unit)?, scale)))
+        .ok_or_else(|| format!("invalid duration {s:?}, expected a unit of s, ms or us"))?;
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .and_then(|value| Duration::try_from_secs_f64(value * scale).ok())
+        .ok_or_else(|| format!("invalid duration {s:?}"))
+}
+
+/// Records the outcome of each check in `summary` and prints it unless the summary is
+/// printed as JSON, returns whether all of them passed
+fn report_checks(
+    expectations: &Expectations,
+    summary: &mut TestSummary,
+    direction: netbench::Direction,
+    prefix: &str,
+    json: bool,
+) -> bool {
+    summary.checks = expectations.check(summary, direction);
+    if !json {
+        for result in &summary.checks {
+            println!("{prefix}{result}");
+        }
+    }
+
+    summary.checks.iter().all(|result| result.passed)
+}
+
+/// Parses `<ip>` or `<ip>:<port>`
+fn parse_server(s: &str, port: u16) -> Result<SocketAddr, Error> {
+    s.parse()
+        .or_else(|_| s.parse().map(|ip: IpAddr| SocketAddr::new(ip, port)))
+        .map_err(|_| anyhow!("invalid server {s:?}, expected an IP with an optional port"))
+}
+
+/// Parses `<seconds>:<change>`, see `Commands::Client::changes`
+fn parse_change(s: &str) -> Result<(Duration, TestUpdate), String> {
+    let (at, change) = s.split_once(':').ok_or("expected <seconds>:<change>")?;
+    let at = at
+        .parse()
+        .ok()
+        .and_then(|at| Duration::try_from_secs_f64(at).ok())
+        .ok_or_else(|| format!("invalid time {at:?}"))?;
+    let parse_value = |value| parse_u64_with_suffix(value).map_err(|e| e.to_string());
+    let update = match change.split_once('=') {
+        None if change == "pause" => TestUpdate::Pause,
+        None if change == "resume" => TestUpdate::Resume,
+        Some(("bitrate", bitrate)) => TestUpdate::Bitrate(parse_value(bitrate)?),
+        Some(("length", length)) => {
+            let length = parse_value(length)?;
+            TestUpdate::BufferSize {
+                send: Some(length),
+                recv: Some(length),
+            }
+        }
+        Some(("send-length", length)) => TestUpdate::BufferSize {
+            send: Some(parse_value(length)?),
+            recv: None,
+        },
+        Some(("recv-length", length)) => TestUpdate::BufferSize {
+            send: None,
+            recv: Some(parse_value(length)?),
+        },
+        _ => {
+            return Err(format!(
+                "unknown change {change:?}, expected pause, resume, bitrate=<rate>, \
+            length=<bytes>, send-length=<bytes> or recv-length=<bytes>"
+            ))
+        }
+    };
+
+    Ok((at, update))
+}
+
+/// The first signal cancels the running tests of the client on both sides, so that their
+/// partial results are still printed. The second one exits right away. Returns the exit
+/// code for the signal, 0 until there was one.
+fn cancel_on_signal(canceller: Canceller) -> io::Result<Arc<AtomicU8>> {
+    let interrupted = Arc::new(AtomicU8::new(0));
+    let signal = shutdown_signal()?;
+    let interrupt = interrupted.clone();
+    tokio::spawn(async move {
+        let code = signal.await;
+        interrupt.store(code, Ordering::SeqCst);
+        if !canceller.cancel_all().await {
+            process::exit(code.into());
+        }
+        if let Ok(signal) = shutdown_signal() {
+            process::exit(signal.await.into());
+        }
+    });
+
+    Ok(interrupted)
+}
+
+/// Resolves to the exit code for the next SIGINT or SIGTERM, 128 plus the signal number
+fn shutdown_signal() -> io::Result<impl Future<Output = u8>> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    Ok(async move {
+        let signal = tokio::select! {
+            _ = interrupt.recv() => libc::SIGINT,
+            _ = terminate.recv() => libc::SIGTERM,
+        };
+        128 + signal as u8
+    })
+}
+
+fn main() -> Result<ExitCode, Error> {
+    let matches = Cli::parse();
+    // changes the environment, and LISTEN_PID names the process before it forks
+    let listener = match matches.command {
+        Commands::Server { .. } => netbench::systemd_listener()?,
+        _ => None,
+    };
+    // forking has to happen before the runtime starts its threads
+    if let Commands::Server {
+        daemon: true,
+        log_file,
+        ..
+    } = &matches.command
+    {
+        netbench::daemonize(log_file.as_deref())?;
+    }
+
     let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(""));
     tracing_subscriber::fmt()
         .with_max_level(Level::TRACE)
         .with_env_filter(env_filter)
         .init();
 
-    let matches = Cli::parse();
+    tokio::runtime::Runtime::new()?.block_on(run(matches, listener))
+}
+
+/// `listener` is the listening socket passed by systemd, if any
+async fn run(matches: Cli, listener: Option<TcpListener>) -> Result<ExitCode, Error> {
+    if matches.heartbeat_interval >= matches.peer_timeout {
+        return Err(anyhow!(
+            "the heartbeat interval has to be shorter than the peer timeout"
+        ));
+    }
     let common_config = CommonConfig {
-        file: None,
+        file: matches.file,
+        recv_file: matches.recv_file,
         format: SizePreference::Auto,
         base: BasePreference::Base2,
+        heartbeat: HeartbeatConfig {
+            interval: matches.heartbeat_interval,
+            timeout: matches.peer_timeout,
+        },
     };
 
     match matches.command {
         Commands::Client {
             host,
-            port,
             proto,
-            direction,
-            ..
+            test,
+            expect,
+            repeat,
+            pause,
         } => {
-            let proto = match proto {
-                ProtocolCommands::TCP {
-                    length,
-                    send_length,
-                    recv_length,
-                } => Protocol::TCP(TCPTestInfo {
-                    recv_buf_size: recv_length.unwrap_or(length),
-                    send_buf_size: send_length.unwrap_or(length),
-                }),
-                _ => todo!("only TCP is implemented right now"),
+            let congestion_comparison = proto.congestion_comparison();
+            if congestion_comparison.is_some() && repeat > 1 {
+                return Err(anyhow!(
+                    "--repeat can't be combined with comparing congestion control algorithms"
+                ));
+            }
+            let proto = proto.into_protocol();
+            let addr = format!("{}:{}", host, test.port);
+            let config = ClientConfig {
+                addr: addr.parse().unwrap(),
+                common: common_config,
+                credentials: test.credentials()?,
+                test: test.test_config(proto),
+                quiet: matches.json,
             };
-            let addr = format!("{}:{}", host, port);
+            let direction = config.test.direction;
+            let expectations = Expectations::from(expect);
+            if expectations.max_retransmits.is_some()
+                && direction == netbench::Direction::Bidirectional
+            {
+                return Err(anyhow!(
+                    "--expect-max-retransmits can't be checked for bidirectional tests"
+                ));
+            }
+
+            let mut c = Client::new(config).await?;
+            let interrupted = cancel_on_signal(c.canceller())?;
+
+            let json = matches.json;
+            let mut passed = true;
+            if let Some(algorithms) = congestion_comparison {
+                let mut comparison = c.compare_congestion_control(&algorithms).await?;
+                if !matches.json {
+                    println!("{comparison}");
+                }
+                for (algorithm, summary) in &mut comparison.0 {
+                    let prefix = format!("[{algorithm}] ");
+                    passed &= report_checks(&expectations, summary, direction, &prefix, json);
+                }
+                if matches.json {
+                    println!("{}", serde_json::to_string_pretty(&comparison)?);
+                }
+            } else if repeat > 1 {
+                let mut repeated = c.repeat_test(repeat as usize, pause).await?;
+                for (run, summary) in repeated.runs.iter_mut().enumerate() {
+                    let prefix = format!("[run {}] ", run + 1);
+                    passed &= report_checks(&expectations, summary, direction, &prefix, json);
+                }
+                if matches.json {
+                    println!("{}", serde_json::to_string_pretty(&repeated)?);
+                } else {
+                    println!("Over {} runs:", repeated.runs.len());
+                    println!("{repeated}");
+                }
+                if repeated
+                    .runs
+                    .iter()
+                    .any(|summary| summary.peer_lost.is_some())
+                {
+                    return Ok(ExitCode::from(PEER_LOST_EXIT_CODE));
+                }
+            } else {
+                let mut summary = c.start_new_test().await?;
+                passed = report_checks(&expectations, &mut summary, direction, "", json);
+                if matches.json {
+                    println!("{}", serde_json::to_string_pretty(&summary)?);
+                }
+                if summary.peer_lost.is_some() {
+                    return Ok(ExitCode::from(PEER_LOST_EXIT_CODE));
+                }
+            }
+
+            match interrupted.load(Ordering::SeqCst) {
+                0 => (),
+                code => return Ok(ExitCode::from(code)),
+            }
+            if !passed {
+                return Ok(ExitCode::from(CHECK_FAILED_EXIT_CODE));
+            }
+        }
+
+        Commands::Sweep {
+            host,
+            proto,
+            test,
+            lengths,
+            bitrates,
+            directions,
+            csv,
+        } => {
+            if proto.congestion_comparison().is_some() {
+                return Err(anyhow!(
+                    "a sweep uses a single congestion control algorithm, pick one"
+                ));
+            }
+            if csv && matches.json {
+                return Err(anyhow!("--csv and --json can't be combined"));
+            }
+            let addr = format!("{}:{}", host, test.port);
             let config = ClientConfig {
                 addr: addr.parse().unwrap(),
                 common: common_config,
-                bw: None,
-                proto,
-                direction: direction.into(),
+                credentials: test.credentials()?,
+                test: test.test_config(proto.into_protocol()),
+                quiet: matches.json,
             };
+            let grid = SweepGrid {
+                lengths,
+                bitrates,
+                directions: directions.into_iter().map(Into::into).collect(),
+            };
+
+            let mut c = Client::new(config).await?;
+            let interrupted = cancel_on_signal(c.canceller())?;
+            let sweep = c.sweep(&grid).await?;
+            if matches.json {
+                println!("{}", serde_json::to_string_pretty(&sweep)?);
+            } else if csv {
+                print!("{}", sweep.to_csv());
+            } else {
+                println!("{sweep}");
+            }
 
-            let mut c = Client::new(config).await.unwrap();
-            c.start_new_test().await.unwrap();
+            if sweep
+                .0
+                .iter()
+                .any(|point| point.summary.peer_lost.is_some())
+            {
+                return Ok(ExitCode::from(PEER_LOST_EXIT_CODE));
+            }
+            match interrupted.load(Ordering::SeqCst) {
+                0 => (),
+                code => return Ok(ExitCode::from(code)),
+            }
         }
 
-        Commands::Server { host, port } => {
+        Commands::Monitor {
+            servers,
+            proto,
+            test,
+            every,
+            output,
+        } => {
+            if proto.congestion_comparison().is_some() {
+                return Err(anyhow!(
+                    "the monitor runs a single test, pick one congestion control algorithm"
+                ));
+            }
+            let servers = servers
+                .iter()
+                .map(|server| parse_server(server, test.port))
+                .collect::<Result<_, _>>()?;
+            let config = MonitorConfig {
+                common: common_config,
+                servers,
+                credentials: test.credentials()?,
+                test: test.test_config(proto.into_protocol()),
+                every,
+                output,
+            };
+
+            // the first signal stops the monitor once the partial result of the running
+            // test is recorded. The second one exits right away.
+            let interrupted = Arc::new(AtomicU8::new(0));
+            let signal = shutdown_signal()?;
+            let interrupt = interrupted.clone();
+            let (stop, stopped) = oneshot::channel();
+            tokio::spawn(async move {
+                let code = signal.await;
+                interrupt.store(code, Ordering::SeqCst);
+                let _ = stop.send(());
+                if let Ok(signal) = shutdown_signal() {
+                    process::exit(signal.await.into());
+                }
+            });
+
+            Monitor::new(config)
+                .run(async {
+                    let _ = stopped.await;
+                })
+                .await?;
+            match interrupted.load(Ordering::SeqCst) {
+                0 => (),
+                code => return Ok(ExitCode::from(code)),
+            }
+        }
+
+        Commands::Capabilities { host, port } => {
+            let addr = format!("{}:{}", host, port);
+            let hello = Client::query_server(addr.parse()?).await?;
+            println!("{hello}");
+        }
+
+        Commands::Server {
+            host,
+            port,
+            auth_file,
+            allow,
+            deny,
+            max_duration,
+            max_bytes,
+            max_bitrate,
+            max_buffer,
+            allow_direction,
+            over_limit,
+            max_tests,
+            queue,
+            max_queue_wait,
+            one_off,
+            idle_timeout,
+            pidfile,
+            admin_socket,
+            ..
+        } => {
             let addr = format!("{}:{}", host, port);
             let config = ServerConfig {
                 addr: addr.parse().unwrap(),
                 common: common_config,
+                keys: auth_file
+                    .map(|auth_file| KeyTable::from_file(&auth_file))
+                    .transpose()?,
+                access: AccessList { allow, deny },
+                policy: Policy {
+                    max_duration: max_duration.map(Duration::from_secs),
+                    max_bytes,
+                    max_bitrate,
+                    max_buffer_size: max_buffer,
+                    directions: (!allow_direction.is_empty())
+                        .then(|| allow_direction.into_iter().map(Into::into).collect()),
+                    over_limit: over_limit.into(),
+                },
+                max_tests,
+                queue_tests: queue,
+                max_queue_wait,
+                one_off,
+                idle_timeout: idle_timeout.map(Duration::from_secs),
+                admin_socket,
+            };
+            let _pidfile = pidfile.map(PidFile::create).transpose()?;
+            let (mut server, com_tx) = match listener {
+                Some(listener) => Server::with_listener(config, listener)?,
+                None => Server::new(config)?,
             };
-            let (mut server, _) = Server::new(config).await.unwrap();
-            server.accept().await.unwrap();
+            // the first signal cancels the running tests and stops the server once their
+            // partial results are printed, idle or not it exits with the signal's code.
+            // The second one exits right away.
+            let interrupted = Arc::new(AtomicU8::new(0));
+            let signal = shutdown_signal()?;
+            let interrupt = interrupted.clone();
+            tokio::spawn(async move {
+                let code = signal.await;
+                // before the server can stop
+                interrupt.store(code, Ordering::SeqCst);
+                let (reply, cancelled) = oneshot::channel();
+                if com_tx.send(ControlMessage::Interrupt(reply)).await.is_err() {
+                    return;
+                }
+                let _ = cancelled.await;
+                if let Ok(signal) = shutdown_signal() {
+                    process::exit(signal.await.into());
+                }
+            });
+
+            if let Err(e) = netbench::notify("READY=1") {
+                warn!("failed to notify the service manager: {e}");
+            }
+            server.accept().await?;
+            let _ = netbench::notify("STOPPING=1");
+            match interrupted.load(Ordering::SeqCst) {
+                0 => (),
+                code => return Ok(ExitCode::from(code)),
+            }
+        }
+
+        Commands::Admin { socket, command } => {
+            let response = netbench::admin_request(&socket, command.into()).await?;
+            if matches.json {
+                println!("{}", serde_json::to_string_pretty(&response)?);
+            }
+            match response {
+                AdminResponse::Error(message) => return Err(anyhow!(message)),
+                _ if matches.json => (),
+                AdminResponse::Tests(tests) if tests.is_empty() => println!("No tests"),
+                AdminResponse::Tests(tests) => {
+                    for test in tests {
+                        println!("{test}");
+                    }
+                }
+                AdminResponse::Stats(stats) => println!("{stats}"),
+                AdminResponse::Done => println!("Done"),
+            }
         }
     }
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
 
 #[test]
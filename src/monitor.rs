@@ -0,0 +1,271 @@
+use std::fmt;
+use std::fs::OpenOptions;
+use std::future::Future;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::{Client, ClientConfig, CommonConfig, Credentials, NBError, TestConfig, TestSummary};
+
+/// Delay before a failed test is tried again, doubled with every further failure
+const BACKOFF_START: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub struct MonitorConfig {
+    pub common: CommonConfig,
+    /// Tested one after the other
+    pub servers: Vec<SocketAddr>,
+    pub test: TestConfig,
+    /// Used for the servers that require authentication
+    pub credentials: Option<Credentials>,
+    /// Time between the tests of a server
+    pub every: Duration,
+    /// File the results are appended to as JSON lines. If not set they are printed, and
+    /// the progress of the tests isn't.
+    pub output: Option<PathBuf>,
+}
+
+/// The result of a single test, written as one JSON line
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MonitorRecord {
+    /// Start of the test in seconds since the unix epoch
+    pub timestamp: i64,
+    pub server: SocketAddr,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<TestSummary>,
+    /// Set if the test failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// How a test ended
+enum Outcome {
+    Finished(Result<TestSummary>),
+    /// Cancelled because the monitor is shut down
+    Cancelled(Result<TestSummary>),
+    /// Shut down before the test started
+    Stopped,
+}
+
+#[derive(Debug)]
+struct Schedule {
+    addr: SocketAddr,
+    /// The next regular test
+    slot: Instant,
+    /// The next test, earlier than `slot` while a failed test is retried
+    next: Instant,
+    /// Number of tests that failed in a row
+    failures: u32,
+}
+
+impl Schedule {
+    /// Schedules the next test after one ended at `now`
+    fn advance(&mut self, every: Duration, result: &Result<TestSummary>, now: Instant) {
+        // skip the slots missed while the test ran
+        while self.slot <= now {
+            self.slot += every;
+        }
+
+        let retry_after = match result {
+            Ok(summary) if summary.peer_lost.is_none() => None,
+            Ok(_) => Some(None),
+            Err(e) => match e.downcast_ref::<NBError>() {
+                Some(NBError::ServerBusy(retry_after)) => {
+                    Some(Some(Duration::from_secs(*retry_after)))
+                }
+                _ => Some(None),
+            },
+        };
+        self.next = match retry_after {
+            None => {
+                self.failures = 0;
+                self.slot
+            }
+            Some(retry_after) => {
+                self.failures += 1;
+                let backoff = BACKOFF_START.saturating_mul(1 << (self.failures - 1).min(16));
+                (now + retry_after.unwrap_or(backoff)).min(self.slot)
+            }
+        };
+    }
+}
+
+/// Runs the configured test against every server periodically, e.g. to keep track of
+/// the throughput of a link over days
+#[derive(Debug)]
+pub struct Monitor {
+    config: MonitorConfig,
+    schedule: Vec<Schedule>,
+}
+
+impl Monitor {
+    pub fn new(config: MonitorConfig) -> Self {
+        let now = Instant::now();
+        let schedule = config
+            .servers
+            .iter()
+            .map(|&addr| Schedule {
+                addr,
+                slot: now,
+                next: now,
+                failures: 0,
+            })
+            .collect();
+
+        Monitor { config, schedule }
+    }
+
+    /// Runs the tests until `shutdown` resolves. A test that runs at that time is
+    /// cancelled, its partial result is still recorded.
+    pub async fn run(&mut self, shutdown: impl Future<Output = ()>) -> Result<()> {
+        tokio::pin!(shutdown);
+        loop {
+            let (index, next) = self
+                .schedule
+                .iter()
+                .enumerate()
+                .map(|(index, server)| (index, server.next))
+                .min_by_key(|(_, next)| *next)
+                .context("no servers to monitor")?;
+            tokio::select! {
+                _ = tokio::time::sleep_until(next) => (),
+                _ = &mut shutdown => return Ok(()),
+            }
+
+            let addr = self.schedule[index].addr;
+            self.status(format_args!("Testing {addr}"));
+            let timestamp = OffsetDateTime::now_utc().unix_timestamp();
+            let (result, stopped) = match self.test(addr, shutdown.as_mut()).await {
+                Outcome::Finished(result) => (result, false),
+                Outcome::Cancelled(result) => (result, true),
+                Outcome::Stopped => return Ok(()),
+            };
+            let record = MonitorRecord {
+                timestamp,
+                server: addr,
+                summary: result.as_ref().ok().cloned(),
+                error: result.as_ref().err().map(|e| format!("{e:#}")),
+            };
+            // a full disk shouldn't end the monitoring, the next record may make it
+            if let Err(e) = self.write(&record) {
+                warn!("failed to record the test against {addr}: {e:#}");
+            }
+            if stopped {
+                return Ok(());
+            }
+
+            let server = &mut self.schedule[index];
+            let now = Instant::now();
+            server.advance(self.config.every, &result, now);
+            if let Err(e) = &result {
+                let retry = (server.next - now).as_secs();
+                self.status(format_args!(
+                    "Test against {addr} failed: {e:#}, trying again in {retry} seconds"
+                ));
+            }
+        }
+    }
+
+    /// Runs one test against `addr`, cancelling it if `shutdown` resolves first
+    async fn test<F: Future<Output = ()>>(
+        &self,
+        addr: SocketAddr,
+        mut shutdown: Pin<&mut F>,
+    ) -> Outcome {
+        let config = ClientConfig {
+            common: self.config.common.clone(),
+            addr,
+            test: self.config.test.clone(),
+            credentials: self.config.credentials.clone(),
+            // the records are the output of the monitor if they go to stdout
+            quiet: self.config.output.is_none(),
+        };
+        let mut client = tokio::select! {
+            client = Client::new(config) => match client {
+                Ok(client) => client,
+                Err(e) => return Outcome::Finished(Err(e)),
+            },
+            _ = shutdown.as_mut() => return Outcome::Stopped,
+        };
+
+        let canceller = client.canceller();
+        let test = client.start_new_test();
+        tokio::pin!(test);
+        tokio::select! {
+            summary = &mut test => return Outcome::Finished(summary),
+            _ = shutdown => (),
+        }
+        canceller.cancel_all().await;
+        Outcome::Cancelled(test.await)
+    }
+
+    /// Prints the progress of the monitor, to stderr if stdout holds the records
+    fn status(&self, message: fmt::Arguments<'_>) {
+        match self.config.output {
+            Some(_) => println!("{message}"),
+            None => eprintln!("{message}"),
+        }
+    }
+
+    /// Appends `record` to the output file, opened for every record so that the file
+    /// can be rotated while the monitor runs
+    fn write(&self, record: &MonitorRecord) -> Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        match &self.config.output {
+            Some(path) => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| file.write_all(line.as_bytes()))
+                .with_context(|| format!("failed to write to {}", path.display()))?,
+            None => print!("{line}"),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_schedule_backoff() {
+        let every = Duration::from_secs(600);
+        let start = Instant::now();
+        let mut server = Schedule {
+            addr: "127.0.0.1:5202".parse().unwrap(),
+            slot: start,
+            next: start,
+            failures: 0,
+        };
+
+        let failed = || Err(NBError::Timeout.into());
+        server.advance(every, &failed(), start);
+        assert_eq!(server.next, start + BACKOFF_START);
+        server.advance(every, &failed(), start);
+        assert_eq!(server.next, start + BACKOFF_START * 2);
+        server.advance(every, &Err(NBError::ServerBusy(30).into()), start);
+        assert_eq!(server.next, start + Duration::from_secs(30));
+        for _ in 0..10 {
+            server.advance(every, &failed(), start);
+        }
+        assert_eq!(server.next, start + every);
+
+        // the slots missed by a long test are skipped
+        let now = start + every * 2 + Duration::from_secs(1);
+        server.advance(every, &Ok(TestSummary::default()), now);
+        assert_eq!(server.failures, 0);
+        assert_eq!(server.next, start + every * 3);
+    }
+}
@@ -0,0 +1,510 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Payload;
+
+/// Minimum number of pseudo-random bytes generated at once, so that generating the next
+/// ones stays cheap compared to the writes
+const RANDOM_CHUNK_LEN: usize = 64 * 1024;
+
+/// Sequence number, body length and checksum in front of every verified block
+pub(crate) const HEADER_LEN: usize = 16;
+
+/// The payload bytes, sent over and over again. A pseudo-random payload never repeats,
+/// the buffer is refilled with the next bytes of the sequence once it has been sent.
+#[derive(Debug)]
+pub(crate) struct PayloadBuffer {
+    data: Vec<u8>,
+    offset: usize,
+    rng: Option<XorShift>,
+}
+
+impl PayloadBuffer {
+    /// Generates the payload for writes of `len` bytes. `file` is only read for
+    /// `Payload::File`.
+    pub(crate) fn new(payload: &Payload, len: usize, file: Option<&Path>) -> io::Result<Self> {
+        let mut rng = None;
+        let data = match payload {
+            Payload::Zeros => vec![0; len],
+            Payload::Pattern(pattern) => {
+                if pattern.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "the payload pattern is empty",
+                    ));
+                }
+                // keep the pattern intact when the buffer wraps around
+                let len = len.max(1).div_ceil(pattern.len()) * pattern.len();
+                pattern.iter().copied().cycle().take(len).collect()
+            }
+            Payload::Random { seed } => {
+                // whole numbers of the generator's output, so that the sequence doesn't
+                // depend on the length
+                let mut data = vec![0; len.max(RANDOM_CHUNK_LEN).next_multiple_of(8)];
+                rng.insert(XorShift::new(*seed)).fill(&mut data);
+                data
+            }
+            Payload::File => {
+                let path = file.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "no file has been configured")
+                })?;
+                let data = fs::read(path)?;
+                if data.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the payload file is empty",
+                    ));
+                }
+                data
+            }
+        };
+
+        Ok(PayloadBuffer {
+            data,
+            offset: 0,
+            rng,
+        })
+    }
+
+    /// The next at most `len` bytes of the payload
+    pub(crate) fn chunk(&self, len: usize) -> &[u8] {
+        let end = self.data.len().min(self.offset + len);
+        &self.data[self.offset..end]
+    }
+
+    pub(crate) fn advance(&mut self, n: usize) {
+        self.offset += n;
+        if self.offset == self.data.len() {
+            self.offset = 0;
+            if let Some(rng) = &mut self.rng {
+                rng.fill(&mut self.data);
+            }
+        }
+    }
+
+    /// Fills `buf` with the next bytes of the payload
+    fn fill(&mut self, mut buf: &mut [u8]) {
+        while !buf.is_empty() {
+            let chunk = self.chunk(buf.len());
+            let n = chunk.len();
+            buf[..n].copy_from_slice(chunk);
+            buf = &mut buf[n..];
+            self.advance(n);
+        }
+    }
+
+    /// Compares `data` with the next bytes of the payload and moves past them
+    fn matches(&mut self, mut data: &[u8]) -> bool {
+        let mut matches = true;
+        while !data.is_empty() {
+            let chunk = self.chunk(data.len());
+            let n = chunk.len();
+            matches &= chunk == &data[..n];
+            data = &data[n..];
+            self.advance(n);
+        }
+
+        matches
+    }
+
+    /// Moves past the next `n` bytes of the payload
+    fn skip(&mut self, mut n: usize) {
+        while n > 0 {
+            let len = self.chunk(n).len();
+            self.advance(len);
+            n -= len;
+        }
+    }
+}
+
+/// xorshift64*, good enough to defeat compression and reproducible from the seed
+#[derive(Debug)]
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        // the state must never be zero
+        XorShift(seed ^ 0x9E37_79B9_7F4A_7C15)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Fills `buf` with the next bytes, eight per number
+    fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            chunk.copy_from_slice(&self.next().to_le_bytes()[..chunk.len()]);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Adler32 {
+    a: u32,
+    b: u32,
+}
+
+impl Adler32 {
+    const MOD: u32 = 65521;
+    /// Maximum number of bytes before the sums have to be reduced to avoid an overflow
+    const NMAX: usize = 5552;
+
+    fn new() -> Self {
+        Adler32 { a: 1, b: 0 }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for chunk in data.chunks(Self::NMAX) {
+            for &byte in chunk {
+                self.a += u32::from(byte);
+                self.b += self.a;
+            }
+            self.a %= Self::MOD;
+            self.b %= Self::MOD;
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        (self.b << 16) | self.a
+    }
+}
+
+/// Splits the payload into blocks of `len` bytes. Every block starts with a header
+/// holding its sequence number and the checksum of its body, so that the receiver can
+/// detect corrupted and missing data.
+#[derive(Debug)]
+pub(crate) struct BlockWriter {
+    payload: PayloadBuffer,
+    block: Vec<u8>,
+    seq: u64,
+    written: usize,
+}
+
+impl BlockWriter {
+    pub(crate) fn new(payload: PayloadBuffer, len: usize) -> io::Result<Self> {
+        if len <= HEADER_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("the send buffer has to be longer than {HEADER_LEN} bytes to verify data"),
+            ));
+        }
+
+        let mut writer = BlockWriter {
+            payload,
+            block: vec![0; len],
+            seq: 0,
+            written: 0,
+        };
+        writer.next_block();
+
+        Ok(writer)
+    }
+
+    fn next_block(&mut self) {
+        let (header, body) = self.block.split_at_mut(HEADER_LEN);
+        self.payload.fill(body);
+        let mut checksum = Adler32::new();
+        checksum.update(body);
+
+        header[..8].copy_from_slice(&self.seq.to_be_bytes());
+        header[8..12].copy_from_slice(&(body.len() as u32).to_be_bytes());
+        header[12..].copy_from_slice(&checksum.finish().to_be_bytes());
+        self.written = 0;
+    }
+
+    fn chunk(&self) -> &[u8] {
+        &self.block[self.written..]
+    }
+
+    fn advance(&mut self, n: usize) {
+        self.written += n;
+        if self.written == self.block.len() {
+            self.seq += 1;
+            self.next_block();
+        }
+    }
+}
+
+/// What the sender writes to the data connection
+#[derive(Debug)]
+pub(crate) enum SendPayload {
+    Stream(PayloadBuffer),
+    Blocks(BlockWriter),
+}
+
+impl SendPayload {
+    /// The bytes the next write of at most `len` bytes has to send
+    pub(crate) fn chunk(&self, len: usize) -> &[u8] {
+        match self {
+            SendPayload::Stream(payload) => payload.chunk(len),
+            SendPayload::Blocks(blocks) => blocks.chunk(),
+        }
+    }
+
+    /// Marks `n` bytes as sent
+    pub(crate) fn advance(&mut self, n: usize) {
+        match self {
+            SendPayload::Stream(payload) => payload.advance(n),
+            SendPayload::Blocks(blocks) => blocks.advance(n),
+        }
+    }
+}
+
+/// Results of checking the received blocks
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IntegrityReport {
+    pub verified_blocks: u64,
+    pub corrupted_bytes: u64,
+    pub missing_bytes: u64,
+}
+
+/// Checks the blocks written by a `BlockWriter`
+#[derive(Debug)]
+pub(crate) struct Verifier {
+    block_len: usize,
+    /// Generates the payload the bodies have to match, if it's known to the receiver
+    expected: Option<PayloadBuffer>,
+    /// Whether the body of the current block matched the payload so far
+    body_matches: bool,
+    header: [u8; HEADER_LEN],
+    header_filled: usize,
+    /// Whether the header of the current block is plausible
+    header_valid: bool,
+    checksum: u32,
+    body_remaining: usize,
+    adler: Adler32,
+    expected_seq: u64,
+    pub(crate) report: IntegrityReport,
+}
+
+impl Verifier {
+    /// `block_len` is the send buffer length of the sender. Without the `expected` payload
+    /// only the checksums of the blocks are checked.
+    pub(crate) fn new(block_len: usize, expected: Option<PayloadBuffer>) -> io::Result<Self> {
+        if block_len <= HEADER_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("the send buffer has to be longer than {HEADER_LEN} bytes to verify data"),
+            ));
+        }
+
+        Ok(Verifier {
+            block_len,
+            expected,
+            body_matches: true,
+            header: [0; HEADER_LEN],
+            header_filled: 0,
+            header_valid: false,
+            checksum: 0,
+            body_remaining: 0,
+            adler: Adler32::new(),
+            expected_seq: 0,
+            report: IntegrityReport::default(),
+        })
+    }
+
+    pub(crate) fn feed(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.header_filled < HEADER_LEN {
+                let n = data.len().min(HEADER_LEN - self.header_filled);
+                self.header[self.header_filled..self.header_filled + n].copy_from_slice(&data[..n]);
+                self.header_filled += n;
+                data = &data[n..];
+                if self.header_filled == HEADER_LEN {
+                    self.parse_header();
+                }
+                continue;
+            }
+
+            let n = data.len().min(self.body_remaining);
+            self.adler.update(&data[..n]);
+            if let Some(expected) = &mut self.expected {
+                self.body_matches &= expected.matches(&data[..n]);
+            }
+            self.body_remaining -= n;
+            data = &data[n..];
+            if self.body_remaining == 0 {
+                self.finish_block();
+            }
+        }
+    }
+
+    fn parse_header(&mut self) {
+        let seq = u64::from_be_bytes(self.header[..8].try_into().unwrap());
+        let len = u32::from_be_bytes(self.header[8..12].try_into().unwrap());
+        self.checksum = u32::from_be_bytes(self.header[12..].try_into().unwrap());
+        self.body_remaining = self.block_len - HEADER_LEN;
+        self.adler = Adler32::new();
+
+        // TCP keeps the block boundaries in place, so a damaged header is treated as a
+        // corrupted block and the next one is expected right after it
+        self.header_valid = len as usize == self.body_remaining && seq >= self.expected_seq;
+        if self.header_valid && seq > self.expected_seq {
+            let missing = seq - self.expected_seq;
+            self.report.missing_bytes += missing * self.block_len as u64;
+            if let Some(expected) = &mut self.expected {
+                expected.skip(missing as usize * self.body_remaining);
+            }
+            self.expected_seq = seq;
+        }
+        self.body_matches = true;
+    }
+
+    fn finish_block(&mut self) {
+        if self.header_valid && self.body_matches && self.adler.finish() == self.checksum {
+            self.report.verified_blocks += 1;
+        } else {
+            self.report.corrupted_bytes += self.block_len as u64;
+        }
+        self.expected_seq += 1;
+        self.header_filled = 0;
+    }
+
+    /// Accounts for a block cut short by the end of the test
+    pub(crate) fn finish(&mut self) -> IntegrityReport {
+        if self.header_filled > 0 {
+            let received = self.header_filled + (self.block_len - HEADER_LEN - self.body_remaining);
+            self.report.missing_bytes += (self.block_len - received) as u64;
+            self.header_filled = 0;
+        }
+
+        self.report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn blocks(len: usize, n: usize) -> Vec<u8> {
+        let payload = PayloadBuffer::new(&Payload::Random { seed: 1 }, len, None).unwrap();
+        let mut writer = BlockWriter::new(payload, len).unwrap();
+        let mut data = Vec::new();
+        while data.len() < len * n {
+            // partial writes must not break the framing
+            let chunk = &writer.chunk()[..7.min(writer.chunk().len())];
+            data.extend_from_slice(chunk);
+            let n = chunk.len();
+            writer.advance(n);
+        }
+
+        data
+    }
+
+    #[test]
+    fn test_pattern() {
+        let mut payload = PayloadBuffer::new(&Payload::Pattern(vec![1, 2, 3]), 4, None).unwrap();
+        assert_eq!(payload.chunk(4), &[1, 2, 3, 1]);
+        payload.advance(4);
+        assert_eq!(payload.chunk(4), &[2, 3]);
+        payload.advance(2);
+        assert_eq!(payload.chunk(4), &[1, 2, 3, 1]);
+
+        assert!(PayloadBuffer::new(&Payload::Pattern(vec![]), 4, None).is_err());
+    }
+
+    #[test]
+    fn test_random_is_reproducible() {
+        let a = PayloadBuffer::new(&Payload::Random { seed: 7 }, 64, None).unwrap();
+        let b = PayloadBuffer::new(&Payload::Random { seed: 7 }, 64, None).unwrap();
+        let c = PayloadBuffer::new(&Payload::Random { seed: 8 }, 64, None).unwrap();
+        assert_eq!(a.chunk(64), b.chunk(64));
+        assert_ne!(a.chunk(64), c.chunk(64));
+    }
+
+    #[test]
+    fn test_random_does_not_repeat() {
+        let mut payload = PayloadBuffer::new(&Payload::Random { seed: 7 }, 1000, None).unwrap();
+        let first = payload.chunk(RANDOM_CHUNK_LEN).to_vec();
+        assert_eq!(first.len(), RANDOM_CHUNK_LEN);
+        payload.advance(RANDOM_CHUNK_LEN);
+        assert_ne!(payload.chunk(RANDOM_CHUNK_LEN), first.as_slice());
+
+        // the sequence doesn't depend on the write length
+        let mut other = PayloadBuffer::new(&Payload::Random { seed: 7 }, 100_003, None).unwrap();
+        let mut stream = vec![0; 3 * RANDOM_CHUNK_LEN];
+        other.fill(&mut stream);
+        assert_eq!(&stream[..RANDOM_CHUNK_LEN], first.as_slice());
+        assert!(payload.matches(&stream[RANDOM_CHUNK_LEN..]));
+    }
+
+    #[test]
+    fn test_verify() {
+        let len = 100;
+        let mut data = blocks(len, 10);
+
+        let mut verifier = Verifier::new(len, None).unwrap();
+        for chunk in data.chunks(33) {
+            verifier.feed(chunk);
+        }
+        assert_eq!(
+            verifier.finish(),
+            IntegrityReport {
+                verified_blocks: 10,
+                ..Default::default()
+            }
+        );
+
+        // flip a byte in the body of the second block
+        data[len + HEADER_LEN + 5] ^= 1;
+        // drop the fourth block
+        data.drain(3 * len..4 * len);
+        // cut the last block short
+        data.truncate(data.len() - 10);
+
+        let mut verifier = Verifier::new(len, None).unwrap();
+        verifier.feed(&data);
+        assert_eq!(
+            verifier.finish(),
+            IntegrityReport {
+                verified_blocks: 7,
+                corrupted_bytes: len as u64,
+                missing_bytes: len as u64 + 10,
+            }
+        );
+    }
+
+    #[test]
+    fn test_verify_payload() {
+        let len = 100;
+        let expected = || {
+            let payload = Payload::Random { seed: 1 };
+            Some(PayloadBuffer::new(&payload, len - HEADER_LEN, None).unwrap())
+        };
+        let mut data = blocks(len, 2000);
+
+        let mut verifier = Verifier::new(len, expected()).unwrap();
+        verifier.feed(&data);
+        assert_eq!(verifier.finish().verified_blocks, 2000);
+
+        // a block with a valid checksum but the wrong payload
+        let mut forged = blocks(len, 1);
+        let (header, body) = forged.split_at_mut(HEADER_LEN);
+        body.fill(0);
+        let mut checksum = Adler32::new();
+        checksum.update(body);
+        header[12..].copy_from_slice(&checksum.finish().to_be_bytes());
+        data[..len].copy_from_slice(&forged);
+        // blocks missing after the first refill of the payload don't shift it
+        data.drain(1000 * len..1500 * len);
+
+        let mut verifier = Verifier::new(len, expected()).unwrap();
+        verifier.feed(&data);
+        assert_eq!(
+            verifier.finish(),
+            IntegrityReport {
+                verified_blocks: 1499,
+                corrupted_bytes: len as u64,
+                missing_bytes: 500 * len as u64,
+            }
+        );
+    }
+}
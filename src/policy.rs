@@ -0,0 +1,306 @@
+use std::time::Duration as StdDuration;
+
+use time::Duration;
+
+use crate::{
+    Backend, BasePreference, Direction, EndCondition, NBError, NBytes, NewTestMessage, Pacing,
+    Protocol, SizePreference, TestUpdate, MAX_BUFFER_SIZE,
+};
+
+/// What the server does with a test that exceeds a limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverLimit {
+    /// Run the test with the value lowered to the limit
+    #[default]
+    Clamp,
+    /// Refuse to run the test
+    Reject,
+}
+
+/// Limits the server enforces on submitted tests. A limit that isn't set isn't enforced.
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    pub max_duration: Option<StdDuration>,
+    /// Applies to tests that end after a number of bytes
+    pub max_bytes: Option<u64>,
+    /// In bits per second, unlimited tests are limited to it as well
+    pub max_bitrate: Option<u64>,
+    /// Applies to the send and receive buffers as well as the window size
+    pub max_buffer_size: Option<u64>,
+    /// Directions tests may use, a test in any other direction is rejected
+    pub directions: Option<Vec<Direction>>,
+    pub over_limit: OverLimit,
+}
+
+impl Policy {
+    /// Enforces the limits on `test`. Returns a description of every limit that has been
+    /// applied, or an error if the test has to be rejected.
+    pub(crate) fn apply(&self, test: &mut NewTestMessage) -> Result<Vec<String>, NBError> {
+        let mut applied = Vec::new();
+
+        if let Some(directions) = &self.directions {
+            if !directions.contains(&test.direction) {
+                return Err(NBError::PolicyViolation(format!(
+                    "the direction {:?} isn't allowed",
+                    test.direction
+                )));
+            }
+        }
+
+        match &mut test.end_condition {
+            EndCondition::Time(duration) => {
+                if let Some(max) = self.max_duration {
+                    let max = Duration::try_from(max).unwrap_or(Duration::MAX);
+                    if *duration > max {
+                        applied.push(self.exceeded(
+                            "duration",
+                            format!("{:.0} seconds", max.as_seconds_f64()),
+                        )?);
+                        *duration = max;
+                    }
+                }
+            }
+            EndCondition::Bytes(bytes) => {
+                if let Some(max) = self.max_bytes {
+                    if *bytes > max {
+                        applied.push(self.exceeded("bytes", format_bytes(max))?);
+                        *bytes = max;
+                    }
+                }
+            }
+        }
+
+        self.limit_bitrate(&mut test.bw, &mut applied)?;
+        // the io_uring backend can only pace in the kernel
+        if test.bw != 0
+            && matches!(&test.protocol, Protocol::TCP(info) if info.backend == Backend::Uring)
+        {
+            test.pacing = Pacing::Kernel;
+        }
+
+        if let Protocol::TCP(tcp_test_info) = &mut test.protocol {
+            let socket_options = &mut tcp_test_info.socket_options;
+            let buffers = [
+                ("send buffer size", &mut tcp_test_info.send_buf_size),
+                ("receive buffer size", &mut tcp_test_info.recv_buf_size),
+            ];
+            for (what, size) in buffers {
+                self.limit_buffer_size(what, size, &mut applied)?;
+            }
+            if let Some(window_size) = &mut socket_options.window_size {
+                self.limit_buffer_size("window size", window_size, &mut applied)?;
+            }
+        }
+
+        Ok(applied)
+    }
+
+    /// Enforces the limits on a change to a running test, like `Policy::apply`
+    pub(crate) fn apply_update(&self, update: &mut TestUpdate) -> Result<Vec<String>, NBError> {
+        let mut applied = Vec::new();
+        match update {
+            TestUpdate::Pause | TestUpdate::Resume => {}
+            TestUpdate::Bitrate(bw) => self.limit_bitrate(bw, &mut applied)?,
+            TestUpdate::BufferSize { send, recv } => {
+                let buffers = [("send buffer size", send), ("receive buffer size", recv)];
+                for (what, size) in buffers {
+                    if let Some(size) = size {
+                        self.limit_buffer_size(what, size, &mut applied)?;
+                        check_buffer_size(what, *size)?;
+                    }
+                }
+            }
+        }
+
+        Ok(applied)
+    }
+
+    fn limit_bitrate(&self, bw: &mut u64, applied: &mut Vec<String>) -> Result<(), NBError> {
+        if let Some(max) = self.max_bitrate {
+            // 0 requests an unlimited bitrate
+            if *bw == 0 || *bw > max {
+                let max_display = NBytes::format_bits_per_second(max as f64);
+                applied.push(self.exceeded("bitrate", format!("{max_display}/s"))?);
+                *bw = max;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn limit_buffer_size(
+        &self,
+        what: &str,
+        size: &mut u64,
+        applied: &mut Vec<String>,
+    ) -> Result<(), NBError> {
+        if let Some(max) = self.max_buffer_size {
+            if *size > max {
+                applied.push(self.exceeded(what, format_bytes(max))?);
+                *size = max;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Describes the applied limit, or rejects the test if limits aren't clamped
+    fn exceeded(&self, what: &str, max: String) -> Result<String, NBError> {
+        match self.over_limit {
+            OverLimit::Clamp => Ok(format!("{what} limited to {max}")),
+            OverLimit::Reject => Err(NBError::PolicyViolation(format!(
+                "the {what} exceeds the limit of {max}"
+            ))),
+        }
+    }
+}
+
+/// Rejects buffers the server doesn't allocate for a test, whether or not a policy is set
+pub(crate) fn check_buffer_sizes(test: &NewTestMessage) -> Result<(), NBError> {
+    if let Protocol::TCP(tcp_test_info) = &test.protocol {
+        check_buffer_size("send buffer size", tcp_test_info.send_buf_size)?;
+        check_buffer_size("receive buffer size", tcp_test_info.recv_buf_size)?;
+    }
+
+    Ok(())
+}
+
+fn check_buffer_size(what: &'static str, size: u64) -> Result<(), NBError> {
+    match size {
+        1..=MAX_BUFFER_SIZE => Ok(()),
+        _ => Err(NBError::InvalidBufferSize(what, size)),
+    }
+}
+
+fn format_bytes(n: u64) -> String {
+    NBytes::format(
+        n as f64,
+        BasePreference::default(),
+        SizePreference::Auto,
+        false,
+    )
+    .to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn new_test() -> NewTestMessage {
+        NewTestMessage {
+            direction: Direction::ServerToClient,
+            end_condition: EndCondition::Time(Duration::new(60, 0)),
+            ..NewTestMessage::for_test(0)
+        }
+    }
+
+    #[test]
+    fn test_clamp() {
+        let policy = Policy {
+            max_duration: Some(StdDuration::from_secs(10)),
+            max_bitrate: Some(100_000_000),
+            max_buffer_size: Some(64 * 1024),
+            ..Default::default()
+        };
+
+        let mut test = new_test();
+        let applied = policy.apply(&mut test).unwrap();
+        assert_eq!(applied.len(), 4);
+        assert!(matches!(test.end_condition, EndCondition::Time(d) if d == Duration::new(10, 0)));
+        assert_eq!(test.bw, 100_000_000);
+        let Protocol::TCP(tcp_test_info) = &test.protocol else {
+            unreachable!()
+        };
+        assert_eq!(tcp_test_info.send_buf_size, 64 * 1024);
+        assert_eq!(tcp_test_info.recv_buf_size, 64 * 1024);
+
+        // a test within the limits is left alone
+        assert!(policy.apply(&mut test).unwrap().is_empty());
+
+        // the io_uring backend can't pace the bitrate it got in the application
+        let mut test = new_test();
+        let Protocol::TCP(tcp_test_info) = &mut test.protocol else {
+            unreachable!()
+        };
+        tcp_test_info.backend = Backend::Uring;
+        policy.apply(&mut test).unwrap();
+        assert_eq!(test.pacing, Pacing::Kernel);
+    }
+
+    #[test]
+    fn test_reject() {
+        let policy = Policy {
+            max_duration: Some(StdDuration::from_secs(10)),
+            over_limit: OverLimit::Reject,
+            ..Default::default()
+        };
+        assert!(matches!(
+            policy.apply(&mut new_test()),
+            Err(NBError::PolicyViolation(_))
+        ));
+
+        let policy = Policy {
+            directions: Some(vec![Direction::ClientToServer]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            policy.apply(&mut new_test()),
+            Err(NBError::PolicyViolation(_))
+        ));
+    }
+
+    #[test]
+    fn test_buffer_size_bounds() {
+        let mut test = new_test();
+        assert!(check_buffer_sizes(&test).is_ok());
+
+        let Protocol::TCP(tcp_test_info) = &mut test.protocol else {
+            unreachable!()
+        };
+        tcp_test_info.recv_buf_size = 0;
+        assert!(matches!(
+            check_buffer_sizes(&test),
+            Err(NBError::InvalidBufferSize("receive buffer size", 0))
+        ));
+
+        let Protocol::TCP(tcp_test_info) = &mut test.protocol else {
+            unreachable!()
+        };
+        tcp_test_info.recv_buf_size = 1;
+        tcp_test_info.send_buf_size = MAX_BUFFER_SIZE + 1;
+        assert!(matches!(
+            check_buffer_sizes(&test),
+            Err(NBError::InvalidBufferSize("send buffer size", _))
+        ));
+    }
+
+    #[test]
+    fn test_update() {
+        let mut policy = Policy {
+            max_bitrate: Some(100_000_000),
+            ..Default::default()
+        };
+
+        let mut update = TestUpdate::Bitrate(0);
+        assert_eq!(policy.apply_update(&mut update).unwrap().len(), 1);
+        assert_eq!(update, TestUpdate::Bitrate(100_000_000));
+        let mut update = TestUpdate::Pause;
+        assert!(policy.apply_update(&mut update).unwrap().is_empty());
+
+        policy.over_limit = OverLimit::Reject;
+        assert!(matches!(
+            policy.apply_update(&mut TestUpdate::Bitrate(200_000_000)),
+            Err(NBError::PolicyViolation(_))
+        ));
+
+        // buffer sizes are bounded without a limit in the policy as well
+        let mut update = TestUpdate::BufferSize {
+            send: None,
+            recv: Some(0),
+        };
+        assert!(matches!(
+            policy.apply_update(&mut update),
+            Err(NBError::InvalidBufferSize("receive buffer size", 0))
+        ));
+    }
+}
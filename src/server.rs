@@ -1,36 +1,292 @@
+use std::io;
 use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
 use std::sync::Arc;
+use std::time::Duration as StdDuration;
 
 use anyhow::Result;
 use parking_lot::Mutex;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
 use tracing::{debug, trace, warn};
 
 use crate::{
-    tcp_test::TCPTest, MessageType, NewTestMessage, Protocol, TestAssociationMessage,
-    CONTROL_MSG_SIZE,
+    admin::{self, AdminSocket, TestInfo, TestState, TestStats},
+    admission::{Admission, TestSlot},
+    auth::{AuthChallengeMessage, AuthResponseMessage},
+    hello::{Capabilities, HelloMessage},
+    policy::{self, Policy},
+    sockopt,
+    tcp_test::{Reconnect, TCPTest},
+    CancelTestMessage, EndCondition, ErrorMessage, MessageID, MessageType, NBError, NewTestMessage,
+    Protocol, TestAcceptedMessage, TestAssociationMessage, TestQueuedMessage, TestResultsMessage,
+    TestUpdatedMessage, UpdateTestMessage, MESSAGE_READ_TIMEOUT,
 };
-use crate::{test_manager, Role, ServerConfig};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc;
+use crate::{
+    test_manager::{self, Test, TestMonitor},
+    Role, ServerConfig,
+};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{sleep_until, Instant, MissedTickBehavior};
+
+/// A test that has been admitted but whose data connection hasn't been associated yet
+#[derive(Debug)]
+struct OutstandingTest {
+    message: NewTestMessage,
+    client: SocketAddr,
+    /// Keeps the admission slot taken until the test is done
+    slot: TestSlot,
+    control: ControlSender,
+    /// The client exchanges heartbeats over its control connection
+    heartbeats: bool,
+    /// The test is dropped if its data connection hasn't been associated by then
+    deadline: Instant,
+}
+
+/// A test whose data connection has been associated
+#[derive(Debug)]
+struct RunningTest {
+    id: u64,
+    client: SocketAddr,
+    message: NewTestMessage,
+    started: Instant,
+    monitor: Arc<TestMonitor>,
+    /// Tells the client's control connection when the server cancels the test
+    control: ControlSender,
+    /// The client exchanges heartbeats over its control connection
+    heartbeats: bool,
+    /// Passes new data connections to a resilient test
+    connections: Option<mpsc::UnboundedSender<TcpStream>>,
+}
+
+/// What the tests of a client tell its control connection
+#[derive(Debug)]
+enum ToClient {
+    /// The server cancelled the test with this code
+    Cancelled([u8; 32]),
+    /// The test is done, only passed on if the client supports it
+    Results(Box<TestResultsMessage>),
+}
+
+/// Passes the messages of the tests to the control connection of their client
+type ControlSender = mpsc::UnboundedSender<ToClient>;
+
+/// The tests of a server, shared by its connections
+#[derive(Debug)]
+struct ServerState {
+    outstanding_tests: Mutex<Vec<OutstandingTest>>,
+    running_tests: Mutex<Vec<RunningTest>>,
+    admission: Arc<Admission>,
+}
+
+impl ServerState {
+    fn list_tests(&self) -> Vec<TestInfo> {
+        let mut tests: Vec<_> = self
+            .outstanding_tests
+            .lock()
+            .iter()
+            .map(|test| {
+                test_info(
+                    test.slot.id(),
+                    test.client,
+                    &test.message,
+                    TestState::Outstanding,
+                    None,
+                )
+            })
+            .collect();
+        tests.extend(self.running_tests.lock().iter().map(RunningTest::info));
+        tests.sort_by_key(|test| test.id);
+        tests
+    }
+
+    fn stats(&self, id: u64) -> Option<TestStats> {
+        let running_tests = self.running_tests.lock();
+        let test = running_tests.iter().find(|test| test.id == id)?;
+        Some(TestStats {
+            test: test.info(),
+            summary: test.monitor.summary(),
+        })
+    }
+
+    /// Cancels a running test or drops an outstanding one. Returns false if there's no
+    /// test with the id.
+    fn cancel(&self, id: u64) -> bool {
+        !self
+            .cancel_matching(|test_id, _| test_id == id, true)
+            .is_empty()
+    }
+
+    /// Cancels all tests, returns how many there were
+    fn cancel_all(&self) -> usize {
+        self.cancel_matching(|_, _| true, true).len()
+    }
+
+    /// Cancels the running tests and drops the outstanding ones `matches` is true for. The
+    /// clients of running tests are told if `notify` is set. Returns the ids of the tests.
+    fn cancel_matching(
+        &self,
+        matches: impl Fn(u64, &NewTestMessage) -> bool,
+        notify: bool,
+    ) -> Vec<u64> {
+        let mut cancelled = Vec::new();
+        for test in self.running_tests.lock().iter() {
+            if matches(test.id, &test.message) {
+                test.monitor.cancel();
+                if notify {
+                    // the control connection may be gone already
+                    let _ = test.control.send(ToClient::Cancelled(test.message.code));
+                }
+                cancelled.push(test.id);
+            }
+        }
+
+        self.outstanding_tests.lock().retain(|test| {
+            let matched = matches(test.slot.id(), &test.message);
+            if matched {
+                cancelled.push(test.slot.id());
+            }
+            !matched
+        });
+        cancelled
+    }
+
+    /// Drops the outstanding tests whose data connection didn't arrive by their deadline,
+    /// freeing their slots, and tells their clients. Returns their ids.
+    fn expire_outstanding(&self, now: Instant) -> Vec<u64> {
+        let mut expired = Vec::new();
+        self.outstanding_tests.lock().retain(|test| {
+            if test.deadline > now {
+                return true;
+            }
+            // the control connection may be gone already
+            let _ = test.control.send(ToClient::Cancelled(test.message.code));
+            expired.push(test.slot.id());
+            false
+        });
+        expired
+    }
+
+    /// Cancels the running tests with one of `codes` because their client hasn't been
+    /// heard from since `last_heard`, returns their ids
+    fn lose_peer(&self, codes: &[[u8; 32]], last_heard: Instant) -> Vec<u64> {
+        let running_tests = self.running_tests.lock();
+        running_tests
+            .iter()
+            .filter(|test| codes.contains(&test.message.code))
+            .map(|test| {
+                test.monitor.lose_peer(last_heard);
+                test.id
+            })
+            .collect()
+    }
+
+    /// Applies a change to the running test with the code of `message`, returns the id of
+    /// the test and the change as it has been applied
+    fn update(
+        &self,
+        message: UpdateTestMessage,
+        policy: &Policy,
+    ) -> Result<(u64, TestUpdatedMessage), NBError> {
+        let mut running_tests = self.running_tests.lock();
+        let test = running_tests
+            .iter_mut()
+            .find(|test| test.message.code == message.code)
+            .ok_or(NBError::TestNotRunning)?;
+
+        let mut update = message.update;
+        let limits = policy.apply_update(&mut update)?;
+        test.message.apply(update)?;
+        test.monitor.update(update);
+
+        Ok((test.id, TestUpdatedMessage { update, limits }))
+    }
+}
+
+impl RunningTest {
+    fn info(&self) -> TestInfo {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        test_info(
+            self.id,
+            self.client,
+            &self.message,
+            TestState::Running,
+            Some(elapsed),
+        )
+    }
+}
+
+fn test_info(
+    id: u64,
+    client: SocketAddr,
+    message: &NewTestMessage,
+    state: TestState,
+    elapsed: Option<f64>,
+) -> TestInfo {
+    TestInfo {
+        id,
+        client,
+        state,
+        protocol: message.protocol.to_string(),
+        direction: message.direction,
+        bitrate: message.bw,
+        duration: match message.end_condition {
+            EndCondition::Time(duration) => Some(duration.as_seconds_f64()),
+            EndCondition::Bytes(_) => None,
+        },
+        elapsed,
+    }
+}
+
+/// How often a queued client is told its position, even if it didn't change
+const QUEUE_UPDATE_INTERVAL: StdDuration = StdDuration::from_secs(5);
+/// A control connection without a test that sends nothing for this long is closed
+const CONTROL_IDLE_TIMEOUT: StdDuration = StdDuration::from_secs(60);
+/// Most tests admitted at the same time that don't have a data connection yet
+const MAX_OUTSTANDING_TESTS: usize = 64;
+/// How long an admitted test waits for its data connection before it's dropped
+const ASSOCIATION_TIMEOUT: StdDuration = StdDuration::from_secs(10);
 
 struct ConnectedClient {
     socket: TcpStream,
     addr: SocketAddr,
-    outstanding_tests: Arc<Mutex<Vec<NewTestMessage>>>,
+    state: Arc<ServerState>,
+    config: Arc<ServerConfig>,
+    /// Codes of the tests submitted over this connection
+    submitted: Vec<[u8; 32]>,
+    /// The challenge the client has to answer, if authentication is required
+    challenge: Option<AuthChallengeMessage>,
+    authenticated: bool,
+    /// The client sends heartbeats and expects them, see `HeartbeatConfig`
+    heartbeats: bool,
+    /// The client accepts `TestResultsMessage`s
+    test_results: bool,
+    /// Messages of the client's tests, see `ToClient`
+    control_tx: ControlSender,
+    control_rx: mpsc::UnboundedReceiver<ToClient>,
 }
 
 impl ConnectedClient {
     pub(crate) async fn init(
         socket: TcpStream,
         addr: SocketAddr,
-        outstanding_tests: Arc<Mutex<Vec<NewTestMessage>>>,
+        state: Arc<ServerState>,
+        config: Arc<ServerConfig>,
     ) {
         debug!("Handling new client from {:?}", addr);
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
         ConnectedClient {
             socket,
             addr,
-            outstanding_tests,
+            state,
+            authenticated: config.keys.is_none(),
+            config,
+            submitted: Vec::new(),
+            challenge: None,
+            heartbeats: false,
+            test_results: false,
+            control_tx,
+            control_rx,
         }
         .msg_loop()
         .await;
@@ -38,70 +294,565 @@ impl ConnectedClient {
     fn get_test_message(
         &mut self,
         test_association_msg: &TestAssociationMessage,
-    ) -> Option<NewTestMessage> {
-        let mut outstanding_tests = self.outstanding_tests.lock();
+    ) -> Option<OutstandingTest> {
+        let mut outstanding_tests = self.state.outstanding_tests.lock();
         outstanding_tests
             .iter()
-            .position(|x| *x == *test_association_msg)
+            .position(|x| x.message == *test_association_msg)
             .map(|index| outstanding_tests.swap_remove(index))
     }
 
+    /// Drops the tests of this client that never got a data connection, freeing their slots
+    fn drop_unassociated_tests(&mut self) {
+        if self.submitted.is_empty() {
+            return;
+        }
+
+        let mut outstanding_tests = self.state.outstanding_tests.lock();
+        let len = outstanding_tests.len();
+        outstanding_tests.retain(|test| {
+            !self
+                .submitted
+                .iter()
+                .any(|code| test.message == TestAssociationMessage { code: *code })
+        });
+        if outstanding_tests.len() != len {
+            debug!(
+                "dropped {} unassociated tests of {}",
+                len - outstanding_tests.len(),
+                self.addr
+            );
+        }
+    }
+
     async fn msg_loop(mut self) {
-        // read control information:
-        let msg_type = ConnectedClient::read_control_info(&mut self.socket)
-            .await
-            .unwrap();
-        match msg_type {
-            MessageType::NewTest(len) => {
-                println!("{}\nNew test submitted from {}", SEPARATOR, self.addr);
-
-                trace!("reading NewTestMessage with len {len}");
-                let mut buf = vec![0; len];
-                self.socket.read_exact(buf.as_mut_slice()).await.unwrap();
-                let message: NewTestMessage = serde_json::from_slice(buf.as_slice()).unwrap();
-                trace!("read NewTestMessage {message:?}");
-                let mut outstanding_tests = self.outstanding_tests.lock();
-                outstanding_tests.push(message);
-            }
-
-            MessageType::TestAssociation(len) => {
-                trace!("reading TestAssociationMessage");
-                let mut buf = vec![0; len];
-                self.socket.read_exact(buf.as_mut_slice()).await.unwrap();
-                let message: TestAssociationMessage =
-                    serde_json::from_slice(buf.as_slice()).unwrap();
-                trace!("read TestAssociationMessage {message:?}");
-
-                if let Some(test_message) = self.get_test_message(&message) {
-                    trace!("found associated TestMessage {test_message:?}");
-
-                    match test_message.protocol {
-                        Protocol::TCP(_) => {
-                            let test = TCPTest::new(test_message, Role::Server, self.socket);
-                            test_manager::run(test, Role::Server).await;
+        let res = self.handle_messages().await;
+        self.drop_unassociated_tests();
+        match res {
+            Ok(Some(msg_type)) => self.run_test(msg_type).await,
+            Ok(None) => (),
+            Err(e) => self.close_with_error(e).await,
+        }
+    }
+
+    /// Handles the messages of the connection until it's closed or turns out to be a data
+    /// connection, in which case the association message is returned.
+    async fn handle_messages(&mut self) -> Result<Option<MessageType>, NBError> {
+        // a connection that never sends anything is closed after the read timeout
+        let mut timeout = Some(MESSAGE_READ_TIMEOUT);
+        let mut hello_received = false;
+        let heartbeat = self.config.common.heartbeat;
+        let mut ticker = tokio::time::interval(heartbeat.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut last_heard = Instant::now();
+        loop {
+            // once the first message showed that this is a control connection, the client is
+            // also told about the tests the server cancels and their results
+            if timeout.is_none() {
+                let mut buf = [0; 1];
+                tokio::select! {
+                    // a message that arrived is read before the client is given up on
+                    biased;
+                    _ = self.socket.peek(&mut buf) => {}
+                    Some(message) = self.control_rx.recv() => {
+                        match message {
+                            ToClient::Cancelled(code) => {
+                                let message = CancelTestMessage { code };
+                                let id = MessageID::CANCEL_TEST_MESSAGE;
+                                crate::send_message(message, id, &mut self.socket).await?;
+                            }
+                            ToClient::Results(results) if self.test_results => {
+                                let id = MessageID::TEST_RESULTS_MESSAGE;
+                                crate::send_message(*results, id, &mut self.socket).await?;
+                            }
+                            ToClient::Results(_) => {}
                         }
-                        _ => todo!(),
+                        continue;
+                    }
+                    _ = ticker.tick(), if self.heartbeats => {
+                        self.send_heartbeat().await?;
+                        continue;
                     }
-                } else {
-                    warn!("Code of message doesn't exist");
-                    self.socket.shutdown().await.expect("shutdown failed");
+                    _ = sleep_until(last_heard + heartbeat.timeout), if self.heartbeats => {
+                        self.lose_client(last_heard);
+                        return Ok(None);
+                    }
+                    _ = sleep_until(last_heard + CONTROL_IDLE_TIMEOUT), if !self.has_tests() => {
+                        debug!("closing the idle control connection to {}", self.addr);
+                        return Ok(None);
+                    }
+                }
+            }
+
+            let msg_type = match crate::read_message_type(&mut self.socket, timeout).await {
+                Ok(msg_type) => msg_type,
+                Err(NBError::ConnectionClosed) => {
+                    debug!("control connection to {} closed", self.addr);
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+            };
+            timeout = None;
+
+            let res = match msg_type {
+                MessageType::Hello(_) if !hello_received => {
+                    hello_received = true;
+                    self.hello(msg_type).await
+                }
+                // data connections start with the association, without a hello
+                MessageType::NewTest(_) if !hello_received => Err(NBError::MissingHello),
+                MessageType::AuthResponse(_) if self.challenge.is_some() => {
+                    self.authenticate(msg_type).await
+                }
+                // only authenticated clients may submit tests
+                MessageType::NewTest(_) if !self.authenticated => {
+                    Err(NBError::AuthenticationRequired)
+                }
+                MessageType::NewTest(_) => self.read_new_test(msg_type).await,
+                MessageType::Heartbeat(_) if hello_received => {
+                    crate::read_message::<(), _>(&mut self.socket, msg_type).await
+                }
+                MessageType::UpdateTest(_) if self.authenticated => {
+                    self.update_test(msg_type).await
+                }
+                MessageType::CancelTest(_) if self.authenticated => {
+                    self.cancel_test(msg_type).await
+                }
+                MessageType::TestAssociation(_) => {
+                    // the connection is used as a data connection
+                    return Ok(Some(msg_type));
+                }
+                MessageType::Close(_) => {
+                    debug!("{} closed the control connection", self.addr);
+                    return Ok(None);
+                }
+                MessageType::CancelTest(_)
+                | MessageType::MsgError(_)
+                | MessageType::Hello(_)
+                | MessageType::AuthChallenge(_)
+                | MessageType::AuthResponse(_)
+                | MessageType::AuthAccepted(_)
+                | MessageType::TestAccepted(_)
+                | MessageType::TestQueued(_)
+                | MessageType::AdminRequest(_)
+                | MessageType::AdminResponse(_)
+                | MessageType::UpdateTest(_)
+                | MessageType::TestUpdated(_)
+                | MessageType::Heartbeat(_)
+                | MessageType::TestResults(_) => Err(NBError::UnexpectedMessage(msg_type.name())),
+            };
+
+            res?;
+            // also covers the time a test spent in the queue, during which nothing is read
+            last_heard = Instant::now();
+        }
+    }
+
+    /// Checks the client's hello and answers with the server's
+    async fn hello(&mut self, msg_type: MessageType) -> Result<(), NBError> {
+        let hello: HelloMessage = crate::read_message(&mut self.socket, msg_type).await?;
+        debug!(
+            "{} runs netbench {} (protocol version {})",
+            self.addr, hello.software_version, hello.protocol_version
+        );
+        hello.check_compatible()?;
+        let supports = |name| {
+            hello
+                .capabilities
+                .options
+                .iter()
+                .any(|option| option == name)
+        };
+        self.heartbeats = supports("heartbeats");
+        self.test_results = supports("testResults");
+
+        let server_hello = HelloMessage {
+            auth_required: self.config.keys.is_some(),
+            ..HelloMessage::new()
+        };
+        crate::send_message(server_hello, MessageID::HELLO_MESSAGE, &mut self.socket).await?;
+
+        if self.config.keys.is_some() {
+            let challenge = AuthChallengeMessage::new();
+            crate::send_message(
+                &challenge,
+                MessageID::AUTH_CHALLENGE_MESSAGE,
+                &mut self.socket,
+            )
+            .await?;
+            self.challenge = Some(challenge);
+        }
+
+        Ok(())
+    }
+
+    /// Checks the client's answer to the challenge. Every challenge can only be answered once.
+    async fn authenticate(&mut self, msg_type: MessageType) -> Result<(), NBError> {
+        let response: AuthResponseMessage = crate::read_message(&mut self.socket, msg_type).await?;
+        let (Some(challenge), Some(keys)) = (self.challenge.take(), &self.config.keys) else {
+            return Err(NBError::UnexpectedMessage(msg_type.name()));
+        };
+
+        if !keys.verify(&challenge, &response) {
+            warn!(
+                "{} failed to authenticate as user {}",
+                self.addr, response.user
+            );
+            return Err(NBError::AuthenticationFailed);
+        }
+
+        debug!("{} authenticated as user {}", self.addr, response.user);
+        self.authenticated = true;
+        crate::send_message((), MessageID::AUTH_ACCEPTED_MESSAGE, &mut self.socket).await
+    }
+
+    async fn read_new_test(&mut self, msg_type: MessageType) -> Result<(), NBError> {
+        trace!("reading NewTestMessage with len {}", msg_type.len());
+        let mut message: NewTestMessage = crate::read_message(&mut self.socket, msg_type).await?;
+        trace!("read NewTestMessage {message:?}");
+        // the connection stays usable, the client may have other tests on it
+        let limits = match self.check_new_test(&mut message) {
+            Ok(limits) => limits,
+            Err(e) => {
+                warn!("rejected test from {}: {e}", self.addr);
+                return self.send_error(e).await;
+            }
+        };
+        if self.state.outstanding_tests.lock().len() >= MAX_OUTSTANDING_TESTS {
+            warn!(
+                "rejected test from {}: too many tests without a data connection",
+                self.addr
+            );
+            return self.send_error(NBError::ServerBusy(1)).await;
+        }
+        let slot = match self.admit(&message).await {
+            Ok(slot) => slot,
+            // the client may submit the test again over the same connection
+            Err(e @ NBError::ServerBusy(_)) => return self.send_error(e).await,
+            Err(e) => return Err(e),
+        };
+
+        println!(
+            "{}\nNew test {} submitted from {}",
+            SEPARATOR,
+            slot.id(),
+            self.addr
+        );
+        for limit in &limits {
+            println!("Server policy: {limit}");
+        }
+        self.submitted.push(message.code);
+        self.state.outstanding_tests.lock().push(OutstandingTest {
+            message: message.clone(),
+            client: self.addr,
+            slot,
+            control: self.control_tx.clone(),
+            heartbeats: self.heartbeats,
+            deadline: Instant::now() + ASSOCIATION_TIMEOUT,
+        });
+
+        let accepted = TestAcceptedMessage {
+            test: message,
+            limits,
+        };
+        crate::send_message(accepted, MessageID::TEST_ACCEPTED_MESSAGE, &mut self.socket).await
+    }
+
+    /// Changes a running test of this client. A change that can't be applied is reported
+    /// to the client without closing the connection.
+    async fn update_test(&mut self, msg_type: MessageType) -> Result<(), NBError> {
+        let message: UpdateTestMessage = crate::read_message(&mut self.socket, msg_type).await?;
+        if !self.submitted.contains(&message.code) {
+            return self.send_error(NBError::TestNotRunning).await;
+        }
+
+        let update = message.update;
+        match self.state.update(message, &self.config.policy) {
+            Ok((id, updated)) => {
+                println!("Test {id}: {}", updated.update);
+                for limit in &updated.limits {
+                    println!("Server policy: {limit}");
                 }
+                crate::send_message(updated, MessageID::TEST_UPDATED_MESSAGE, &mut self.socket)
+                    .await
+            }
+            Err(e) => {
+                warn!("can't apply {update:?} for {}: {e}", self.addr);
+                self.send_error(e).await
+            }
+        }
+    }
+
+    /// Cancels a test of this client on its request, without answering
+    async fn cancel_test(&mut self, msg_type: MessageType) -> Result<(), NBError> {
+        let message: CancelTestMessage = crate::read_message(&mut self.socket, msg_type).await?;
+        if !self.submitted.contains(&message.code) {
+            debug!("{} cancelled a test that isn't its own", self.addr);
+            return Ok(());
+        }
+
+        let cancelled = self
+            .state
+            .cancel_matching(|_, test| test.code == message.code, false);
+        for id in cancelled {
+            println!("Test {id} cancelled by the client");
+        }
+        Ok(())
+    }
+
+    /// Whether a test submitted over this connection is outstanding or running
+    fn has_tests(&self) -> bool {
+        let submitted = |code: &[u8; 32]| self.submitted.contains(code);
+        self.state
+            .outstanding_tests
+            .lock()
+            .iter()
+            .any(|test| submitted(&test.message.code))
+            || self
+                .state
+                .running_tests
+                .lock()
+                .iter()
+                .any(|test| submitted(&test.message.code))
+    }
+
+    async fn send_heartbeat(&mut self) -> Result<(), NBError> {
+        crate::send_message((), MessageID::HEARTBEAT_MESSAGE, &mut self.socket).await
+    }
+
+    /// Ends the tests of a client that hasn't been heard from since `last_heard`
+    fn lose_client(&self, last_heard: Instant) {
+        warn!(
+            "nothing heard from {} for {:?}, closing its control connection",
+            self.addr,
+            last_heard.elapsed()
+        );
+        for id in self.state.lose_peer(&self.submitted, last_heard) {
+            println!("Test {id}: lost the client");
+        }
+    }
+
+    /// Checks whether `test` can run on this server and applies the server policy to it.
+    /// Returns the limits that lowered some of its values.
+    fn check_new_test(&self, test: &mut NewTestMessage) -> Result<Vec<String>, NBError> {
+        let missing = Capabilities::local().missing(test);
+        if !missing.is_empty() {
+            return Err(NBError::MissingCapabilities(missing.join(", ")));
+        }
+        test.check_supported()?;
+        let limits = self.config.policy.apply(test)?;
+        policy::check_buffer_sizes(test)?;
+        if let Some(e) = unavailable_congestion(test) {
+            return Err(e);
+        }
+
+        Ok(limits)
+    }
+
+    async fn send_error(&mut self, error: NBError) -> Result<(), NBError> {
+        let message = ErrorMessage::from(&error);
+        crate::send_message(message, MessageID::MSG_ERROR_MESSAGE, &mut self.socket).await
+    }
+
+    /// Takes a slot for `test`. If the server is busy the test is rejected, or queued with
+    /// the client being told its position until a slot is free.
+    async fn admit(&mut self, test: &NewTestMessage) -> Result<TestSlot, NBError> {
+        if self.state.admission.is_draining() {
+            return Err(NBError::ShuttingDown);
+        }
+        let duration = match test.end_condition {
+            EndCondition::Time(duration) => duration.try_into().unwrap_or_default(),
+            EndCondition::Bytes(_) => StdDuration::ZERO,
+        };
+        let retry_after = match self.state.admission.try_admit(duration) {
+            Ok(slot) => return Ok(slot),
+            Err(retry_after) => retry_after,
+        };
+        if !self.config.queue_tests {
+            warn!("rejected test from {}: the server is busy", self.addr);
+            return Err(NBError::ServerBusy(retry_after));
+        }
+
+        let mut ticket = self.state.admission.enqueue();
+        let mut reported = None;
+        let deadline = Instant::now() + self.config.max_queue_wait;
+        // the timers persist across the loop, recreating them would let the shorter one
+        // always win
+        let mut update = tokio::time::interval_at(
+            Instant::now() + QUEUE_UPDATE_INTERVAL,
+            QUEUE_UPDATE_INTERVAL,
+        );
+        let mut ticker = tokio::time::interval(self.config.common.heartbeat.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            if self.state.admission.is_draining() {
+                return Err(NBError::ShuttingDown);
+            }
+            let position = match ticket.try_admit(duration) {
+                Ok(slot) => return Ok(slot),
+                Err(position) => position,
+            };
+            if reported != Some(position) {
+                debug!("test from {} queued at position {position}", self.addr);
+                let queued = TestQueuedMessage { position };
+                crate::send_message(queued, MessageID::TEST_QUEUED_MESSAGE, &mut self.socket)
+                    .await?;
+                reported = Some(position);
+            }
+
+            tokio::select! {
+                _ = ticket.changed() => {}
+                _ = update.tick() => reported = None,
+                _ = ticker.tick(), if self.heartbeats => self.send_heartbeat().await?,
+                _ = sleep_until(deadline) => {
+                    warn!("rejected test from {}: queued for too long", self.addr);
+                    // dropping the ticket leaves the queue
+                    let retry_after = self.state.admission.estimate_retry_after();
+                    return Err(NBError::ServerBusy(retry_after));
+                }
+            }
+        }
+    }
+
+    async fn run_test(mut self, msg_type: MessageType) {
+        trace!("reading TestAssociationMessage");
+        let message: TestAssociationMessage =
+            match crate::read_message(&mut self.socket, msg_type).await {
+                Ok(message) => message,
+                Err(e) => return self.close_with_error(e).await,
+            };
+        trace!("read TestAssociationMessage {message:?}");
+
+        let Some(OutstandingTest {
+            message: test_message,
+            client,
+            slot,
+            control,
+            heartbeats,
+            ..
+        }) = self.get_test_message(&message)
+        else {
+            return self.reassociate(&message).await;
+        };
+        trace!("found associated TestMessage {test_message:?}");
+
+        match &test_message.protocol {
+            Protocol::TCP(_) => {
+                self.apply_data_options(&test_message, heartbeats);
+                let id = slot.id();
+                let monitor = Arc::new(TestMonitor::default());
+                let (connections, reconnect) = mpsc::unbounded_channel();
+                self.state.running_tests.lock().push(RunningTest {
+                    id,
+                    client,
+                    message: test_message.clone(),
+                    started: Instant::now(),
+                    monitor: monitor.clone(),
+                    control: control.clone(),
+                    heartbeats,
+                    connections: test_message.resilient.then_some(connections),
+                });
+
+                let resilient = test_message.resilient;
+                let mut test =
+                    TCPTest::new(test_message, Role::Server, self.socket, &self.config.common);
+                if resilient {
+                    test = test.with_reconnect(Reconnect::Accept(reconnect));
+                }
+                let code = message.code;
+                let effective_options = test.effective_options().cloned();
+                let summary = test_manager::run(test, Role::Server, &monitor).await;
+                self.state.running_tests.lock().retain(|test| test.id != id);
+                // the control connection may be gone already
+                let results = TestResultsMessage {
+                    code,
+                    summary,
+                    effective_options,
+                };
+                let _ = control.send(ToClient::Results(Box::new(results)));
+                slot.finish();
             }
             _ => {
-                todo!()
+                self.close_with_error(NBError::Unsupported("only TCP tests are implemented"))
+                    .await
             }
         }
     }
 
-    async fn read_control_info(socket: &mut TcpStream) -> Result<MessageType> {
-        let mut buf = [0; CONTROL_MSG_SIZE];
-        socket.read_exact(&mut buf).await?;
+    /// Hands the connection to the running resilient test it's associated with, which
+    /// lost its data connection
+    async fn reassociate(mut self, message: &TestAssociationMessage) {
+        let test = self
+            .state
+            .running_tests
+            .lock()
+            .iter()
+            .find(|test| test.message == *message)
+            .map(|test| {
+                let connections = test.connections.clone();
+                let client = test.client.ip();
+                (
+                    test.id,
+                    client,
+                    test.message.clone(),
+                    test.heartbeats,
+                    connections,
+                )
+            });
+        let (id, test_message, heartbeats, connections) = match test {
+            Some((id, client, ..)) if client != self.addr.ip() => {
+                warn!(
+                    "{} tried to replace the data connection of test {id} from {client}",
+                    self.addr
+                );
+                let _ = self.socket.shutdown().await;
+                return;
+            }
+            Some((id, _, test_message, heartbeats, Some(connections))) => {
+                (id, test_message, heartbeats, connections)
+            }
+            Some((id, ..)) => {
+                warn!("test {id} isn't resilient, its data connection can't be replaced");
+                let _ = self.socket.shutdown().await;
+                return;
+            }
+            None => {
+                warn!("Code of message doesn't exist");
+                let _ = self.socket.shutdown().await;
+                return;
+            }
+        };
+
+        self.apply_data_options(&test_message, heartbeats);
+        println!("Test {id}: the client connected again");
+        // fails if the test ended in the meantime
+        let _ = connections.send(self.socket);
+    }
+
+    /// Applies the socket options of `test` to the data connection. The user timeout is
+    /// only set if the client exchanges `heartbeats`, like the client does.
+    ///
+    /// Data connections are accepted on the listener shared with the control connections,
+    /// so the options are only set after the handshake. The window scale and MSS the server
+    /// announced aren't affected by them, the client reports what took effect.
+    fn apply_data_options(&self, test: &NewTestMessage, heartbeats: bool) {
+        let fd = self.socket.as_raw_fd();
+        if let Protocol::TCP(tcp_test_info) = &test.protocol {
+            if let Err(e) = sockopt::apply_tcp_options(fd, &tcp_test_info.socket_options) {
+                warn!("failed to apply socket options: {e}");
+            }
+        }
+        if heartbeats {
+            if let Err(e) = sockopt::set_user_timeout(fd, self.config.common.heartbeat.timeout) {
+                warn!("failed to set TCP_USER_TIMEOUT: {e}");
+            }
+        }
+    }
 
-        let msg_type = u16::from_be_bytes(buf[..2].try_into()?);
-        let len = u32::from_be_bytes(buf[2..6].try_into()?);
-        trace!("read control info: msg: {msg_type}, len: {len}");
-        let msg_type = MessageType::new(msg_type, len as usize)?;
-        Ok(msg_type)
+    /// Tells the peer why its connection is closed and closes it
+    async fn close_with_error(self, error: NBError) {
+        warn!("closing connection to {}: {error}", self.addr);
+        reject(self.socket, error).await;
     }
 }
 
@@ -109,43 +860,150 @@ impl ConnectedClient {
 pub struct Server {
     listener: TcpListener,
     com_rx: mpsc::Receiver<ControlMessage>,
-    outstanding_tests: Arc<Mutex<Vec<NewTestMessage>>>,
+    /// Handed to admin connections, which pass their requests on as control messages
+    com_tx: mpsc::Sender<ControlMessage>,
+    admin: Option<AdminSocket>,
+    state: Arc<ServerState>,
+    config: Arc<ServerConfig>,
 }
 
 const SEPARATOR: &str = "-----------------------";
 
 impl Server {
-    pub async fn new(config: ServerConfig) -> Result<(Self, mpsc::Sender<ControlMessage>)> {
+    pub fn new(config: ServerConfig) -> Result<(Self, mpsc::Sender<ControlMessage>)> {
+        let socket = if config.addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        // a restarted server shouldn't have to wait for the connections of the old one
+        socket.set_reuseaddr(true)?;
+        socket.bind(config.addr)?;
+        Server::from_listener(config, socket.listen(1024)?)
+    }
+
+    /// Creates a server accepting connections on an already bound `listener`, e.g. one
+    /// passed in by systemd. `config.addr` is ignored.
+    pub fn with_listener(
+        config: ServerConfig,
+        listener: std::net::TcpListener,
+    ) -> Result<(Self, mpsc::Sender<ControlMessage>)> {
+        listener.set_nonblocking(true)?;
+        Server::from_listener(config, TcpListener::from_std(listener)?)
+    }
+
+    fn from_listener(
+        config: ServerConfig,
+        listener: TcpListener,
+    ) -> Result<(Self, mpsc::Sender<ControlMessage>)> {
         let (com_tx, com_rx) = mpsc::channel(10);
-        let listener = TcpListener::bind(&config.addr).await?;
-        debug!("Server listening on {}", config.addr);
-        println!("Server ready to accept connections on {}", config.addr);
+        let addr = listener.local_addr()?;
+        let admin = config
+            .admin_socket
+            .as_deref()
+            .map(AdminSocket::bind)
+            .transpose()?;
+        debug!("Server listening on {addr}");
+        println!("Server ready to accept connections on {addr}");
         Ok((
             Server {
                 listener,
                 com_rx,
-                outstanding_tests: Arc::new(Mutex::new(Vec::new())),
+                com_tx: com_tx.clone(),
+                admin,
+                state: Arc::new(ServerState {
+                    outstanding_tests: Mutex::new(Vec::new()),
+                    running_tests: Mutex::new(Vec::new()),
+                    admission: Admission::new(config.max_tests),
+                }),
+                config: Arc::new(config),
             },
             com_tx,
         ))
     }
 
+    /// Accepts clients until the server is stopped through `ControlMessage::Stop`, has been
+    /// drained, ran its one test in one-off mode, or has been idle for the idle timeout.
     pub async fn accept(&mut self) -> Result<()> {
+        let admission = self.state.admission.clone();
+        let mut admission_changed = admission.subscribe();
+        let mut idle_since = Instant::now();
+        let mut expiry = tokio::time::interval(StdDuration::from_secs(1));
+        expiry.set_missed_tick_behavior(MissedTickBehavior::Delay);
         loop {
+            if admission.is_draining() && admission.is_idle() {
+                println!("All tests are done, shutting down");
+                break;
+            }
+
+            let idle_deadline = self.config.idle_timeout.map(|timeout| idle_since + timeout);
             tokio::select! {
                 Ok((socket, addr)) = self.listener.accept() => {
-                    let outstanding_tests = self.outstanding_tests.clone();
+                    idle_since = Instant::now();
+                    if !self.config.access.is_allowed(addr.ip()) {
+                        warn!("rejected connection from {addr}: not allowed by the access list");
+                        tokio::spawn(reject(socket, NBError::AccessDenied(addr.ip())));
+                        continue;
+                    }
+
+                    let state = self.state.clone();
+                    let config = self.config.clone();
                     tokio::spawn(async move {
                         debug!("New connection from {addr}");
-                        ConnectedClient::init(socket, addr, outstanding_tests).await;
+                        ConnectedClient::init(socket, addr, state, config).await;
                     });
                 }
-                msg = self.com_rx.recv() => {
-                    // Currently there's only the stop message, that's why this is okay
-                    if msg.is_some() {
+                Ok(stream) = accept_admin(self.admin.as_ref()) => {
+                    tokio::spawn(admin::serve(stream, self.com_tx.clone()));
+                }
+                Some(msg) = self.com_rx.recv() => match msg {
+                    ControlMessage::Stop => {
                         debug!("Shutting down server...");
                         break;
                     }
+                    ControlMessage::Drain => {
+                        println!("Draining, no more tests are accepted");
+                        admission.drain();
+                    }
+                    ControlMessage::ListTests(reply) => {
+                        let _ = reply.send(self.state.list_tests());
+                    }
+                    ControlMessage::GetStats(id, reply) => {
+                        let _ = reply.send(self.state.stats(id));
+                    }
+                    ControlMessage::Cancel(id, reply) => {
+                        let cancelled = self.state.cancel(id);
+                        if cancelled {
+                            println!("Cancelling test {id}");
+                        }
+                        let _ = reply.send(cancelled);
+                    }
+                    ControlMessage::Interrupt(reply) => {
+                        admission.drain();
+                        let cancelled = self.state.cancel_all();
+                        println!("Interrupted, cancelled {cancelled} tests");
+                        let _ = reply.send(cancelled);
+                    }
+                },
+                _ = expiry.tick() => {
+                    for id in self.state.expire_outstanding(Instant::now()) {
+                        println!("Test {id}: no data connection after {ASSOCIATION_TIMEOUT:?}, dropped");
+                    }
+                }
+                _ = admission_changed.changed() => {
+                    idle_since = Instant::now();
+                    if self.config.one_off && admission.finished() > 0 {
+                        println!("The test is done, shutting down");
+                        break;
+                    }
+                }
+                _ = sleep_until(idle_deadline.unwrap_or_else(Instant::now)), if idle_deadline.is_some() => {
+                    if admission.running() > 0 {
+                        idle_since = Instant::now();
+                        continue;
+                    }
+                    println!("No tests for {:?}, shutting down", self.config.idle_timeout.unwrap_or_default());
+                    break;
                 }
             }
         }
@@ -154,7 +1012,133 @@ impl Server {
     }
 }
 
+/// The error if the congestion control `test` asks for can't be used on its data connection
+fn unavailable_congestion(test: &NewTestMessage) -> Option<NBError> {
+    let Protocol::TCP(tcp_test_info) = &test.protocol else {
+        return None;
+    };
+    let algorithm = tcp_test_info.socket_options.congestion.as_ref()?;
+    sockopt::check_congestion(algorithm)
+        .err()
+        .map(|e| NBError::CongestionUnavailable(algorithm.clone(), e))
+}
+
+/// Accepts the next admin connection, never returns if there's no admin socket
+async fn accept_admin(admin: Option<&AdminSocket>) -> io::Result<UnixStream> {
+    match admin {
+        Some(admin) => admin.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Sends `error` to the peer and closes the connection. Errors while doing so are
+/// ignored, the connection is gone either way.
+async fn reject(mut socket: TcpStream, error: NBError) {
+    let message = ErrorMessage::from(&error);
+    let _ = crate::send_message(message, MessageID::MSG_ERROR_MESSAGE, &mut socket).await;
+    let _ = socket.shutdown().await;
+}
+
+/// Sent to a running server through the sender returned by `Server::new`
 #[derive(Debug)]
 pub enum ControlMessage {
+    /// Stop accepting clients, `Server::accept` returns
     Stop,
+    /// Stop admitting tests, `Server::accept` returns once the admitted tests are done
+    Drain,
+    /// Lists the outstanding and running tests
+    ListTests(oneshot::Sender<Vec<TestInfo>>),
+    /// Live statistics of a running test, `None` if there's no such test
+    GetStats(u64, oneshot::Sender<Option<TestStats>>),
+    /// Cancels a test, answers whether there was a test with the id
+    Cancel(u64, oneshot::Sender<bool>),
+    /// Stop admitting tests and cancel all of them, `Server::accept` returns once they're
+    /// done. Answers the number of cancelled tests.
+    Interrupt(oneshot::Sender<usize>),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn server_state() -> ServerState {
+        ServerState {
+            outstanding_tests: Mutex::new(Vec::new()),
+            running_tests: Mutex::new(Vec::new()),
+            admission: Admission::new(0),
+        }
+    }
+
+    #[test]
+    fn test_expire_outstanding() {
+        let state = server_state();
+        let (control, mut cancelled) = mpsc::unbounded_channel();
+        let now = Instant::now();
+        for (code, deadline) in [(1, now), (2, now + ASSOCIATION_TIMEOUT)] {
+            let slot = state.admission.try_admit(StdDuration::ZERO).unwrap();
+            state.outstanding_tests.lock().push(OutstandingTest {
+                message: NewTestMessage::for_test(code),
+                client: "127.0.0.1:40000".parse().unwrap(),
+                slot,
+                control: control.clone(),
+                heartbeats: true,
+                deadline,
+            });
+        }
+
+        assert_eq!(state.expire_outstanding(now), [0]);
+        // the slot of the dropped test is free and its client is told
+        assert_eq!(state.admission.running(), 1);
+        assert!(matches!(cancelled.try_recv(), Ok(ToClient::Cancelled(code)) if code == [1; 32]));
+        assert!(cancelled.try_recv().is_err());
+        assert_eq!(state.list_tests().len(), 1);
+    }
+
+    #[test]
+    fn test_status() {
+        let state = server_state();
+        let (control, _cancelled) = mpsc::unbounded_channel();
+        let client: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let running = state.admission.try_admit(StdDuration::ZERO).unwrap();
+        let outstanding = state.admission.try_admit(StdDuration::ZERO).unwrap();
+        state.outstanding_tests.lock().push(OutstandingTest {
+            message: NewTestMessage::for_test(2),
+            client,
+            slot: outstanding,
+            control: control.clone(),
+            heartbeats: true,
+            deadline: Instant::now() + ASSOCIATION_TIMEOUT,
+        });
+        state.running_tests.lock().push(RunningTest {
+            id: running.id(),
+            client,
+            message: NewTestMessage::for_test(1),
+            started: Instant::now(),
+            monitor: Arc::default(),
+            control,
+            heartbeats: true,
+            connections: None,
+        });
+
+        let tests = state.list_tests();
+        assert_eq!(
+            tests
+                .iter()
+                .map(|test| (test.id, test.state))
+                .collect::<Vec<_>>(),
+            [(0, TestState::Running), (1, TestState::Outstanding)]
+        );
+        assert_eq!(tests[0].client, client);
+        assert_eq!(tests[0].protocol, "TCP");
+        assert_eq!(tests[0].duration, Some(10.0));
+        assert!(tests[0].elapsed.is_some());
+        assert_eq!(tests[1].elapsed, None);
+
+        let stats = state.stats(0).unwrap();
+        assert_eq!(stats.test.id, 0);
+        assert_eq!(stats.summary.bytes_sent, 0);
+        // only running tests have statistics
+        assert_eq!(state.stats(1), None);
+        assert_eq!(state.stats(2), None);
+    }
 }
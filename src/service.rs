@@ -0,0 +1,174 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::net::TcpListener;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use tracing::{debug, warn};
+
+/// The first file descriptor passed by systemd socket activation
+const LISTEN_FDS_START: i32 = 3;
+
+/// Moves the process into the background: forks twice so that it isn't a session leader
+/// and can't reacquire a terminal, and points stdin to /dev/null and stdout and stderr
+/// to `log_file` or /dev/null. The working directory is kept, so relative paths given on
+/// the command line stay valid.
+///
+/// Has to be called before any threads, including the tokio runtime, are started.
+pub fn daemonize(log_file: Option<&Path>) -> Result<()> {
+    let null = File::options().read(true).write(true).open("/dev/null")?;
+    let log = match log_file {
+        Some(path) => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open log file {}", path.display()))?,
+        None => null.try_clone()?,
+    };
+
+    fork_and_exit_parent()?;
+    // SAFETY: no preconditions
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error()).context("setsid failed");
+    }
+    fork_and_exit_parent()?;
+
+    for (fd, target) in [(&null, 0), (&log, 1), (&log, 2)] {
+        // SAFETY: both file descriptors are valid
+        if unsafe { libc::dup2(fd.as_raw_fd(), target) } == -1 {
+            return Err(io::Error::last_os_error()).context("failed to redirect stdio");
+        }
+    }
+
+    Ok(())
+}
+
+fn fork_and_exit_parent() -> Result<()> {
+    // SAFETY: the process is single threaded, see `daemonize`
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()).context("fork failed"),
+        0 => Ok(()),
+        _ => std::process::exit(0),
+    }
+}
+
+/// A file holding the process id, removed when dropped
+#[derive(Debug)]
+pub struct PidFile(PathBuf);
+
+impl PidFile {
+    pub fn create(path: PathBuf) -> Result<Self> {
+        if let Ok(pid) = fs::read_to_string(&path) {
+            if let Ok(pid) = pid.trim().parse::<libc::pid_t>() {
+                // SAFETY: signal 0 only checks whether the process exists
+                if unsafe { libc::kill(pid, 0) } == 0 {
+                    bail!("{} belongs to running process {pid}", path.display());
+                }
+            }
+        }
+
+        fs::write(&path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("failed to write pid file {}", path.display()))?;
+        Ok(PidFile(path))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.0) {
+            warn!("failed to remove pid file {}: {e}", self.0.display());
+        }
+    }
+}
+
+/// Sends `state` (e.g. `READY=1`) to the service manager if it asked for notifications
+/// through `NOTIFY_SOCKET`. Does nothing otherwise.
+pub fn notify(state: &str) -> io::Result<()> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+
+    let path = path.to_string_lossy();
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path.as_ref())?,
+    };
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    debug!("notified the service manager: {state}");
+
+    Ok(())
+}
+
+/// Takes the listening socket passed by systemd socket activation (`LISTEN_PID` and
+/// `LISTEN_FDS`), if there is one. Only the first passed socket is used. The variables
+/// are removed so that child processes don't take the socket as well.
+///
+/// Has to be called before any threads, including the tokio runtime, are started.
+pub fn systemd_listener() -> Result<Option<TcpListener>> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+    let fds = passed_fds(pid.as_deref(), fds.as_deref(), std::process::id())?;
+    if fds == 0 {
+        return Ok(None);
+    }
+    if fds > 1 {
+        warn!("systemd passed {fds} sockets, only the first one is used");
+    }
+
+    // SAFETY: systemd passes ownership of the descriptors starting at LISTEN_FDS_START
+    let listener = unsafe { TcpListener::from_raw_fd(LISTEN_FDS_START) };
+    // SAFETY: the descriptor is valid
+    unsafe { libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, libc::FD_CLOEXEC) };
+    listener
+        .local_addr()
+        .context("the socket passed by systemd isn't a TCP socket")?;
+
+    Ok(Some(listener))
+}
+
+/// Returns the number of sockets systemd passed to process `own_pid`, given the values of
+/// `LISTEN_PID` and `LISTEN_FDS`
+fn passed_fds(pid: Option<&str>, fds: Option<&str>, own_pid: u32) -> Result<i32> {
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(0);
+    };
+    // the sockets are meant for another process, e.g. our parent
+    if pid.parse() != Ok(own_pid) {
+        return Ok(0);
+    }
+    let fds: i32 = fds.parse().context("invalid LISTEN_FDS")?;
+
+    Ok(fds.max(0))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_passed_fds() {
+        assert_eq!(passed_fds(None, None, 42).unwrap(), 0);
+        assert_eq!(passed_fds(Some("42"), None, 42).unwrap(), 0);
+        assert_eq!(passed_fds(Some("42"), Some("1"), 42).unwrap(), 1);
+        assert_eq!(passed_fds(Some("42"), Some("3"), 42).unwrap(), 3);
+        assert_eq!(passed_fds(Some("42"), Some("0"), 42).unwrap(), 0);
+        assert_eq!(passed_fds(Some("42"), Some("-1"), 42).unwrap(), 0);
+        assert!(passed_fds(Some("42"), Some("one"), 42).is_err());
+    }
+
+    #[test]
+    fn test_passed_fds_pid_mismatch() {
+        assert_eq!(passed_fds(Some("41"), Some("1"), 42).unwrap(), 0);
+        assert_eq!(passed_fds(Some("pid"), Some("1"), 42).unwrap(), 0);
+        // the descriptors aren't parsed if they aren't ours
+        assert_eq!(passed_fds(Some("41"), Some("one"), 42).unwrap(), 0);
+    }
+}
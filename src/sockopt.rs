@@ -0,0 +1,348 @@
+use std::fmt;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::time::Duration as StdDuration;
+
+use libc::{c_int, c_uint};
+use serde::{Deserialize, Serialize};
+
+use crate::TCPSocketOptions;
+
+fn set_option<T>(fd: RawFd, level: c_int, name: c_int, value: T) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+fn get_option<T: Default>(fd: RawFd, level: c_int, name: c_int) -> io::Result<T> {
+    let mut value = T::default();
+    let mut len = mem::size_of::<T>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            &mut value as *mut T as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(value)
+}
+
+fn to_c_int(value: u64, name: &str) -> io::Result<c_int> {
+    c_int::try_from(value).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{name} value {value} is out of range"),
+        )
+    })
+}
+
+/// Applies the requested socket options to `fd`.
+///
+/// Options that influence the handshake (window size and MSS) only take full effect
+/// if this is called before the socket is connected.
+pub(crate) fn apply_tcp_options(fd: RawFd, opts: &TCPSocketOptions) -> io::Result<()> {
+    if let Some(window) = opts.window_size {
+        let window = to_c_int(window, "window size")?;
+        set_option(fd, libc::SOL_SOCKET, libc::SO_SNDBUF, window)?;
+        set_option(fd, libc::SOL_SOCKET, libc::SO_RCVBUF, window)?;
+    }
+
+    if let Some(mss) = opts.mss {
+        set_option(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_MAXSEG,
+            to_c_int(mss, "MSS")?,
+        )?;
+    }
+
+    if let Some(lowat) = opts.notsent_lowat {
+        let lowat = to_c_int(lowat, "notsent lowat")?;
+        set_option(fd, libc::IPPROTO_TCP, libc::TCP_NOTSENT_LOWAT, lowat)?;
+    }
+
+    if opts.nodelay {
+        set_option(fd, libc::IPPROTO_TCP, libc::TCP_NODELAY, 1 as c_int)?;
+    }
+
+    if opts.quickack {
+        set_option(fd, libc::IPPROTO_TCP, libc::TCP_QUICKACK, 1 as c_int)?;
+    }
+
+    if opts.keepalive {
+        set_option(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1 as c_int)?;
+    }
+
+    if let Some(congestion) = &opts.congestion {
+        set_congestion(fd, congestion)?;
+    }
+
+    if let Some(tos) = opts.tos {
+        set_tos(fd, tos)?;
+    }
+
+    Ok(())
+}
+
+/// Limits the rate the kernel sends at, in bytes per second
+pub(crate) fn set_max_pacing_rate(fd: RawFd, rate: u64) -> io::Result<()> {
+    set_option(fd, libc::SOL_SOCKET, libc::SO_MAX_PACING_RATE, rate)
+}
+
+/// Aborts the connection once sent data stayed unacknowledged for `timeout`
+pub(crate) fn set_user_timeout(fd: RawFd, timeout: StdDuration) -> io::Result<()> {
+    let timeout = c_uint::try_from(timeout.as_millis()).unwrap_or(c_uint::MAX);
+    set_option(fd, libc::IPPROTO_TCP, libc::TCP_USER_TIMEOUT, timeout)
+}
+
+fn is_ipv6(fd: RawFd) -> io::Result<bool> {
+    let domain: c_int = get_option(fd, libc::SOL_SOCKET, libc::SO_DOMAIN)?;
+    Ok(domain == libc::AF_INET6)
+}
+
+/// Sets the TOS byte, or the traffic class for IPv6 sockets
+pub(crate) fn set_tos(fd: RawFd, tos: u8) -> io::Result<()> {
+    if is_ipv6(fd)? {
+        set_option(fd, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, c_int::from(tos))
+    } else {
+        set_option(fd, libc::IPPROTO_IP, libc::IP_TOS, c_int::from(tos))
+    }
+}
+
+fn get_tos(fd: RawFd) -> io::Result<u8> {
+    let tos: c_int = if is_ipv6(fd)? {
+        get_option(fd, libc::IPPROTO_IPV6, libc::IPV6_TCLASS)?
+    } else {
+        get_option(fd, libc::IPPROTO_IP, libc::IP_TOS)?
+    };
+
+    Ok(tos as u8)
+}
+
+const TCP_ECN_SYSCTL: &str = "/proc/sys/net/ipv4/tcp_ecn";
+
+/// Reads the tcp_ecn sysctl. Linux has no per socket switch for ECN: 1 requests it on
+/// outgoing connections, 2 (the default) only accepts it when the peer asks for it and 0
+/// disables it.
+pub(crate) fn tcp_ecn_sysctl() -> io::Result<u8> {
+    let value = std::fs::read_to_string(TCP_ECN_SYSCTL)?;
+    value.trim().parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected net.ipv4.tcp_ecn value {value:?}"),
+        )
+    })
+}
+
+/// Fails unless the system requests ECN on the connections it opens
+pub(crate) fn check_tcp_ecn() -> io::Result<()> {
+    match tcp_ecn_sysctl()? {
+        1 => Ok(()),
+        value => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("ECN has been requested but net.ipv4.tcp_ecn is {value}, set it to 1"),
+        )),
+    }
+}
+
+/// Maximum length of a congestion control algorithm name, including the NUL byte
+const TCP_CA_NAME_MAX: usize = 16;
+
+fn set_congestion(fd: RawFd, algorithm: &str) -> io::Result<()> {
+    if algorithm.len() >= TCP_CA_NAME_MAX {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("congestion control algorithm name {algorithm} is too long"),
+        ));
+    }
+
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_CONGESTION,
+            algorithm.as_ptr() as *const libc::c_void,
+            algorithm.len() as libc::socklen_t,
+        )
+    };
+
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Checks that this process can use the congestion control `algorithm`, by setting it on
+/// a socket that is never connected
+pub(crate) fn check_congestion(algorithm: &str) -> io::Result<()> {
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    set_congestion(socket.as_raw_fd(), algorithm)
+}
+
+fn get_congestion(fd: RawFd) -> io::Result<String> {
+    let buf: [u8; TCP_CA_NAME_MAX] = get_option(fd, libc::IPPROTO_TCP, libc::TCP_CONGESTION)?;
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+
+    Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+/// The socket options as reported back by the kernel after they have been applied.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct EffectiveTCPOptions {
+    pub send_buf: u32,
+    pub recv_buf: u32,
+    pub mss: u32,
+    pub nodelay: bool,
+    pub notsent_lowat: u32,
+    pub quickack: bool,
+    pub keepalive: bool,
+    pub congestion: String,
+    pub tos: u8,
+    /// Whether ECN has been negotiated on the connection
+    pub ecn: bool,
+}
+
+pub(crate) fn effective_tcp_options(fd: RawFd) -> io::Result<EffectiveTCPOptions> {
+    let send_buf: c_int = get_option(fd, libc::SOL_SOCKET, libc::SO_SNDBUF)?;
+    let recv_buf: c_int = get_option(fd, libc::SOL_SOCKET, libc::SO_RCVBUF)?;
+    let mss: c_int = get_option(fd, libc::IPPROTO_TCP, libc::TCP_MAXSEG)?;
+    let nodelay: c_int = get_option(fd, libc::IPPROTO_TCP, libc::TCP_NODELAY)?;
+    let notsent_lowat: c_int = get_option(fd, libc::IPPROTO_TCP, libc::TCP_NOTSENT_LOWAT)?;
+    let quickack: c_int = get_option(fd, libc::IPPROTO_TCP, libc::TCP_QUICKACK)?;
+    let keepalive: c_int = get_option(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE)?;
+    let congestion = get_congestion(fd)?;
+    let tos = get_tos(fd)?;
+
+    Ok(EffectiveTCPOptions {
+        send_buf: send_buf as u32,
+        recv_buf: recv_buf as u32,
+        mss: mss as u32,
+        nodelay: nodelay != 0,
+        notsent_lowat: notsent_lowat as u32,
+        quickack: quickack != 0,
+        keepalive: keepalive != 0,
+        congestion,
+        tos,
+        ecn: false,
+    })
+}
+
+impl fmt::Display for EffectiveTCPOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sndbuf: {}, rcvbuf: {}, mss: {}, nodelay: {}, notsent_lowat: {}, quickack: {}, keepalive: {}, congestion: {}, tos: {:#04x}, ecn: {}",
+            self.send_buf,
+            self.recv_buf,
+            self.mss,
+            self.nodelay,
+            self.notsent_lowat,
+            self.quickack,
+            self.keepalive,
+            self.congestion,
+            self.tos,
+            self.ecn
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_apply_tcp_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let opts = TCPSocketOptions {
+            window_size: Some(64 * 1024),
+            nodelay: true,
+            keepalive: true,
+            ..Default::default()
+        };
+        apply_tcp_options(listener.as_raw_fd(), &opts).unwrap();
+
+        let effective = effective_tcp_options(listener.as_raw_fd()).unwrap();
+        // the kernel doubles the requested value to account for bookkeeping overhead
+        assert!(effective.send_buf >= 64 * 1024);
+        assert!(effective.recv_buf >= 64 * 1024);
+        assert!(effective.nodelay);
+        assert!(effective.keepalive);
+    }
+
+    #[test]
+    fn test_congestion_control() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        // reno is built into every kernel with TCP support
+        let opts = TCPSocketOptions {
+            congestion: Some(String::from("reno")),
+            ..Default::default()
+        };
+        apply_tcp_options(listener.as_raw_fd(), &opts).unwrap();
+
+        let effective = effective_tcp_options(listener.as_raw_fd()).unwrap();
+        assert_eq!(effective.congestion, "reno");
+
+        let opts = TCPSocketOptions {
+            congestion: Some(String::from("an-algorithm-that-is-too-long")),
+            ..Default::default()
+        };
+        assert!(apply_tcp_options(listener.as_raw_fd(), &opts).is_err());
+
+        check_congestion("reno").unwrap();
+        assert!(check_congestion("not-an-algorithm").is_err());
+    }
+
+    #[test]
+    fn test_tos() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let opts = TCPSocketOptions {
+            tos: Some(0xb8),
+            ..Default::default()
+        };
+        apply_tcp_options(listener.as_raw_fd(), &opts).unwrap();
+
+        let effective = effective_tcp_options(listener.as_raw_fd()).unwrap();
+        assert_eq!(effective.tos, 0xb8);
+    }
+
+    #[test]
+    fn test_user_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        set_user_timeout(listener.as_raw_fd(), StdDuration::from_millis(2500)).unwrap();
+
+        let timeout: c_uint = get_option(
+            listener.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_USER_TIMEOUT,
+        )
+        .unwrap();
+        assert_eq!(timeout, 2500);
+    }
+}
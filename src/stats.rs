@@ -0,0 +1,165 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::{NBytes, TestSummary};
+
+/// Two-sided 95% quantiles of Student's t-distribution for 1 to 30 degrees of freedom
+const T_QUANTILES: [f64; 30] = [
+    12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+    2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+    2.052, 2.048, 2.045, 2.042,
+];
+
+fn t_quantile(degrees_of_freedom: usize) -> f64 {
+    match degrees_of_freedom {
+        0 => f64::NAN,
+        1..=30 => T_QUANTILES[degrees_of_freedom - 1],
+        31..=40 => 2.021,
+        41..=60 => 2.000,
+        61..=120 => 1.980,
+        _ => 1.960,
+    }
+}
+
+/// How a value varied over the runs of a repeated test
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Statistics {
+    pub mean: f64,
+    pub median: f64,
+    /// Sample standard deviation
+    pub stddev: f64,
+    /// Coefficient of variation, the standard deviation relative to the mean
+    pub cv: f64,
+    /// 95% confidence interval of the mean, based on Student's t-distribution
+    pub confidence_interval: [f64; 2],
+}
+
+impl Statistics {
+    /// `None` for less than two samples
+    pub fn from_samples(samples: &[f64]) -> Option<Self> {
+        if samples.len() < 2 {
+            return None;
+        }
+
+        let n = samples.len() as f64;
+        let mean = samples.iter().sum::<f64>() / n;
+        let variance = samples
+            .iter()
+            .map(|sample| (sample - mean).powi(2))
+            .sum::<f64>()
+            / (n - 1.0);
+        let stddev = variance.sqrt();
+
+        let mut sorted = samples.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        let middle = sorted.len() / 2;
+        let median = if sorted.len().is_multiple_of(2) {
+            (sorted[middle - 1] + sorted[middle]) / 2.0
+        } else {
+            sorted[middle]
+        };
+
+        let margin = t_quantile(samples.len() - 1) * stddev / n.sqrt();
+        Some(Statistics {
+            mean,
+            median,
+            stddev,
+            cv: if mean == 0.0 { 0.0 } else { stddev / mean },
+            confidence_interval: [mean - margin, mean + margin],
+        })
+    }
+
+    fn write_row(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        name: &str,
+        format: impl Fn(f64) -> String,
+    ) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<10} | {:>14} | {:>14} | {:>14} | {:>5.1}% | {} - {}",
+            name,
+            format(self.mean),
+            format(self.median),
+            format(self.stddev),
+            self.cv * 100.0,
+            format(self.confidence_interval[0]),
+            format(self.confidence_interval[1]),
+        )
+    }
+}
+
+/// The results of a test that ran several times
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RepeatedTest {
+    pub runs: Vec<TestSummary>,
+    /// In bits per second, in the direction that carried the most data
+    pub throughput: Option<Statistics>,
+    /// Of the mean RTT in milliseconds
+    pub rtt: Option<Statistics>,
+}
+
+impl RepeatedTest {
+    /// Runs that were cancelled or lost their peer are left out of the statistics
+    pub fn from_runs(runs: Vec<TestSummary>) -> Self {
+        let complete = || {
+            runs.iter()
+                .filter(|summary| !summary.cancelled && summary.peer_lost.is_none())
+        };
+        let throughput: Vec<_> = complete().map(TestSummary::bits_per_second).collect();
+        let rtt: Vec<_> = complete().filter_map(|summary| summary.mean_rtt).collect();
+
+        RepeatedTest {
+            throughput: Statistics::from_samples(&throughput),
+            rtt: Statistics::from_samples(&rtt),
+            runs,
+        }
+    }
+}
+
+impl fmt::Display for RepeatedTest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.throughput.is_none() && self.rtt.is_none() {
+            return write!(f, "Not enough complete runs for statistics");
+        }
+
+        writeln!(
+            f,
+            "{:<10} | {:>14} | {:>14} | {:>14} | {:>6} | 95% confidence interval",
+            "", "mean", "median", "stddev", "cv"
+        )?;
+        if let Some(throughput) = &self.throughput {
+            throughput.write_row(f, "throughput", |bits| {
+                format!("{}/s", NBytes::format_bits_per_second(bits))
+            })?;
+        }
+        if let Some(rtt) = &self.rtt {
+            rtt.write_row(f, "rtt", |ms| format!("{ms:.3} ms"))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_statistics() {
+        assert_eq!(Statistics::from_samples(&[1.0]), None);
+
+        let statistics =
+            Statistics::from_samples(&[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]).unwrap();
+        assert_eq!(statistics.mean, 5.0);
+        assert_eq!(statistics.median, 4.5);
+        assert!((statistics.stddev - 2.138).abs() < 0.001);
+        assert!((statistics.cv - 0.4276).abs() < 0.001);
+        // 5 +- 2.365 * 2.138 / sqrt(8)
+        assert!((statistics.confidence_interval[0] - 3.212).abs() < 0.001);
+        assert!((statistics.confidence_interval[1] - 6.788).abs() < 0.001);
+    }
+}
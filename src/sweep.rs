@@ -0,0 +1,137 @@
+use std::fmt;
+use std::fmt::Write;
+
+use serde::Serialize;
+
+use crate::{Direction, NBytes, TestSummary};
+
+/// The values a sweep tries for each parameter, every combination runs one test. An empty
+/// list keeps the value of the configured test.
+#[derive(Debug, Clone, Default)]
+pub struct SweepGrid {
+    /// Send and receive buffer lengths
+    pub lengths: Vec<u64>,
+    /// Target bitrates, 0 for unlimited
+    pub bitrates: Vec<u64>,
+    pub directions: Vec<Direction>,
+}
+
+/// The result of one combination of a sweep
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SweepPoint {
+    pub length: u64,
+    /// 0 for unlimited
+    pub bitrate: u64,
+    pub direction: Direction,
+    pub summary: TestSummary,
+}
+
+/// Results of running a test over a grid of parameters
+#[derive(Debug, Serialize)]
+pub struct Sweep(pub Vec<SweepPoint>);
+
+const CSV_HEADER: &str = "length,bitrate,direction,bitsPerSecond,bytesSent,bytesReceived,\
+duration,retransmits,meanRtt,cancelled";
+
+impl Sweep {
+    /// One line per combination, the throughput in bits per second and the RTT in
+    /// milliseconds. Values that weren't measured are empty.
+    pub fn to_csv(&self) -> String {
+        let mut csv = format!("{CSV_HEADER}\n");
+        let optional = |value: Option<String>| value.unwrap_or_default();
+        for point in &self.0 {
+            let summary = &point.summary;
+            let _ = writeln!(
+                csv,
+                "{},{},{},{:.0},{},{},{:.3},{},{},{}",
+                point.length,
+                point.bitrate,
+                direction_name(point.direction),
+                summary.bits_per_second(),
+                summary.bytes_sent,
+                summary.bytes_received,
+                summary.duration,
+                optional(summary.retransmits.map(|r| r.to_string())),
+                optional(summary.mean_rtt.map(|rtt| format!("{rtt:.3}"))),
+                summary.cancelled,
+            );
+        }
+
+        csv
+    }
+}
+
+pub(crate) fn direction_name(direction: Direction) -> &'static str {
+    match direction {
+        Direction::ClientToServer => "to server",
+        Direction::ServerToClient => "to client",
+        Direction::Bidirectional => "both",
+    }
+}
+
+impl fmt::Display for Sweep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>12} | {:>16} | {:>9} | {:>16} | {:>11} | {:>10}",
+            "length", "bitrate", "direction", "throughput", "retransmits", "rtt"
+        )?;
+        for point in &self.0 {
+            let summary = &point.summary;
+            let length = NBytes::from(point.length).format_as_bytes();
+            let bitrate = match point.bitrate {
+                0 => String::from("unlimited"),
+                bitrate => format!("{}/s", NBytes::format_bits_per_second(bitrate as f64)),
+            };
+            let throughput = NBytes::format_bits_per_second(summary.bits_per_second());
+            let retransmits = summary
+                .retransmits
+                .map_or_else(|| String::from("-"), |r| r.to_string());
+            let rtt = summary
+                .mean_rtt
+                .map_or_else(|| String::from("-"), |rtt| format!("{rtt:.3} ms"));
+            writeln!(
+                f,
+                "{:>12} | {:>16} | {:>9} | {:>16} | {:>11} | {:>10}",
+                length.to_string(),
+                bitrate,
+                direction_name(point.direction),
+                format!("{throughput}/s"),
+                retransmits,
+                rtt
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_csv() {
+        let sweep = Sweep(vec![SweepPoint {
+            length: 1024,
+            bitrate: 0,
+            direction: Direction::ServerToClient,
+            summary: TestSummary {
+                bytes_received: 1_000_000,
+                duration: 2.0,
+                mean_rtt: Some(0.25),
+                ..Default::default()
+            },
+        }]);
+
+        let csv = sweep.to_csv();
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some(CSV_HEADER));
+        assert_eq!(
+            lines.next(),
+            Some("1024,0,to client,4000000,0,1000000,2.000,,0.250,false")
+        );
+        assert_eq!(lines.next(), None);
+    }
+}
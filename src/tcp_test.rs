@@ -1,16 +1,31 @@
+use std::fs::File;
+use std::io::{self, Write};
 use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::time::Duration as StdDuration;
 
 use libc::c_int;
+use tokio::io::Interest;
 use tokio::net::TcpStream;
-use tracing::{debug, error, trace};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::{debug, error, trace, warn};
 
 use crate::{
-    test_manager::{IntervalResult, Test, TestControlMessage},
-    NewTestMessage, Protocol, Role, TCPTestInfo,
+    client::DataConnector,
+    payload::{BlockWriter, PayloadBuffer, SendPayload, Verifier, HEADER_LEN},
+    sockopt::{self, EffectiveTCPOptions},
+    test_manager::{IntervalResult, TcpStats, Test, TestControlMessage},
+    token_bucket::TokenBucket,
+    zerocopy::{self, SplicePipe, ZeroCopyCompletions, ZeroCopyMapping},
+    Backend, CommonConfig, NewTestMessage, Pacing, Payload, Protocol, RecvMode, Role, SendMode,
+    TCPTestInfo, TestUpdate,
 };
 
 #[cfg(target_os = "linux")]
 #[derive(Debug, Default, PartialEq, PartialOrd)]
+#[repr(C)]
 pub(crate) struct TcpInfo {
     state: u8,
     ca_state: u8,
@@ -82,6 +97,9 @@ pub(crate) struct TcpInfo {
     snd_wnd: u32,
 }
 
+/// Set in `TcpInfo::options` if ECN has been negotiated
+const TCPI_OPT_ECN: u8 = 8;
+
 #[cfg(target_os = "linux")]
 fn get_tcp_info(sockfd: c_int) -> Option<TcpInfo> {
     let mut tcp_info = TcpInfo::default();
@@ -109,22 +127,396 @@ pub(crate) struct TCPTest {
     test_info: NewTestMessage,
     tcp_test_info: TCPTestInfo,
     role: Role,
+    effective_options: Option<EffectiveTCPOptions>,
+    tcp_stats: TcpStatsSampler,
+    /// File used by the sendfile send path and the file payload
+    file: Option<PathBuf>,
+    /// File the file receive path writes to
+    recv_file: Option<PathBuf>,
+    /// Set for resilient tests
+    reconnect: Option<Reconnect>,
+    /// The data connection failed with an error, rather than being closed by the peer
+    failed: bool,
+}
+
+/// How long a reconnection attempt may take, the kernel retries a connect with an
+/// exponential backoff which would delay noticing that the path is back
+const RECONNECT_TIMEOUT: StdDuration = StdDuration::from_secs(2);
+/// Pause between failed reconnection attempts
+const RECONNECT_DELAY: StdDuration = StdDuration::from_secs(1);
+
+/// How long the server waits for the client of a resilient test to connect again
+const ACCEPT_TIMEOUT: StdDuration = StdDuration::from_secs(60);
+/// A resilient test that doesn't receive anything for this long, while it expects data,
+/// considers its data connection broken
+const RECV_STALL_TIMEOUT: StdDuration = StdDuration::from_secs(3);
+/// At a low bitrate the sender writes rarely, the receiver then waits for this many of
+/// its writes instead
+const RECV_STALL_WRITES: u32 = 3;
+/// Application pacing sends at most a write and this much of the target bitrate at once
+const PACING_BURST: StdDuration = StdDuration::from_millis(4);
+
+/// How a resilient test gets a new data connection once the old one broke
+pub(crate) enum Reconnect {
+    /// Connect to the server again, done by the client
+    Connect(DataConnector),
+    /// Wait for the client to connect again, done by the server
+    Accept(mpsc::UnboundedReceiver<TcpStream>),
+}
+
+impl Reconnect {
+    /// Waits until there is a new data connection. Returns `None` if the client didn't
+    /// connect again within `ACCEPT_TIMEOUT`.
+    async fn next(&mut self) -> Option<TcpStream> {
+        match self {
+            Reconnect::Connect(connector) => loop {
+                match tokio::time::timeout(RECONNECT_TIMEOUT, connector.connect()).await {
+                    Ok(Ok(socket)) => return Some(socket),
+                    Ok(Err(e)) => debug!("failed to reconnect: {e}"),
+                    Err(_) => debug!("failed to reconnect: timed out"),
+                }
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            },
+            Reconnect::Accept(_) => {
+                match tokio::time::timeout(ACCEPT_TIMEOUT, self.accepted()).await {
+                    Ok(socket) => Some(socket),
+                    Err(_) => {
+                        warn!("the client didn't connect again within {ACCEPT_TIMEOUT:?}");
+                        None
+                    }
+                }
+            }
+        }
+    }
+
+    /// Waits for a connection the client opened to replace the current one, which may
+    /// happen before the server noticed that the current one broke
+    async fn accepted(&mut self) -> TcpStream {
+        match self {
+            Reconnect::Accept(connections) => match connections.recv().await {
+                Some(socket) => socket,
+                // the server dropped the test, it ends through the test manager
+                None => std::future::pending().await,
+            },
+            Reconnect::Connect(_) => std::future::pending().await,
+        }
+    }
+}
+
+/// Waits for a replacement connection of a resilient test, never returns otherwise
+async fn replacement(reconnect: Option<&mut Reconnect>) -> TcpStream {
+    match reconnect {
+        Some(reconnect) => reconnect.accepted().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Turns the cumulative counters of TCP_INFO into the increments of an interval
+#[derive(Debug, Default)]
+struct TcpStatsSampler {
+    last_total_retrans: u32,
+    last_delivered_ce: u32,
+}
+
+impl TcpStatsSampler {
+    fn sample(&mut self, fd: c_int) -> Option<TcpStats> {
+        let tcp_info = get_tcp_info(fd)?;
+        let retransmits = tcp_info
+            .total_retrans
+            .saturating_sub(self.last_total_retrans);
+        self.last_total_retrans = tcp_info.total_retrans;
+        let delivered_ce = if tcp_info.options & TCPI_OPT_ECN != 0 {
+            let delivered_ce = tcp_info.delivered_ce.saturating_sub(self.last_delivered_ce);
+            self.last_delivered_ce = tcp_info.delivered_ce;
+            Some(delivered_ce)
+        } else {
+            None
+        };
+
+        Some(TcpStats {
+            retransmits,
+            rtt: tcp_info.rtt,
+            rttvar: tcp_info.rttvar,
+            delivered_ce,
+            pacing_rate: tcp_info.pacing_rate,
+            max_pacing_rate: tcp_info.max_pacing_rate,
+        })
+    }
 }
 
 impl TCPTest {
+    fn sample_tcp_stats(&mut self) -> Option<TcpStats> {
+        self.tcp_stats.sample(self.socket.as_raw_fd())
+    }
+
+    /// Waits for a new data connection after the old one broke, answering the test manager
+    /// in the meantime. Returns `None` if the test isn't resilient or ends first.
+    async fn reconnect(
+        &mut self,
+        comm_channel: &mut mpsc::Receiver<TestControlMessage>,
+        interval: &mut IntervalResult,
+    ) -> Option<TcpStream> {
+        let mut reconnect = self.reconnect.take()?;
+        warn!("the data connection broke, waiting for a new one");
+        let socket = {
+            let attempt = reconnect.next();
+            tokio::pin!(attempt);
+            loop {
+                tokio::select! {
+                    socket = &mut attempt => break socket,
+                    msg = comm_channel.recv() => match msg {
+                        Some(TestControlMessage::GetIntervalResult(chan)) => {
+                            let mut interval_to_send = mem::take(&mut *interval);
+                            interval_to_send.prepare_to_send();
+                            if chan.send(interval_to_send).is_err() {
+                                error!("failed to send interval results");
+                            }
+                        }
+                        Some(TestControlMessage::Update(update)) => {
+                            warn!("can't change the test while it reconnects, ignoring {update:?}");
+                        }
+                        Some(TestControlMessage::Done | TestControlMessage::Abort) | None => {
+                            break None
+                        }
+                    }
+                }
+            }
+        };
+
+        self.reconnect = Some(reconnect);
+        socket
+    }
+
+    /// Continues the test on `socket` after the previous connection broke
+    fn resume(&mut self, socket: TcpStream, path: &mut DataPath) {
+        debug!("replaced the data connection");
+        self.socket = socket;
+        self.failed = false;
+        self.tcp_stats = TcpStatsSampler::default();
+
+        // these are tied to the old socket
+        if matches!(path.receiver, Receiver::ZeroCopy(_)) {
+            path.receiver = self.receiver(path.read_buf.len());
+        }
+        if matches!(path.sender, Sender::ZeroCopy(_)) {
+            path.sender = self.sender();
+        }
+        if path.should_send {
+            path.bucket = self.rate_limiter(path.send_len);
+            path.throttled_until = None;
+        }
+        path.last_received = Instant::now();
+    }
+
+    /// Sets up the data path of a test that starts
+    fn data_path(&self) -> DataPath {
+        let read_buf = vec![0; self.tcp_test_info.recv_buf_size.try_into().unwrap()];
+        let send_len = self.tcp_test_info.send_buf_size.try_into().unwrap();
+        let should_send = crate::should_send(self.test_info.direction, self.role);
+        let should_recv = crate::should_recv(self.test_info.direction, self.role);
+        let receiver = if should_recv {
+            self.receiver(read_buf.len())
+        } else {
+            Receiver::Copy
+        };
+        let verifier = if should_recv { self.verifier() } else { None };
+        let sender = if should_send {
+            self.sender()
+        } else {
+            Sender::Copy
+        };
+        let payload = if should_send {
+            self.send_payload(send_len)
+        } else {
+            SendPayload::Stream(PayloadBuffer::new(&Payload::Zeros, 0, None).unwrap())
+        };
+        let bucket = if should_send {
+            self.rate_limiter(send_len)
+        } else {
+            None
+        };
+
+        DataPath {
+            interval: IntervalResult::default(),
+            read_buf,
+            send_len,
+            should_send,
+            should_recv,
+            receiver,
+            verifier,
+            sender,
+            payload,
+            bucket,
+            throttled_until: None,
+            is_done: false,
+            paused: false,
+            aborted: false,
+            last_received: Instant::now(),
+            n_send: 0,
+            n_read: 0,
+            n_chan: 0,
+        }
+    }
+
+    /// Moves data on the current connection until the test ends or the connection breaks
+    async fn transfer(
+        &mut self,
+        path: &mut DataPath,
+        comm_channel: &mut mpsc::Receiver<TestControlMessage>,
+    ) {
+        loop {
+            let detect_stall =
+                self.reconnect.is_some() && path.should_recv && !path.paused && !path.is_done;
+            let stall_timeout = self.recv_stall_timeout();
+            tokio::select! {
+                _ = self.socket.readable(), if path.should_recv => {
+                    if !self.read(&mut path.n_read, &mut path.receiver, &mut path.verifier, path.read_buf.as_mut_slice(), &mut path.interval) {
+                        break;
+                    }
+                    path.last_received = Instant::now();
+                }
+                _ = self.socket.writable(), if path.should_send && !path.paused && path.throttled_until.is_none() => {
+                    if let Some(bucket) = &mut path.bucket {
+                        let len = path.send_len as u64;
+                        if bucket.try_consume(len).is_err() {
+                            path.throttled_until = Some(Instant::now() + bucket.wait_time(len));
+                            continue;
+                        }
+                    }
+                    if !self.write(&mut path.n_send, path.is_done, &mut path.sender, &mut path.payload, path.send_len, &mut path.interval) {
+                        break;
+                    }
+                    if matches!(path.sender, Sender::ZeroCopy(_)) {
+                        // completions on the error queue keep the socket writable,
+                        // yield so that the control channel doesn't starve
+                        tokio::task::yield_now().await;
+                    }
+                }
+                _ = tokio::time::sleep_until(path.throttled_until.unwrap_or_else(Instant::now)), if path.throttled_until.is_some() => {
+                    path.throttled_until = None;
+                }
+                _ = tokio::time::sleep_until(path.last_received + stall_timeout), if detect_stall => {
+                    warn!("nothing received for {stall_timeout:?}, the data connection seems broken");
+                    self.failed = true;
+                    break;
+                }
+                socket = replacement(self.reconnect.as_mut()), if matches!(self.reconnect, Some(Reconnect::Accept(_))) => {
+                    // the client noticed the broken connection first
+                    path.interval.set_disconnected();
+                    self.resume(socket, path);
+                    path.interval.set_reconnected();
+                }
+
+                msg = comm_channel.recv(), if !path.is_done => {
+                    trace!("chan selected ({msg:?})");
+                    path.n_chan += 1;
+                    if let Some(msg) = msg {
+                        match msg {
+                            TestControlMessage::GetIntervalResult(chan) => {
+                                let mut interval_to_send = std::mem::take(&mut path.interval);
+                                interval_to_send.prepare_to_send();
+                                if let Some(tcp_stats) = self.sample_tcp_stats() {
+                                    interval_to_send.set_tcp_stats(tcp_stats);
+                                }
+                                if chan.send(interval_to_send).is_err() {
+                                    error!("failed to send interval results");
+                                }
+
+                            }
+
+                            TestControlMessage::Done => {
+                                debug!("done");
+                                path.is_done = true;
+                                // the write path ends the test
+                                path.paused = false;
+                            }
+
+                            TestControlMessage::Abort => {
+                                debug!("aborted");
+                                path.aborted = true;
+                                break;
+                            }
+
+                            TestControlMessage::Update(update) => {
+                                if let Err(e) = self.test_info.apply(update) {
+                                    warn!("can't apply {update:?}: {e}");
+                                    continue;
+                                }
+                                match update {
+                                    TestUpdate::Pause => path.paused = true,
+                                    TestUpdate::Resume => {
+                                        path.paused = false;
+                                        path.last_received = Instant::now();
+                                    }
+                                    TestUpdate::Bitrate(bw) => {
+                                        if path.should_send {
+                                            self.reset_pacing(bw);
+                                            path.bucket = self.rate_limiter(path.send_len);
+                                            path.throttled_until = None;
+                                        }
+                                    }
+                                    TestUpdate::BufferSize { send, recv } => {
+                                        if let Some(size) = recv {
+                                            self.tcp_test_info.recv_buf_size = size;
+                                            path.read_buf.resize(size.try_into().unwrap(), 0);
+                                        }
+                                        if let Some(size) = send {
+                                            self.tcp_test_info.send_buf_size = size;
+                                            path.send_len = size.try_into().unwrap();
+                                            if path.should_send {
+                                                path.payload = self.send_payload(path.send_len);
+                                                path.bucket = self.rate_limiter(path.send_len);
+                                            }
+                                        }
+                                    }
+                                }
+                                path.interval.add_update(update);
+                            }
+                        }
+                    }
+                }
+
+                else => {
+                    trace!("else branch");
+                    break;
+                }
+            }
+        }
+    }
+
     fn read(
         &mut self,
         n_read: &mut u32,
+        receiver: &mut Receiver,
+        verifier: &mut Option<Verifier>,
         read_buf: &mut [u8],
         interval: &mut IntervalResult,
     ) -> bool {
         *n_read += 1;
-        match self.socket.try_read(read_buf) {
+        let fd = self.socket.as_raw_fd();
+        let res = match receiver {
+            Receiver::Copy => self.socket.try_read(read_buf),
+            Receiver::Splice(pipe) => self
+                .socket
+                .try_io(Interest::READABLE, || pipe.splice_from(fd, read_buf.len())),
+            Receiver::ZeroCopy(mapping) => self
+                .socket
+                .try_io(Interest::READABLE, || mapping.receive(fd, read_buf)),
+            Receiver::File(file) => match self.socket.try_read(read_buf) {
+                // this blocks the task on purpose, the disk is part of what is measured
+                Ok(n) => file.write_all(&read_buf[..n]).map(|_| n),
+                err => err,
+            },
+        };
+        match res {
             Ok(n) => {
                 if n == 0 {
                     trace!("read 0");
                     return false;
                 }
+                if let Some(verifier) = verifier {
+                    verifier.feed(&read_buf[..n]);
+                }
                 interval.add_bytes_received(n);
             }
             Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
@@ -132,6 +524,7 @@ impl TCPTest {
             }
             Err(e) => {
                 error!("{e}");
+                self.failed = true;
                 return false;
             }
         }
@@ -143,7 +536,9 @@ impl TCPTest {
         &mut self,
         n_send: &mut u32,
         is_done: bool,
-        send_buf: &[u8],
+        sender: &mut Sender,
+        payload: &mut SendPayload,
+        send_len: usize,
         interval: &mut IntervalResult,
     ) -> bool {
         *n_send += 1;
@@ -152,9 +547,33 @@ impl TCPTest {
             trace!("done from send");
             return false;
         }
-        match self.socket.try_write(send_buf) {
+        let fd = self.socket.as_raw_fd();
+        let send_buf = payload.chunk(send_len);
+        let res = match sender {
+            Sender::Copy => self.socket.try_write(send_buf),
+            Sender::ZeroCopy(completions) => {
+                if let Err(e) = completions.drain(fd) {
+                    error!("failed to read zerocopy completions: {e}");
+                    return false;
+                }
+                match self
+                    .socket
+                    .try_io(Interest::WRITABLE, || zerocopy::send_zerocopy(fd, send_buf))
+                {
+                    // the kernel ran out of option memory to track the outstanding sends,
+                    // retry once completions have been read
+                    Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => return true,
+                    res => res,
+                }
+            }
+            Sender::Sendfile { file, offset } => self.socket.try_io(Interest::WRITABLE, || {
+                zerocopy::sendfile(fd, file, offset, send_len)
+            }),
+        };
+        match res {
             Ok(n) => {
                 //trace!("sent bytes");
+                payload.advance(n);
                 interval.add_bytes_sent(n);
             }
             Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
@@ -162,6 +581,7 @@ impl TCPTest {
             }
             Err(e) => {
                 error!("{e}");
+                self.failed = true;
                 return false;
             }
         }
@@ -170,84 +590,422 @@ impl TCPTest {
     }
 }
 
-impl Test for TCPTest {
-    fn start_test(
-        mut self,
-        mut comm_channel: tokio::sync::mpsc::Receiver<crate::test_manager::TestControlMessage>,
-    ) -> tokio::task::JoinHandle<()> {
+/// The send path chosen by `SendMode`
+enum Sender {
+    Copy,
+    ZeroCopy(ZeroCopyCompletions),
+    Sendfile { file: File, offset: i64 },
+}
+
+/// The receive path chosen by `RecvMode`
+enum Receiver {
+    Copy,
+    Splice(SplicePipe),
+    ZeroCopy(ZeroCopyMapping),
+    File(File),
+}
+
+/// The state of a running test's data path, kept when the data connection is replaced
+struct DataPath {
+    interval: IntervalResult,
+    read_buf: Vec<u8>,
+    send_len: usize,
+    should_send: bool,
+    should_recv: bool,
+    receiver: Receiver,
+    verifier: Option<Verifier>,
+    sender: Sender,
+    payload: SendPayload,
+    bucket: Option<TokenBucket>,
+    throttled_until: Option<Instant>,
+    is_done: bool,
+    paused: bool,
+    aborted: bool,
+    /// Last time the connection became readable, to notice a stalled resilient test
+    last_received: Instant,
+    n_send: u32,
+    n_read: u32,
+    n_chan: u32,
+}
+
+impl TCPTest {
+    /// Sets up the receive path for the requested `RecvMode`, falling back to regular
+    /// copying reads if that isn't possible.
+    fn receiver(&self, read_len: usize) -> Receiver {
+        let recv_mode = self.tcp_test_info.recv_mode;
+        if self.tcp_test_info.verify && !matches!(recv_mode, RecvMode::Copy | RecvMode::File) {
+            warn!("receive mode {recv_mode:?} doesn't read the data, copying it to verify it");
+            return Receiver::Copy;
+        }
+
+        let receiver = match recv_mode {
+            RecvMode::Copy => Ok(Receiver::Copy),
+            RecvMode::Splice => SplicePipe::new().map(Receiver::Splice),
+            RecvMode::ZeroCopy => {
+                ZeroCopyMapping::new(self.socket.as_raw_fd(), read_len).map(Receiver::ZeroCopy)
+            }
+            RecvMode::File => match &self.recv_file {
+                Some(path) => File::create(path).map(Receiver::File),
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no file to receive to has been configured",
+                )),
+            },
+        };
+
+        receiver.unwrap_or_else(|e| {
+            warn!(
+                "can't use receive mode {:?}, falling back to copying: {e}",
+                self.tcp_test_info.recv_mode
+            );
+            Receiver::Copy
+        })
+    }
+
+    /// Sets up the send path for the requested `SendMode`, falling back to regular
+    /// copying writes if that isn't possible.
+    fn sender(&self) -> Sender {
+        let send_mode = self.tcp_test_info.send_mode;
+        if self.tcp_test_info.verify && send_mode != SendMode::Copy {
+            warn!("send mode {send_mode:?} can't send verifiable blocks, falling back to copying");
+            return Sender::Copy;
+        }
+
+        match send_mode {
+            SendMode::Copy => Sender::Copy,
+            SendMode::ZeroCopy => match zerocopy::enable_zerocopy(self.socket.as_raw_fd()) {
+                Ok(()) => Sender::ZeroCopy(ZeroCopyCompletions::default()),
+                Err(e) => {
+                    warn!("failed to enable SO_ZEROCOPY, falling back to copying: {e}");
+                    Sender::Copy
+                }
+            },
+            SendMode::Sendfile => {
+                let file = match &self.file {
+                    Some(path) => File::open(path),
+                    None => Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "no file has been configured",
+                    )),
+                };
+                match file {
+                    Ok(file) => Sender::Sendfile { file, offset: 0 },
+                    Err(e) => {
+                        warn!("can't use sendfile, falling back to copying: {e}");
+                        Sender::Copy
+                    }
+                }
+            }
+        }
+    }
+
+    /// Generates the payload for writes of `send_len` bytes, falling back to the default
+    /// payload if the requested one can't be generated.
+    fn payload_buffer(&self, send_len: usize) -> PayloadBuffer {
+        let payload = &self.tcp_test_info.payload;
+        PayloadBuffer::new(payload, send_len, self.file.as_deref()).unwrap_or_else(|e| {
+            warn!("can't use payload {payload:?}, falling back to the default: {e}");
+            PayloadBuffer::new(&Payload::default(), send_len, None).unwrap()
+        })
+    }
+
+    fn send_payload(&self, send_len: usize) -> SendPayload {
+        let payload = self.payload_buffer(send_len);
+        if !self.tcp_test_info.verify {
+            return SendPayload::Stream(payload);
+        }
+
+        // the payload is moved into the block writer, so a failure has to generate it again
+        BlockWriter::new(payload, send_len)
+            .map(SendPayload::Blocks)
+            .unwrap_or_else(|e| {
+                warn!("not sending verifiable blocks: {e}");
+                SendPayload::Stream(self.payload_buffer(send_len))
+            })
+    }
+
+    fn verifier(&self) -> Option<Verifier> {
+        if !self.tcp_test_info.verify {
+            return None;
+        }
+
+        // the receiver doesn't have the sender's file, only the checksums can be checked
+        let block_len = self.tcp_test_info.send_buf_size as usize;
+        let expected = match &self.tcp_test_info.payload {
+            Payload::File => None,
+            payload => PayloadBuffer::new(payload, block_len.saturating_sub(HEADER_LEN), None).ok(),
+        };
+        Verifier::new(block_len, expected)
+            .map_err(|e| warn!("not verifying the received data: {e}"))
+            .ok()
+    }
+
+    /// Lifts the kernel pacing rate if the bitrate of a running test changes to unlimited,
+    /// `TCPTest::rate_limiter` only sets it for limited bitrates
+    fn reset_pacing(&self, bw: u64) {
+        if bw == 0 && self.test_info.pacing == Pacing::Kernel {
+            if let Err(e) = sockopt::set_max_pacing_rate(self.socket.as_raw_fd(), u64::MAX) {
+                warn!("failed to reset SO_MAX_PACING_RATE: {e}");
+            }
+        }
+    }
+
+    /// How long nothing may be received before the data connection counts as broken. The
+    /// peer sends a write of the send buffer length at once, which takes a while at a low
+    /// target bitrate.
+    fn recv_stall_timeout(&self) -> StdDuration {
+        if self.test_info.bw == 0 {
+            return RECV_STALL_TIMEOUT;
+        }
+
+        let write_interval =
+            self.tcp_test_info.send_buf_size as f64 * 8.0 / self.test_info.bw as f64;
+        RECV_STALL_TIMEOUT.max(StdDuration::from_secs_f64(write_interval) * RECV_STALL_WRITES)
+    }
+
+    /// Sets up pacing for the target bitrate. Kernel pacing is applied to the socket
+    /// directly, application pacing returns the token bucket the writes have to go through.
+    fn rate_limiter(&self, send_len: usize) -> Option<TokenBucket> {
+        if self.test_info.bw == 0 {
+            return None;
+        }
+
+        let rate = self.test_info.bw / 8;
+        match self.test_info.pacing {
+            Pacing::Kernel => {
+                if let Err(e) = sockopt::set_max_pacing_rate(self.socket.as_raw_fd(), rate) {
+                    warn!("failed to set SO_MAX_PACING_RATE: {e}");
+                }
+                None
+            }
+            Pacing::Application => {
+                // tokens beyond a write make up for timers that fire late
+                let burst = (rate as f64 * PACING_BURST.as_secs_f64()) as u64;
+                Some(TokenBucket::new(rate, send_len as u64 + burst))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "uring")]
+impl TCPTest {
+    /// Runs the data path on a dedicated thread with io_uring. The test task only
+    /// collects the byte counters for the interval results.
+    fn start_uring_test(
+        self,
+        mut comm_channel: tokio::sync::mpsc::Receiver<TestControlMessage>,
+    ) -> tokio::task::JoinHandle<IntervalResult> {
+        use std::net::Shutdown;
+        use std::os::unix::io::AsFd;
+        use std::sync::{atomic::Ordering, Arc};
+
+        use crate::uring::{self, Counters};
+
         tokio::spawn(async move {
-            let mut interval = IntervalResult::default();
-            let mut read_buf = vec![0; self.tcp_test_info.recv_buf_size.try_into().unwrap()];
-            let send_buf = vec![0xAB; self.tcp_test_info.send_buf_size.try_into().unwrap()];
-            let mut is_done = false;
-            let (mut n_send, mut n_read, mut n_chan) = (0, 0, 0);
             let should_send = crate::should_send(self.test_info.direction, self.role);
             let should_recv = crate::should_recv(self.test_info.direction, self.role);
+            let send_len = self.tcp_test_info.send_buf_size.try_into().unwrap();
+            let recv_len = self.tcp_test_info.recv_buf_size.try_into().unwrap();
+            // NewTestMessage::check_supported rejected the options io_uring can't run
+            if should_send {
+                self.rate_limiter(send_len);
+            }
 
-            loop {
-                tokio::select! {
-                    _ = self.socket.readable(), if should_recv => {
-                        if !self.read(&mut n_read, read_buf.as_mut_slice(), &mut interval) {
-                            break;
-                        }
-                    }
-                    _ = self.socket.writable(), if should_send => {
-                        if !self.write(&mut n_send, is_done, send_buf.as_slice(), &mut interval) {
-                            break;
-                        }
-                    }
+            let send_buf = self.payload_buffer(send_len).chunk(send_len).to_vec();
+            let TCPTest {
+                socket,
+                mut tcp_stats,
+                ..
+            } = self;
+            // io_uring waits for the socket itself, so tokio has to give it up and the
+            // operations have to block
+            let socket = match socket
+                .into_std()
+                .and_then(|socket| socket.set_nonblocking(false).map(|_| socket))
+            {
+                Ok(socket) => Arc::new(socket),
+                Err(e) => {
+                    error!("failed to hand the test socket to io_uring: {e}");
+                    return IntervalResult::default();
+                }
+            };
+
+            let counters = Arc::new(Counters::default());
+            let io = tokio::task::spawn_blocking({
+                let counters = counters.clone();
+                let socket = socket.clone();
+                move || {
+                    uring::run(
+                        socket.as_fd(),
+                        send_buf,
+                        recv_len,
+                        should_send,
+                        should_recv,
+                        &counters,
+                    )
+                }
+            });
+            tokio::pin!(io);
 
-                    msg = comm_channel.recv(), if !is_done => {
+            let mut interval = IntervalResult::default();
+            let io_result = loop {
+                tokio::select! {
+                    res = &mut io => break res,
+                    msg = comm_channel.recv() => {
                         trace!("chan selected ({msg:?})");
-                        n_chan += 1;
-                        if let Some(msg) = msg {
-                            match msg {
-                                TestControlMessage::GetIntervalResult(chan) => {
-                                    //info!("{:?}", get_tcp_info(self.socket.as_raw_fd()));
-                                    let mut interval_to_send = std::mem::take(&mut interval);
-                                    interval_to_send.prepare_to_send();
-                                    if chan.send(interval_to_send).is_err() {
-                                        error!("failed to send interval results");
-                                    }
-
+                        match msg {
+                            Some(TestControlMessage::GetIntervalResult(chan)) => {
+                                let mut interval_to_send = std::mem::take(&mut interval);
+                                interval_to_send
+                                    .add_bytes_sent(counters.sent.swap(0, Ordering::Relaxed) as usize);
+                                interval_to_send.add_bytes_received(
+                                    counters.received.swap(0, Ordering::Relaxed) as usize,
+                                );
+                                interval_to_send.prepare_to_send();
+                                if let Some(tcp_stats) = tcp_stats.sample(socket.as_raw_fd()) {
+                                    interval_to_send.set_tcp_stats(tcp_stats);
                                 }
-
-                                TestControlMessage::Done => {
-                                    debug!("done");
-                                    is_done = true;
+                                if chan.send(interval_to_send).is_err() {
+                                    error!("failed to send interval results");
                                 }
                             }
+                            Some(TestControlMessage::Update(update)) => {
+                                warn!("the io_uring backend can't change a running test, ignoring {update:?}");
+                            }
+                            Some(TestControlMessage::Done) | None => {
+                                debug!("done");
+                                counters.done.store(true, Ordering::Relaxed);
+                                break (&mut io).await;
+                            }
+                            Some(TestControlMessage::Abort) => {
+                                debug!("aborted");
+                                counters.done.store(true, Ordering::Relaxed);
+                                // wakes the io_uring thread if it waits for the peer
+                                let _ = socket.shutdown(Shutdown::Both);
+                                break (&mut io).await;
+                            }
                         }
                     }
+                }
+            };
 
-                    else => {
-                        trace!("else branch");
-                        break;
-                    }
+            match io_result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("{e}"),
+                Err(e) => error!("io_uring thread failed: {e}"),
+            }
+            interval.add_bytes_sent(counters.sent.load(Ordering::Relaxed) as usize);
+            interval.add_bytes_received(counters.received.load(Ordering::Relaxed) as usize);
+            interval.prepare_to_send();
+            if let Some(tcp_stats) = tcp_stats.sample(socket.as_raw_fd()) {
+                interval.set_tcp_stats(tcp_stats);
+            }
+            interval
+        })
+    }
+}
+
+impl Test for TCPTest {
+    fn start_test(
+        mut self,
+        mut comm_channel: tokio::sync::mpsc::Receiver<crate::test_manager::TestControlMessage>,
+    ) -> tokio::task::JoinHandle<IntervalResult> {
+        if self.tcp_test_info.backend == Backend::Uring {
+            #[cfg(feature = "uring")]
+            return self.start_uring_test(comm_channel);
+            #[cfg(not(feature = "uring"))]
+            warn!("netbench has been built without io_uring support, falling back to epoll");
+        }
+
+        tokio::spawn(async move {
+            let mut path = self.data_path();
+            loop {
+                self.transfer(&mut path, &mut comm_channel).await;
+
+                // errors mean that the connection broke, the peer closes it at the end
+                if !self.failed || path.is_done || path.aborted {
+                    break;
+                }
+                path.interval.set_disconnected();
+                let Some(socket) = self.reconnect(&mut comm_channel, &mut path.interval).await
+                else {
+                    break;
+                };
+                self.resume(socket, &mut path);
+                path.interval.set_reconnected();
+            }
+            if let Sender::ZeroCopy(mut completions) = path.sender {
+                if let Err(e) = completions.drain(self.socket.as_raw_fd()) {
+                    warn!("failed to read zerocopy completions: {e}");
                 }
+                path.interval.set_zerocopy(completions);
             }
-            trace!("{n_send} {n_read} {n_chan}")
+            if let Some(mut verifier) = path.verifier {
+                path.interval.set_integrity(verifier.finish());
+            }
+            trace!("{} {} {}", path.n_send, path.n_read, path.n_chan);
+
+            let mut interval = path.interval;
+            interval.prepare_to_send();
+            if let Some(tcp_stats) = self.sample_tcp_stats() {
+                interval.set_tcp_stats(tcp_stats);
+            }
+            interval
         })
     }
 
     fn test_info(&self) -> &NewTestMessage {
         &self.test_info
     }
+
+    fn effective_options(&self) -> Option<&EffectiveTCPOptions> {
+        self.effective_options.as_ref()
+    }
 }
 
 impl TCPTest {
-    pub(crate) fn new(msg: NewTestMessage, role: Role, socket: TcpStream) -> Self {
-        let tcp_test_info = if let Protocol::TCP(tcp_test_info) = msg.protocol {
-            tcp_test_info
+    pub(crate) fn new(
+        msg: NewTestMessage,
+        role: Role,
+        socket: TcpStream,
+        common: &CommonConfig,
+    ) -> Self {
+        let tcp_test_info = if let Protocol::TCP(tcp_test_info) = &msg.protocol {
+            tcp_test_info.clone()
         } else {
             panic!()
         };
 
+        let effective_options = match sockopt::effective_tcp_options(socket.as_raw_fd()) {
+            Ok(mut options) => {
+                if let Some(tcp_info) = get_tcp_info(socket.as_raw_fd()) {
+                    options.ecn = tcp_info.options & TCPI_OPT_ECN != 0;
+                }
+                Some(options)
+            }
+            Err(e) => {
+                warn!("failed to read effective socket options: {e}");
+                None
+            }
+        };
+
         TCPTest {
             socket,
             test_info: msg,
             role,
             tcp_test_info,
+            effective_options,
+            tcp_stats: TcpStatsSampler::default(),
+            file: common.file.clone(),
+            recv_file: common.recv_file.clone(),
+            reconnect: None,
+            failed: false,
         }
     }
+
+    /// Makes the test replace its data connection through `reconnect` when it breaks
+    pub(crate) fn with_reconnect(mut self, reconnect: Reconnect) -> Self {
+        self.reconnect = Some(reconnect);
+        self
+    }
 }
@@ -1,18 +1,22 @@
 use crate::{
-    should_recv, should_send, Direction, EndCondition, NBytes, NBytesDisplay, NewTestMessage, Role,
+    expect::CheckResult, payload::IntegrityReport, should_recv, should_send, Direction,
+    EffectiveTCPOptions, EndCondition, NBytes, NBytesDisplay, NewTestMessage, Role,
+    TestResultsMessage, TestUpdate, ZeroCopyCompletions,
 };
 
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::io;
+use std::time::Duration as StdDuration;
 use termcolor::{ColorChoice, ColorSpec, StandardStream};
 use time::{Duration, OffsetDateTime};
 use tokio::{
-    sync::{
-        mpsc,
-        oneshot::{self, error::TryRecvError},
-    },
+    sync::{mpsc, oneshot, Notify},
     task::JoinHandle,
+    time::Instant,
 };
-use tracing::{debug, error, info, warn};
+use tracing::{debug, info, warn};
 
 #[derive(Debug)]
 pub struct IntervalResult {
@@ -20,6 +24,33 @@ pub struct IntervalResult {
     bytes_sent: NBytes,
     start: OffsetDateTime,
     end: OffsetDateTime,
+    tcp_stats: Option<TcpStats>,
+    /// Changes applied to the test during the interval
+    updates: Vec<TestUpdate>,
+    /// When the data connection broke during the interval
+    disconnected: Option<OffsetDateTime>,
+    /// When the data connection has been replaced during the interval
+    reconnected: Option<OffsetDateTime>,
+    /// The outcome of verifying the received data, set on the last interval of the test
+    integrity: Option<IntegrityReport>,
+    /// The zerocopy sends the kernel is done with, set on the last interval of the test
+    zerocopy: Option<ZeroCopyCompletions>,
+}
+
+/// Statistics taken from TCP_INFO at the end of an interval
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct TcpStats {
+    /// Retransmitted segments during the interval
+    pub(crate) retransmits: u32,
+    /// Smoothed round trip time in microseconds
+    pub(crate) rtt: u32,
+    pub(crate) rttvar: u32,
+    /// Segments delivered with the CE mark, only set if ECN has been negotiated
+    pub(crate) delivered_ce: Option<u32>,
+    /// Pacing rate in bytes per second
+    pub(crate) pacing_rate: u64,
+    /// SO_MAX_PACING_RATE in bytes per second, u64::MAX if unlimited
+    pub(crate) max_pacing_rate: u64,
 }
 
 impl IntervalResult {
@@ -48,13 +79,14 @@ impl IntervalResult {
         test_start_time: &OffsetDateTime,
         role: Role,
         direction: Direction,
+        prefix: &str,
         printer: &mut P,
     ) -> io::Result<()> {
         printer.reset()?;
         // Write the time interval
         write!(
             printer,
-            "{:.2}-{:.2}",
+            "{prefix}{:.2}-{:.2}",
             (self.start - *test_start_time).as_seconds_f64(),
             (self.end - *test_start_time).as_seconds_f64(),
         )?;
@@ -84,6 +116,25 @@ impl IntervalResult {
             write!(printer, "{:<4.2}", bits_recv.n)?;
             printer.set_color(ColorSpec::new().set_bold(true))?;
             write!(printer, " {}/s", bits_recv.unit)?;
+            printer.reset()?;
+        }
+
+        if let Some(delivered_ce) = self.tcp_stats.and_then(|stats| stats.delivered_ce) {
+            write!(printer, "{:^3}", "|")?;
+            write!(printer, "{delivered_ce}")?;
+            printer.set_color(ColorSpec::new().set_bold(true))?;
+            write!(printer, " CE")?;
+            printer.reset()?;
+        }
+
+        for update in &self.updates {
+            write!(printer, "{:^3}{update}", "|")?;
+        }
+        if self.disconnected.is_some() {
+            write!(printer, "{:^3}connection lost", "|")?;
+        }
+        if self.reconnected.is_some() {
+            write!(printer, "{:^3}reconnected", "|")?;
         }
 
         writeln!(printer)?;
@@ -99,6 +150,12 @@ impl Default for IntervalResult {
             bytes_sent: NBytes::default(),
             start: OffsetDateTime::now_utc(),
             end: OffsetDateTime::now_utc() + Duration::new(1, 0),
+            tcp_stats: None,
+            updates: Vec::new(),
+            disconnected: None,
+            reconnected: None,
+            integrity: None,
+            zerocopy: None,
         }
     }
 }
@@ -117,71 +174,661 @@ impl IntervalResult {
     pub(crate) fn prepare_to_send(&mut self) {
         self.end = OffsetDateTime::now_utc();
     }
+
+    /// Whether anything happened in the interval
+    fn is_empty(&self) -> bool {
+        self.bytes_sent.n == 0
+            && self.bytes_received.n == 0
+            && self.updates.is_empty()
+            && self.disconnected.is_none()
+            && self.reconnected.is_none()
+            && self.integrity.is_none()
+            && self.zerocopy.is_none()
+    }
+
+    pub(crate) fn set_tcp_stats(&mut self, tcp_stats: TcpStats) {
+        self.tcp_stats = Some(tcp_stats);
+    }
+
+    pub(crate) fn add_update(&mut self, update: TestUpdate) {
+        self.updates.push(update);
+    }
+
+    pub(crate) fn set_disconnected(&mut self) {
+        self.disconnected
+            .get_or_insert_with(OffsetDateTime::now_utc);
+    }
+
+    pub(crate) fn set_reconnected(&mut self) {
+        self.reconnected = Some(OffsetDateTime::now_utc());
+    }
+
+    pub(crate) fn set_integrity(&mut self, integrity: IntegrityReport) {
+        self.integrity = Some(integrity);
+    }
+
+    pub(crate) fn set_zerocopy(&mut self, zerocopy: ZeroCopyCompletions) {
+        self.zerocopy = Some(zerocopy);
+    }
+}
+
+/// Aggregated results of a whole test
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TestSummary {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Time covered by the collected intervals in seconds
+    pub duration: f64,
+    pub retransmits: Option<u64>,
+    /// Mean smoothed round trip time in milliseconds
+    pub mean_rtt: Option<f64>,
+    /// Mean RTT variation in milliseconds
+    #[serde(default)]
+    pub mean_rttvar: Option<f64>,
+    /// Segments delivered with the CE mark
+    pub delivered_ce: Option<u64>,
+    /// Mean pacing rate reported by the kernel in bits per second, also with application
+    /// pacing or none at all
+    pub pacing_rate: Option<f64>,
+    /// SO_MAX_PACING_RATE in bits per second, only set if the kernel pacing rate has been
+    /// limited
+    pub max_pacing_rate: Option<u64>,
+    /// Of the whole process, including tests that ran at the same time
+    pub cpu: Option<CpuUsage>,
+    /// The test has been cancelled before its end condition, the results are partial
+    #[serde(default)]
+    pub cancelled: bool,
+    /// Seconds into the test at which the peer was last heard from, set if the test has
+    /// been cancelled because the peer disappeared
+    #[serde(default)]
+    pub peer_lost: Option<f64>,
+    /// The outages of a resilient test
+    #[serde(default)]
+    pub outages: Vec<Outage>,
+    /// The socket options in effect on the peer's end of the data connection, if it
+    /// reported them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer_options: Option<EffectiveTCPOptions>,
+    /// The outcome of the thresholds the test was expected to meet
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub checks: Vec<CheckResult>,
+    /// The outcome of verifying the received data, only known to the receiver
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub integrity: Option<IntegrityReport>,
+    /// The completions of zerocopy sends, only known to the sender
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zerocopy: Option<ZeroCopyCompletions>,
+}
+
+/// A period in which a resilient test didn't transfer any data
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Outage {
+    /// Seconds into the test at which the outage started
+    pub start: f64,
+    /// Seconds until data flowed again, or until the end of the test
+    pub duration: f64,
+    /// Seconds from the start of the outage until the throughput before it has been
+    /// reached again, `None` if it never was
+    pub recovery: Option<f64>,
+    /// The data connection broke and had to be replaced
+    pub connection_lost: bool,
+}
+
+/// Throughput counts as regained once an interval reaches this share of the throughput
+/// before the outage
+const RECOVERY_THRESHOLD: f64 = 0.9;
+/// Number of intervals the throughput before an outage is averaged over
+const BASELINE_INTERVALS: usize = 3;
+
+/// Finds the outages of a resilient test in its intervals. Stalls are only seen at the
+/// granularity of the intervals, broken connections at the time they broke.
+#[derive(Debug, Default)]
+struct OutageTracker {
+    /// Throughput of the last intervals without an outage in bits per second
+    recent: VecDeque<f64>,
+    /// The last outage and the throughput before it, until the throughput is regained
+    current: Option<(Outage, f64)>,
+    /// Set while no data flows
+    down: bool,
+    outages: Vec<Outage>,
+}
+
+impl OutageTracker {
+    fn add(&mut self, interval: &IntervalResult, test_start: OffsetDateTime) {
+        let at = |time: OffsetDateTime| (time - test_start).as_seconds_f64();
+        let bytes = interval.bytes_sent.n + interval.bytes_received.n;
+        let rate = bits_per_second(bytes, (interval.end - interval.start).as_seconds_f64());
+
+        if !self.down && (interval.disconnected.is_some() || bytes == 0) {
+            let baseline = match self.current.take() {
+                // another outage before the throughput has been regained
+                Some((outage, baseline)) => {
+                    self.outages.push(outage);
+                    baseline
+                }
+                None if self.recent.is_empty() => 0.0,
+                None => self.recent.iter().sum::<f64>() / self.recent.len() as f64,
+            };
+            let outage = Outage {
+                start: interval.disconnected.map_or(at(interval.start), at),
+                duration: 0.0,
+                recovery: None,
+                connection_lost: false,
+            };
+            self.current = Some((outage, baseline));
+            self.down = true;
+        }
+
+        let Some((outage, baseline)) = &mut self.current else {
+            self.recent.push_back(rate);
+            if self.recent.len() > BASELINE_INTERVALS {
+                self.recent.pop_front();
+            }
+            return;
+        };
+        outage.connection_lost |= interval.disconnected.is_some();
+        if self.down {
+            let end = match (interval.disconnected, interval.reconnected) {
+                (_, Some(reconnected)) => at(reconnected),
+                (None, None) if bytes > 0 => at(interval.start),
+                _ => return,
+            };
+            outage.duration = end - outage.start;
+            self.down = false;
+        }
+
+        if bytes > 0 && rate >= *baseline * RECOVERY_THRESHOLD {
+            outage.recovery = Some(at(interval.end) - outage.start);
+            self.outages.push(*outage);
+            self.current = None;
+            self.recent.clear();
+            self.recent.push_back(rate);
+        }
+    }
+
+    fn finish(mut self, test_start: OffsetDateTime) -> Vec<Outage> {
+        if let Some((mut outage, _)) = self.current {
+            let end = (OffsetDateTime::now_utc() - test_start).as_seconds_f64();
+            // a break in the last interval can't be told apart from the peer ending the test
+            if self.down && end - outage.start < INTERVAL.as_secs_f64() {
+                return self.outages;
+            }
+            if self.down {
+                outage.duration = end - outage.start;
+            }
+            self.outages.push(outage);
+        }
+
+        self.outages
+    }
+}
+
+/// CPU time used by the whole process during a test, in percent of one core. The work
+/// of a test is spread over the threads of the runtime, so it can't be told apart from
+/// that of other tests.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CpuUsage {
+    pub user: f64,
+    pub system: f64,
+}
+
+impl CpuUsage {
+    pub fn total(&self) -> f64 {
+        self.user + self.system
+    }
+}
+
+/// User and system CPU time of all threads of the process in seconds
+fn cpu_time() -> Option<(f64, f64)> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return None;
+    }
+
+    let seconds = |tv: libc::timeval| tv.tv_sec as f64 + tv.tv_usec as f64 / 1_000_000.0;
+    Some((seconds(usage.ru_utime), seconds(usage.ru_stime)))
+}
+
+/// Measures the CPU usage between its creation and `CpuMeasurement::finish`
+struct CpuMeasurement {
+    start: Instant,
+    cpu_time: Option<(f64, f64)>,
+}
+
+impl CpuMeasurement {
+    fn start() -> Self {
+        CpuMeasurement {
+            start: Instant::now(),
+            cpu_time: cpu_time(),
+        }
+    }
+
+    fn finish(self) -> Option<CpuUsage> {
+        let (start_user, start_system) = self.cpu_time?;
+        let (user, system) = cpu_time()?;
+        let elapsed = self.start.elapsed().as_secs_f64();
+        if elapsed <= 0.0 {
+            return None;
+        }
+
+        Some(CpuUsage {
+            user: (user - start_user) / elapsed * 100.0,
+            system: (system - start_system) / elapsed * 100.0,
+        })
+    }
+}
+
+impl TestSummary {
+    pub(crate) fn from_intervals(intervals: &[IntervalResult]) -> Self {
+        let mut summary = TestSummary::default();
+        let mut rtt_sum = 0.0;
+        let mut rttvar_sum = 0.0;
+        let mut rtt_samples = 0;
+        let mut pacing_rate_sum = 0.0;
+        let mut pacing_rate_samples = 0;
+
+        for interval in intervals {
+            summary.bytes_sent += interval.bytes_sent.n;
+            summary.bytes_received += interval.bytes_received.n;
+            summary.duration += (interval.end - interval.start).as_seconds_f64();
+            summary.integrity = interval.integrity.or(summary.integrity);
+            summary.zerocopy = interval.zerocopy.or(summary.zerocopy);
+
+            if let Some(tcp_stats) = interval.tcp_stats {
+                *summary.retransmits.get_or_insert(0) += u64::from(tcp_stats.retransmits);
+                if let Some(delivered_ce) = tcp_stats.delivered_ce {
+                    *summary.delivered_ce.get_or_insert(0) += u64::from(delivered_ce);
+                }
+                if tcp_stats.max_pacing_rate != u64::MAX {
+                    summary.max_pacing_rate = Some(tcp_stats.max_pacing_rate.saturating_mul(8));
+                }
+                // unset until the congestion control sets it
+                if tcp_stats.pacing_rate != 0 && tcp_stats.pacing_rate != u64::MAX {
+                    pacing_rate_sum += tcp_stats.pacing_rate as f64 * 8.0;
+                    pacing_rate_samples += 1;
+                }
+                if tcp_stats.rtt != 0 {
+                    rtt_sum += f64::from(tcp_stats.rtt) / 1000.0;
+                    rttvar_sum += f64::from(tcp_stats.rttvar) / 1000.0;
+                    rtt_samples += 1;
+                }
+            }
+        }
+
+        if rtt_samples > 0 {
+            summary.mean_rtt = Some(rtt_sum / f64::from(rtt_samples));
+            summary.mean_rttvar = Some(rttvar_sum / f64::from(rtt_samples));
+        }
+        if pacing_rate_samples > 0 {
+            summary.pacing_rate = Some(pacing_rate_sum / f64::from(pacing_rate_samples));
+        }
+
+        summary
+    }
+
+    /// The statistics only the sender of the data knows are taken from the summary of the
+    /// `peer` if this side didn't send, they aren't reported without it
+    fn use_sender_stats(&mut self, role: Role, direction: Direction, peer: Option<&TestSummary>) {
+        if should_send(direction, role) {
+            return;
+        }
+        let peer = peer.cloned().unwrap_or_default();
+        self.retransmits = peer.retransmits;
+        self.mean_rtt = peer.mean_rtt;
+        self.mean_rttvar = peer.mean_rttvar;
+        self.delivered_ce = peer.delivered_ce;
+        self.pacing_rate = peer.pacing_rate;
+        self.max_pacing_rate = peer.max_pacing_rate;
+        self.zerocopy = peer.zerocopy;
+    }
+
+    pub fn sent_bits_per_second(&self) -> f64 {
+        bits_per_second(self.bytes_sent, self.duration)
+    }
+
+    pub fn received_bits_per_second(&self) -> f64 {
+        bits_per_second(self.bytes_received, self.duration)
+    }
+
+    /// The throughput in the direction that carried the most data
+    pub fn bits_per_second(&self) -> f64 {
+        self.sent_bits_per_second()
+            .max(self.received_bits_per_second())
+    }
+
+    fn print(&self, role: Role, direction: Direction, prefix: &str) {
+        let mut line = match self.peer_lost {
+            Some(at) => format!("{prefix}Total (peer lost at t={at:.2} s):"),
+            None if self.cancelled => format!("{prefix}Total (cancelled):"),
+            None => format!("{prefix}Total:"),
+        };
+        if should_send(direction, role) {
+            let sent = NBytes::from(self.bytes_sent).format_as_bytes();
+            let rate = NBytes::format_bits_per_second(self.sent_bits_per_second());
+            line.push_str(&format!(" sent {sent} ({rate}/s)"));
+        }
+        if should_recv(direction, role) {
+            let received = NBytes::from(self.bytes_received).format_as_bytes();
+            let rate = NBytes::format_bits_per_second(self.received_bits_per_second());
+            line.push_str(&format!(" received {received} ({rate}/s)"));
+        }
+        if let Some(retransmits) = self.retransmits {
+            line.push_str(&format!(" retransmits {retransmits}"));
+        }
+        if let Some(rtt) = self.mean_rtt {
+            line.push_str(&format!(" rtt {rtt:.3} ms"));
+        }
+        if let Some(delivered_ce) = self.delivered_ce {
+            line.push_str(&format!(" CE {delivered_ce}"));
+        }
+        if let Some(pacing_rate) = self.pacing_rate {
+            let pacing_rate = NBytes::format_bits_per_second(pacing_rate);
+            line.push_str(&format!(" pacing rate {pacing_rate}/s"));
+            if let Some(max_pacing_rate) = self.max_pacing_rate {
+                let max_pacing_rate = NBytes::format_bits_per_second(max_pacing_rate as f64);
+                line.push_str(&format!(" (max {max_pacing_rate}/s)"));
+            }
+        }
+        if let Some(cpu) = self.cpu {
+            line.push_str(&format!(
+                " process cpu {:.1}% (user {:.1}% system {:.1}%)",
+                cpu.total(),
+                cpu.user,
+                cpu.system
+            ));
+        }
+
+        println!("{line}");
+    }
+
+    fn print_zerocopy(&self, prefix: &str) {
+        if let Some(zerocopy) = self.zerocopy {
+            println!(
+                "{prefix}Zerocopy: {} sends completed, {} of them copied by the kernel",
+                zerocopy.completed, zerocopy.copied
+            );
+        }
+    }
+
+    fn print_integrity(&self, prefix: &str) {
+        if let Some(integrity) = self.integrity {
+            println!(
+                "{prefix}Verified {} blocks: {} bytes corrupted, {} bytes missing",
+                integrity.verified_blocks, integrity.corrupted_bytes, integrity.missing_bytes
+            );
+        }
+    }
+
+    fn print_outages(&self, prefix: &str) {
+        if self.outages.is_empty() {
+            println!("{prefix}No outages");
+        }
+        for outage in &self.outages {
+            let mut line = format!(
+                "{prefix}Outage at t={:.2} s lasting {:.2} s",
+                outage.start, outage.duration
+            );
+            if outage.connection_lost {
+                line.push_str(", the data connection had to be replaced");
+            }
+            match outage.recovery {
+                Some(recovery) => {
+                    line.push_str(&format!(", throughput regained after {recovery:.2} s"))
+                }
+                None => line.push_str(", throughput not regained"),
+            }
+            println!("{line}");
+        }
+    }
+}
+
+fn bits_per_second(bytes: u64, duration: f64) -> f64 {
+    if duration <= 0.0 {
+        return 0.0;
+    }
+
+    bytes as f64 * 8.0 / duration
+}
+
+/// Shared with a running test to observe and cancel it from the outside
+#[derive(Debug, Default)]
+pub(crate) struct TestMonitor {
+    /// Summary of the intervals collected so far
+    summary: Mutex<TestSummary>,
+    cancel: Notify,
+    /// Changes that haven't been passed on to the test yet
+    updates: Mutex<Vec<TestUpdate>>,
+    updated: Notify,
+    /// Prefixed to the output of the test to tell it apart from concurrent tests
+    label: Option<String>,
+    /// When the peer was last heard from, set once it's considered lost
+    peer_lost: Mutex<Option<Instant>>,
+    /// Nothing is printed, the caller reports the summary
+    quiet: bool,
+    /// The peer sends its summary once the test is done, see `TestResultsMessage`
+    peer_results: bool,
+    peer_results_slot: Mutex<Option<TestResultsMessage>>,
+    peer_results_received: Notify,
+}
+
+impl TestMonitor {
+    pub(crate) fn labeled(label: String) -> Self {
+        TestMonitor {
+            label: Some(label),
+            ..TestMonitor::default()
+        }
+    }
+
+    pub(crate) fn quiet(self, quiet: bool) -> Self {
+        TestMonitor { quiet, ..self }
+    }
+
+    pub(crate) fn is_quiet(&self) -> bool {
+        self.quiet
+    }
+
+    pub(crate) fn peer_results(self, peer_results: bool) -> Self {
+        TestMonitor {
+            peer_results,
+            ..self
+        }
+    }
+
+    /// Passes on the results of the peer, the test waits for them before it reports its own
+    pub(crate) fn set_peer_results(&self, results: TestResultsMessage) {
+        *self.peer_results_slot.lock() = Some(results);
+        self.peer_results_received.notify_one();
+    }
+
+    pub(crate) fn prefix(&self) -> String {
+        self.label
+            .as_ref()
+            .map_or_else(String::new, |label| format!("[{label}] "))
+    }
+
+    pub(crate) fn summary(&self) -> TestSummary {
+        self.summary.lock().clone()
+    }
+
+    /// Ends the test as if it reached its end condition
+    pub(crate) fn cancel(&self) {
+        // stores a permit if the test isn't waiting yet, so the cancellation isn't lost
+        self.cancel.notify_one();
+    }
+
+    /// Cancels the test because the peer hasn't been heard from since `last_heard`. The
+    /// data connection isn't expected to end cleanly.
+    pub(crate) fn lose_peer(&self, last_heard: Instant) {
+        *self.peer_lost.lock() = Some(last_heard);
+        self.cancel.notify_one();
+    }
+
+    /// Changes the running test
+    pub(crate) fn update(&self, update: TestUpdate) {
+        self.updates.lock().push(update);
+        self.updated.notify_one();
+    }
 }
 
 #[derive(Debug)]
 pub(crate) enum TestControlMessage {
     Done,
+    /// Ends the test right away, without waiting for the peer
+    Abort,
     GetIntervalResult(oneshot::Sender<IntervalResult>),
+    /// Applied by the test and recorded in the current interval
+    Update(TestUpdate),
 }
 
 pub(crate) trait Test {
+    /// Runs the test in a task, which returns what happened after the last interval it
+    /// reported
     fn start_test(
         self,
         comm_channel: tokio::sync::mpsc::Receiver<TestControlMessage>,
-    ) -> JoinHandle<()>;
+    ) -> JoinHandle<IntervalResult>;
     fn test_info(&self) -> &NewTestMessage;
+    fn effective_options(&self) -> Option<&EffectiveTCPOptions> {
+        None
+    }
 }
 
-fn print_header(test_info: &NewTestMessage) {
+fn print_header(
+    test_info: &NewTestMessage,
+    effective_options: Option<&EffectiveTCPOptions>,
+    prefix: &str,
+) {
     match test_info.end_condition {
         EndCondition::Bytes(bytes) => {
             println!(
-                "Running a {} test for {} bytes...",
+                "{prefix}Running a {} test for {} bytes...",
                 test_info.protocol, bytes
             );
         }
         EndCondition::Time(duration) => {
             println!(
-                "Running a {} test for {:.0} seconds...",
+                "{prefix}Running a {} test for {:.0} seconds...",
                 test_info.protocol,
                 duration.as_seconds_f64()
             );
         }
     }
+
+    if test_info.bw > 0 {
+        let bitrate = NBytes::format_bits_per_second(test_info.bw as f64);
+        println!(
+            "{prefix}Target bitrate {bitrate}/s, paced by the {}",
+            test_info.pacing
+        );
+    }
+
+    if let Some(options) = effective_options {
+        println!("{prefix}Socket options ({options})");
+    }
 }
 
-pub(crate) async fn run<T: Test>(test: T, role: Role) {
+const INTERVAL: StdDuration = StdDuration::from_secs(1);
+/// How long a test waits for the summary of the peer after it ended
+const PEER_RESULTS_TIMEOUT: StdDuration = StdDuration::from_secs(3);
+
+pub(crate) async fn run<T: Test>(test: T, role: Role, monitor: &TestMonitor) -> TestSummary {
     let test_info = test.test_info();
     let direction = test_info.direction;
-    print_header(test_info);
+    let resilient = test_info.resilient;
+    let mut outages = OutageTracker::default();
+    let prefix = monitor.prefix();
+    let quiet = monitor.quiet;
+    if !quiet {
+        print_header(test_info, test.effective_options(), &prefix);
+    }
 
     let (send, recv) = tokio::sync::mpsc::channel(5);
     let test_start = OffsetDateTime::now_utc();
-    let mut current_interval = test_start;
+    let started = Instant::now();
     let test_duration = match test_info.end_condition {
         EndCondition::Time(duration) => duration,
         _ => panic!("Only EndCondition::Time is currently implemented"),
     };
-    const INTERVAL: Duration = Duration::new(1, 0);
     let mut intervals = Vec::new();
+    let cpu_measurement = CpuMeasurement::start();
     let handle = test.start_test(recv);
+    let mut cancelled = false;
+    let mut peer_lost = None;
+
+    let deadline = tokio::time::sleep(test_duration.unsigned_abs());
+    tokio::pin!(deadline);
+    let mut ticker = tokio::time::interval_at(Instant::now() + INTERVAL, INTERVAL);
 
-    while OffsetDateTime::now_utc() - test_start < test_duration {
-        if OffsetDateTime::now_utc() - current_interval > INTERVAL {
-            current_interval = OffsetDateTime::now_utc();
-
-            match get_interval_stats(&send).await {
-                Ok(Some(res)) => {
-                    info!("{res:?}");
-                    let stdout = StandardStream::stdout(ColorChoice::Always);
-                    let mut stdout = stdout.lock();
-                    res.print_for_display(&test_start, role, direction, &mut stdout)
-                        .unwrap();
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            _ = monitor.cancel.notified() => {
+                let lost = monitor.peer_lost.lock().take();
+                if lost.is_some() && resilient {
+                    if !quiet {
+                        println!("{prefix}Lost the control connection, the test keeps running");
+                    }
+                    continue;
+                }
+                match lost {
+                    Some(last_heard) => {
+                        let at = last_heard.saturating_duration_since(started).as_secs_f64();
+                        if !quiet {
+                            println!("{prefix}Lost the peer, last heard from it at t={at:.2} s");
+                        }
+                        peer_lost = Some(at);
+                    }
+                    None if !quiet => println!("{prefix}The test has been cancelled"),
+                    None => (),
+                }
+                cancelled = true;
+                // the interval that was cut short
+                if let Some(res) = get_interval_stats(&send).await {
+                    if !quiet {
+                        let stdout = StandardStream::stdout(ColorChoice::Always);
+                        let mut stdout = stdout.lock();
+                        res.print_for_display(&test_start, role, direction, &prefix, &mut stdout)
+                            .unwrap();
+                    }
                     intervals.push(res);
                 }
-                Ok(None) => warn!("didn't get interval results"),
-                Err(e) => error!("join error: {e}"),
+                break;
+            }
+            _ = monitor.updated.notified() => {
+                let updates = std::mem::take(&mut *monitor.updates.lock());
+                for update in updates {
+                    debug!("updating the test: {update:?}");
+                    if send.send(TestControlMessage::Update(update)).await.is_err() {
+                        warn!("the test ended before it could be updated");
+                    }
+                }
+            }
+            _ = ticker.tick() => {
+                match get_interval_stats(&send).await {
+                    Some(res) => {
+                        info!("{res:?}");
+                        if !quiet {
+                            let stdout = StandardStream::stdout(ColorChoice::Always);
+                            let mut stdout = stdout.lock();
+                            res.print_for_display(&test_start, role, direction, &prefix, &mut stdout)
+                                .unwrap();
+                        }
+                        if resilient {
+                            outages.add(&res, test_start);
+                        }
+                        intervals.push(res);
+                        *monitor.summary.lock() = TestSummary::from_intervals(&intervals);
+                    }
+                    // the test stops handling messages when its connection is gone
+                    None if send.is_closed() => {
+                        warn!("the test ended before its end condition");
+                        break;
+                    }
+                    None => warn!("didn't get interval results"),
+                }
             }
         }
     }
@@ -189,31 +836,173 @@ pub(crate) async fn run<T: Test>(test: T, role: Role) {
     debug!("done");
 
     if !handle.is_finished() {
-        send.send(TestControlMessage::Done).await.unwrap();
+        // a lost peer would never finish the test
+        let message = match peer_lost {
+            Some(_) => TestControlMessage::Abort,
+            None => TestControlMessage::Done,
+        };
+        // fails if the test has ended in the meantime, it's done either way
+        let _ = send.send(message).await;
     }
 
-    handle.await.unwrap();
+    // e.g. the end of a test whose peer closed the connection first
+    let rest = handle.await.unwrap();
+    if !rest.is_empty() {
+        // a rest of a few ms only adds noise to the output
+        if !quiet && rest.end - rest.start >= INTERVAL / 10 {
+            let stdout = StandardStream::stdout(ColorChoice::Always);
+            let mut stdout = stdout.lock();
+            rest.print_for_display(&test_start, role, direction, &prefix, &mut stdout)
+                .unwrap();
+        }
+        if resilient {
+            outages.add(&rest, test_start);
+        }
+        intervals.push(rest);
+    }
+
+    let mut summary = TestSummary::from_intervals(&intervals);
+    summary.cpu = cpu_measurement.finish();
+    summary.cancelled = cancelled;
+    summary.peer_lost = peer_lost;
+    let peer_results = if monitor.peer_results && peer_lost.is_none() {
+        let received = monitor.peer_results_received.notified();
+        if tokio::time::timeout(PEER_RESULTS_TIMEOUT, received)
+            .await
+            .is_err()
+        {
+            warn!("the peer didn't send its results");
+        }
+        monitor.peer_results_slot.lock().take()
+    } else {
+        None
+    };
+    let peer_summary = peer_results.as_ref().map(|results| &results.summary);
+    summary.use_sender_stats(role, direction, peer_summary);
+    if !should_recv(direction, role) {
+        summary.integrity = peer_summary.and_then(|peer| peer.integrity);
+    }
+    summary.peer_options = peer_results.and_then(|results| results.effective_options);
+    if resilient {
+        summary.outages = outages.finish(test_start);
+    }
+    if !quiet {
+        if let Some(options) = &summary.peer_options {
+            println!("{prefix}Server socket options ({options})");
+        }
+        summary.print(role, direction, &prefix);
+        summary.print_zerocopy(&prefix);
+        summary.print_integrity(&prefix);
+        if resilient {
+            summary.print_outages(&prefix);
+        }
+    }
+    summary
 }
 
-fn get_interval_stats(
-    send: &mpsc::Sender<TestControlMessage>,
-) -> JoinHandle<Option<IntervalResult>> {
-    let (interval_send, mut interval_recv) = tokio::sync::oneshot::channel();
-    send.try_send(TestControlMessage::GetIntervalResult(interval_send))
-        .expect("failed to send");
+/// Asks the test for the results of the current interval, returns `None` if the test
+/// doesn't answer
+async fn get_interval_stats(send: &mpsc::Sender<TestControlMessage>) -> Option<IntervalResult> {
+    let (interval_send, interval_recv) = oneshot::channel();
+    if let Err(e) = send.try_send(TestControlMessage::GetIntervalResult(interval_send)) {
+        debug!("failed to request interval results: {e}");
+        return None;
+    }
 
-    tokio::spawn(async move {
-        loop {
-            match interval_recv.try_recv() {
-                Ok(res) => {
-                    return Some(res);
-                }
-                Err(TryRecvError::Closed) => {
-                    error!("channel closed without value");
-                    return None;
-                }
-                Err(TryRecvError::Empty) => continue,
-            }
+    match interval_recv.await {
+        Ok(res) => Some(res),
+        Err(_) => {
+            // the test ended in the meantime, it returns the interval itself
+            debug!("channel closed without value");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_outage_tracker() {
+        let test_start = OffsetDateTime::now_utc();
+        let mut tracker = OutageTracker::default();
+        for (i, bytes) in [1000u64, 1000, 1000, 0, 0, 500, 1000]
+            .into_iter()
+            .enumerate()
+        {
+            let start = test_start + Duration::seconds(i as i64);
+            let mut interval = IntervalResult {
+                start,
+                end: start + Duration::SECOND,
+                ..Default::default()
+            };
+            interval.add_bytes_sent(bytes as usize);
+            tracker.add(&interval, test_start);
         }
-    })
+
+        let outages = tracker.finish(test_start);
+        assert_eq!(
+            outages,
+            [Outage {
+                start: 3.0,
+                duration: 2.0,
+                recovery: Some(4.0),
+                connection_lost: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_sender_stats() {
+        let peer = TestSummary {
+            retransmits: Some(3),
+            mean_rtt: Some(0.5),
+            zerocopy: Some(ZeroCopyCompletions {
+                completed: 10,
+                copied: 1,
+            }),
+            ..Default::default()
+        };
+        let local = TestSummary {
+            retransmits: Some(0),
+            mean_rtt: Some(0.1),
+            ..Default::default()
+        };
+
+        let mut summary = local.clone();
+        summary.use_sender_stats(Role::Client, Direction::ServerToClient, Some(&peer));
+        assert_eq!(summary.retransmits, Some(3));
+        assert_eq!(summary.zerocopy, peer.zerocopy);
+        assert_eq!(summary.mean_rtt, Some(0.5));
+
+        let mut summary = local.clone();
+        summary.use_sender_stats(Role::Client, Direction::ServerToClient, None);
+        assert_eq!(summary.retransmits, None);
+        assert_eq!(summary.mean_rtt, None);
+
+        let mut summary = local.clone();
+        summary.use_sender_stats(Role::Client, Direction::Bidirectional, Some(&peer));
+        assert_eq!(summary.retransmits, Some(0));
+        assert_eq!(summary.mean_rtt, Some(0.1));
+    }
+
+    #[test]
+    fn test_integrity_summary() {
+        let report = IntegrityReport {
+            verified_blocks: 10,
+            corrupted_bytes: 0,
+            missing_bytes: 100,
+        };
+        let mut first = IntervalResult::default();
+        first.add_bytes_received(1000);
+        // the rest of the test may carry nothing but the report
+        let mut rest = IntervalResult::default();
+        assert!(rest.is_empty());
+        rest.set_integrity(report);
+        assert!(!rest.is_empty());
+
+        let summary = TestSummary::from_intervals(&[first, rest]);
+        assert_eq!(summary.integrity, Some(report));
+    }
 }
@@ -1,6 +1,7 @@
 use crate::NBError;
 use std::cmp;
-use time::OffsetDateTime;
+use std::time::Duration as StdDuration;
+use time::{Duration, OffsetDateTime};
 
 #[derive(Debug)]
 pub struct TokenBucket {
@@ -23,13 +24,16 @@ impl TokenBucket {
     fn update(&mut self) {
         let current = OffsetDateTime::now_utc();
         let diff = current - self.last_refill;
-        let tokens_added = diff.as_seconds_f64() as u64 * self.rate;
+        let tokens_added = (diff.as_seconds_f64() * self.rate as f64) as u64;
         if tokens_added == 0 {
             return;
         }
-        println!("diff = {} tokens_added = {}", diff, tokens_added);
-        self.current_token = cmp::min(self.current_token + tokens_added, self.max_token);
-        self.last_refill = current;
+        self.current_token = cmp::min(
+            self.current_token.saturating_add(tokens_added),
+            self.max_token,
+        );
+        // the fraction of a token that hasn't been added yet carries over
+        self.last_refill += Duration::seconds_f64(tokens_added as f64 / self.rate as f64);
     }
 
     pub fn try_consume(&mut self, bytes: u64) -> Result<(), NBError> {
@@ -41,6 +45,17 @@ impl TokenBucket {
         self.current_token -= bytes;
         Ok(())
     }
+
+    /// Time until `bytes` tokens will be available, tokens are added continuously
+    pub fn wait_time(&self, bytes: u64) -> StdDuration {
+        if self.current_token >= bytes || self.rate == 0 {
+            return StdDuration::ZERO;
+        }
+
+        let missing = (bytes - self.current_token) as f64 / self.rate as f64;
+        let elapsed = OffsetDateTime::now_utc() - self.last_refill;
+        StdDuration::from_secs_f64(missing).saturating_sub(elapsed.unsigned_abs())
+    }
 }
 
 #[cfg(test)]
@@ -61,11 +76,28 @@ mod test {
                 sent_this_sec += 1500;
                 if Instant::now() - interval_start >= Duration::from_secs(1) {
                     assert!(sent_this_sec > (50 * 1024) - 1500);
-                    assert!(sent_this_sec < (100 * 1024) + 1500);
+                    // the rate plus at most one full bucket
+                    assert!(sent_this_sec < (150 * 1024) + 1500);
                     sent_this_sec = 0;
                     interval_start = Instant::now();
                 }
             }
         }
     }
+
+    #[test]
+    fn test_burst() {
+        // 1 MB/s with a burst of 5 ms
+        let mut bucket = TokenBucket::new(1_000_000, 5_000);
+        assert!(bucket.try_consume(5_000).is_ok());
+        assert!(bucket.try_consume(5_000).is_err());
+        let wait = bucket.wait_time(5_000);
+        assert!(wait > Duration::ZERO && wait <= Duration::from_millis(5));
+
+        sleep(Duration::from_millis(6));
+        assert!(bucket.try_consume(5_000).is_ok());
+        // a pause doesn't allow more than the burst
+        sleep(Duration::from_millis(20));
+        assert!(bucket.try_consume(6_000).is_err());
+    }
 }
@@ -0,0 +1,235 @@
+use std::io;
+use std::os::unix::io::{AsRawFd, BorrowedFd};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use io_uring::{cqueue, opcode, squeue, types, IoUring};
+use tracing::{debug, trace};
+
+const QUEUE_DEPTH: u32 = 64;
+/// Number of writes kept in flight, they are submitted together
+const SEND_BATCH: usize = 8;
+/// Number of buffers provided to the multishot receive
+const RECV_BUFFERS: u16 = 16;
+const BUFFER_GROUP: u16 = 0;
+
+const SEND: u64 = 1;
+const RECV: u64 = 2;
+const PROVIDE_BUFFERS: u64 = 3;
+
+/// Byte counters shared between the io_uring thread and the test task
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    pub(crate) sent: AtomicU64,
+    pub(crate) received: AtomicU64,
+    /// Set by the test task once the test is over, stops sending
+    pub(crate) done: AtomicBool,
+}
+
+struct Ring {
+    ring: IoUring,
+    /// Submitted operations that haven't completed yet
+    in_flight: usize,
+}
+
+impl Ring {
+    fn push(&mut self, entry: squeue::Entry) -> io::Result<()> {
+        // the queue is only full if too many entries have been pushed since the last submit
+        while unsafe { self.ring.submission().push(&entry) }.is_err() {
+            self.ring.submit()?;
+        }
+        self.in_flight += 1;
+
+        Ok(())
+    }
+
+    /// Submits the queued entries and waits for at least one completion, or until the
+    /// timeout expires so that `Counters::done` is noticed.
+    fn submit_and_wait(&mut self) -> io::Result<Vec<(u64, i32, u32)>> {
+        let timeout = types::Timespec::new().nsec(100_000_000);
+        let args = types::SubmitArgs::new().timespec(&timeout);
+        match self.ring.submitter().submit_with_args(1, &args) {
+            Ok(_) => {}
+            Err(e) if e.raw_os_error() == Some(libc::ETIME) => {}
+            Err(e) if e.raw_os_error() == Some(libc::EINTR) => {}
+            Err(e) => return Err(e),
+        }
+
+        let completions: Vec<_> = self
+            .ring
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result(), cqe.flags()))
+            .collect();
+        for (user_data, _, flags) in &completions {
+            // a multishot receive only goes away with its last completion
+            if *user_data != RECV || !cqueue::more(*flags) {
+                self.in_flight -= 1;
+            }
+        }
+
+        Ok(completions)
+    }
+}
+
+fn recv_multi(fd: types::Fd) -> squeue::Entry {
+    opcode::RecvMulti::new(fd, BUFFER_GROUP)
+        .build()
+        .user_data(RECV)
+}
+
+fn os_error(result: i32) -> io::Error {
+    io::Error::from_raw_os_error(-result)
+}
+
+/// Runs the data path of a TCP test on `socket` until the test is done or the peer closes
+/// the connection. `socket` has to be in blocking mode so that io_uring waits for the
+/// socket to become ready instead of failing with EAGAIN.
+pub(crate) fn run(
+    socket: BorrowedFd<'_>,
+    send_buf: Vec<u8>,
+    recv_len: usize,
+    should_send: bool,
+    should_recv: bool,
+    counters: &Counters,
+) -> io::Result<()> {
+    let fd = types::Fd(socket.as_raw_fd());
+    let mut ring = Ring {
+        ring: IoUring::new(QUEUE_DEPTH)?,
+        in_flight: 0,
+    };
+
+    let mut recv_bufs = vec![0_u8; recv_len * usize::from(RECV_BUFFERS)];
+    let recv_len = i32::try_from(recv_len)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "receive buffer too large"))?;
+    let send_len = u32::try_from(send_buf.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "send buffer too large"))?;
+
+    if should_send {
+        let iovec = libc::iovec {
+            iov_base: send_buf.as_ptr() as *mut libc::c_void,
+            iov_len: send_buf.len(),
+        };
+        // the buffer outlives the ring, it is declared before it is used and dropped after
+        // all operations have completed
+        unsafe { ring.ring.submitter().register_buffers(&[iovec])? };
+    }
+
+    let mut data_path = || -> io::Result<()> {
+        if should_recv {
+            let provide = opcode::ProvideBuffers::new(
+                recv_bufs.as_mut_ptr(),
+                recv_len,
+                RECV_BUFFERS,
+                BUFFER_GROUP,
+                0,
+            )
+            .build()
+            .user_data(PROVIDE_BUFFERS);
+            ring.push(provide)?;
+            ring.push(recv_multi(fd))?;
+        }
+
+        let mut sends_in_flight = 0;
+        loop {
+            if counters.done.load(Ordering::Relaxed) && !should_recv {
+                return Ok(());
+            }
+
+            if should_send && !counters.done.load(Ordering::Relaxed) {
+                while sends_in_flight < SEND_BATCH {
+                    let write = opcode::WriteFixed::new(fd, send_buf.as_ptr(), send_len, 0)
+                        .build()
+                        .user_data(SEND);
+                    ring.push(write)?;
+                    sends_in_flight += 1;
+                }
+            }
+
+            for (user_data, result, flags) in ring.submit_and_wait()? {
+                match user_data {
+                    SEND => {
+                        sends_in_flight -= 1;
+                        match result {
+                            n if n >= 0 => {
+                                counters.sent.fetch_add(n as u64, Ordering::Relaxed);
+                            }
+                            e if e == -libc::EAGAIN => {}
+                            e => return Err(os_error(e)),
+                        }
+                    }
+                    RECV => {
+                        if result == 0 {
+                            trace!("read 0");
+                            return Ok(());
+                        } else if result > 0 {
+                            counters
+                                .received
+                                .fetch_add(result as u64, Ordering::Relaxed);
+                        } else if result != -libc::ENOBUFS {
+                            return Err(os_error(result));
+                        }
+
+                        // hand the buffer back to the kernel
+                        if let Some(bid) = cqueue::buffer_select(flags) {
+                            let offset = usize::from(bid) * recv_len as usize;
+                            let provide = opcode::ProvideBuffers::new(
+                                recv_bufs[offset..].as_mut_ptr(),
+                                recv_len,
+                                1,
+                                BUFFER_GROUP,
+                                bid,
+                            )
+                            .build()
+                            .user_data(PROVIDE_BUFFERS);
+                            ring.push(provide)?;
+                        }
+                        if !cqueue::more(flags) {
+                            ring.push(recv_multi(fd))?;
+                        }
+                    }
+                    PROVIDE_BUFFERS if result < 0 => return Err(os_error(result)),
+                    _ => {}
+                }
+            }
+        }
+    };
+    let result = data_path();
+
+    // The kernel may still write into the receive buffers, shut the socket down so that
+    // all outstanding operations complete before the buffers are dropped.
+    debug!("waiting for {} io_uring operations", ring.in_flight);
+    unsafe { libc::shutdown(fd.0, libc::SHUT_RDWR) };
+    while ring.in_flight > 0 {
+        ring.submit_and_wait()?;
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::io::AsFd;
+
+    use super::*;
+
+    #[test]
+    fn test_recv() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        let writer = std::thread::spawn(move || {
+            for _ in 0..64 {
+                client.write_all(&[1; 1000]).unwrap();
+            }
+        });
+
+        let counters = Counters::default();
+        run(server.as_fd(), vec![0; 128], 128, false, true, &counters).unwrap();
+        writer.join().unwrap();
+
+        assert_eq!(counters.received.load(Ordering::Relaxed), 64_000);
+        assert_eq!(counters.sent.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,278 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr;
+
+use libc::c_int;
+use serde::{Deserialize, Serialize};
+
+const SO_EE_ORIGIN_ZEROCOPY: u8 = 5;
+const SO_EE_CODE_ZEROCOPY_COPIED: u8 = 1;
+
+fn cvt(ret: isize) -> io::Result<usize> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(ret as usize)
+}
+
+pub(crate) fn enable_zerocopy(fd: RawFd) -> io::Result<()> {
+    let one: c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ZEROCOPY,
+            &one as *const c_int as *const libc::c_void,
+            mem::size_of::<c_int>() as libc::socklen_t,
+        )
+    };
+
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Sends `buf` with MSG_ZEROCOPY. The kernel pins the pages of `buf` until the
+/// completion for this send has been read from the error queue.
+pub(crate) fn send_zerocopy(fd: RawFd, buf: &[u8]) -> io::Result<usize> {
+    let ret = unsafe {
+        libc::send(
+            fd,
+            buf.as_ptr() as *const libc::c_void,
+            buf.len(),
+            libc::MSG_ZEROCOPY | libc::MSG_DONTWAIT,
+        )
+    };
+
+    cvt(ret)
+}
+
+/// Completion notifications read from the socket error queue
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ZeroCopyCompletions {
+    /// Sends the kernel is done with
+    pub completed: u64,
+    /// Completed sends for which the kernel had to fall back to copying
+    pub copied: u64,
+}
+
+impl ZeroCopyCompletions {
+    /// Reads all pending completions from the error queue without blocking
+    pub(crate) fn drain(&mut self, fd: RawFd) -> io::Result<()> {
+        loop {
+            let mut control = [0u64; 16];
+            let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = mem::size_of_val(&control) as _;
+
+            let ret =
+                unsafe { libc::recvmsg(fd, &mut msg, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) };
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    return Ok(());
+                }
+                return Err(err);
+            }
+
+            let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+            while !cmsg.is_null() {
+                let err = unsafe {
+                    (libc::CMSG_DATA(cmsg) as *const libc::sock_extended_err).read_unaligned()
+                };
+                if err.ee_origin == SO_EE_ORIGIN_ZEROCOPY {
+                    // ee_info and ee_data hold the inclusive range of completed sends
+                    let n = u64::from(err.ee_data.wrapping_sub(err.ee_info)) + 1;
+                    self.completed += n;
+                    if err.ee_code & SO_EE_CODE_ZEROCOPY_COPIED != 0 {
+                        self.copied += n;
+                    }
+                }
+                cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+            }
+        }
+    }
+}
+
+/// Sends up to `len` bytes of `file` starting at `offset`, wrapping around at the end
+/// of the file.
+pub(crate) fn sendfile(fd: RawFd, file: &File, offset: &mut i64, len: usize) -> io::Result<usize> {
+    loop {
+        let ret = unsafe { libc::sendfile(fd, file.as_raw_fd(), offset, len) };
+        match cvt(ret)? {
+            0 if *offset == 0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "the file to send is empty",
+                ))
+            }
+            0 => *offset = 0,
+            n => return Ok(n),
+        }
+    }
+}
+
+/// Discards received data by splicing it through a pipe into /dev/null without
+/// copying it into user space.
+pub(crate) struct SplicePipe {
+    read: File,
+    write: File,
+    dev_null: File,
+}
+
+impl SplicePipe {
+    pub(crate) fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (read, write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        let dev_null = OpenOptions::new().write(true).open("/dev/null")?;
+
+        Ok(SplicePipe {
+            read,
+            write,
+            dev_null,
+        })
+    }
+
+    /// Moves up to `len` bytes from the socket into the pipe and drains the pipe.
+    /// Returns 0 once the peer has closed the connection.
+    pub(crate) fn splice_from(&self, fd: RawFd, len: usize) -> io::Result<usize> {
+        let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+        let n = cvt(unsafe {
+            libc::splice(
+                fd,
+                ptr::null_mut(),
+                self.write.as_raw_fd(),
+                ptr::null_mut(),
+                len,
+                flags,
+            )
+        })?;
+
+        let mut remaining = n;
+        while remaining > 0 {
+            remaining -= cvt(unsafe {
+                libc::splice(
+                    self.read.as_raw_fd(),
+                    ptr::null_mut(),
+                    self.dev_null.as_raw_fd(),
+                    ptr::null_mut(),
+                    remaining,
+                    flags,
+                )
+            })?;
+        }
+
+        Ok(n)
+    }
+}
+
+/// `struct tcp_zerocopy_receive` up to the `err` field, the kernel accepts the
+/// shorter versions of the struct.
+#[repr(C)]
+#[derive(Debug, Default)]
+struct TcpZeroCopyReceive {
+    address: u64,
+    length: u32,
+    recv_skip_hint: u32,
+    inq: u32,
+    err: i32,
+}
+
+/// A mapping of the socket receive queue for TCP_ZEROCOPY_RECEIVE
+pub(crate) struct ZeroCopyMapping {
+    address: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is only ever accessed through the kernel
+unsafe impl Send for ZeroCopyMapping {}
+
+impl ZeroCopyMapping {
+    pub(crate) fn new(fd: RawFd, len: usize) -> io::Result<Self> {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let len = len.max(1).div_ceil(page_size) * page_size;
+        let address = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if address == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(ZeroCopyMapping { address, len })
+    }
+
+    /// Maps received data into the mapping. Data that can't be mapped, because it
+    /// doesn't fill a whole page, is read into `copy_buf`. Returns 0 once the peer has
+    /// closed the connection.
+    pub(crate) fn receive(&self, fd: RawFd, copy_buf: &mut [u8]) -> io::Result<usize> {
+        let mut zc = TcpZeroCopyReceive {
+            address: self.address as u64,
+            length: self.len as u32,
+            ..Default::default()
+        };
+        let mut zc_len = mem::size_of::<TcpZeroCopyReceive>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::IPPROTO_TCP,
+                libc::TCP_ZEROCOPY_RECEIVE,
+                &mut zc as *mut TcpZeroCopyReceive as *mut libc::c_void,
+                &mut zc_len,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if zc.err != 0 {
+            return Err(io::Error::from_raw_os_error(-zc.err));
+        }
+
+        let mapped = zc.length as usize;
+        let skip = (zc.recv_skip_hint as usize).min(copy_buf.len());
+        if mapped > 0 && skip == 0 {
+            return Ok(mapped);
+        }
+
+        // either there is data that has to be copied or nothing was mapped, in which case
+        // recv reports EOF or would block
+        let len = if skip > 0 { skip } else { copy_buf.len() };
+        let copied = cvt(unsafe {
+            libc::recv(
+                fd,
+                copy_buf.as_mut_ptr() as *mut libc::c_void,
+                len,
+                libc::MSG_DONTWAIT,
+            )
+        });
+
+        match copied {
+            Ok(copied) => Ok(mapped + copied),
+            Err(_) if mapped > 0 => Ok(mapped),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Drop for ZeroCopyMapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.address, self.len);
+        }
+    }
+}